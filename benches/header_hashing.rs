@@ -0,0 +1,62 @@
+//! Throughput of the header hashing strategies: hex round trip, binary serialization and
+//! midstate. Run with `cargo bench`.
+
+use std::time::Instant;
+
+use code_challenge_2024_mirebella_v2::block::{create_header, double_sha256};
+use code_challenge_2024_mirebella_v2::{BlockHash, HeaderHasher};
+
+const ITERATIONS: u32 = 1_000_000;
+
+fn main() -> anyhow::Result<()> {
+    let header = create_header(
+        BlockHash::ZERO,
+        "d6a4188c7a12a966f86cb76dd993169628820394482d5577dfb57302415f6201".parse()?,
+        1_700_000_000,
+        0x1f00ffff,
+    );
+
+    let hex_round_trip = benchmark(|nonce| {
+        let mut header = header.clone();
+        header.nonce = nonce;
+        let header_bytes = hex::decode(header.to_hex()).unwrap();
+        double_sha256(&header_bytes)[0]
+    });
+
+    let mut header_bytes = header.serialize();
+    let binary = benchmark(|nonce| {
+        header_bytes[76..].copy_from_slice(&nonce.to_le_bytes());
+        double_sha256(&header_bytes)[0]
+    });
+
+    let mut hasher = HeaderHasher::new(&header.serialize());
+    let midstate = benchmark(|nonce| hasher.hash(nonce)[0]);
+
+    println!("Header hashing benchmark ({ITERATIONS} hashes per strategy)");
+    for (name, hashrate) in [
+        ("to_hex + hex::decode", hex_round_trip),
+        ("serialize", binary),
+        ("midstate", midstate),
+    ] {
+        println!(
+            "  {:<22} {:>8.3} MH/s  ({:.2}x)",
+            name,
+            hashrate / 1_000_000.0,
+            hashrate / hex_round_trip
+        );
+    }
+
+    Ok(())
+}
+
+/// Hashes per second of `hash_fn` over `ITERATIONS` nonces
+fn benchmark(mut hash_fn: impl FnMut(u32) -> u8) -> f64 {
+    let started = Instant::now();
+    let mut sink = 0u8;
+    for nonce in 0..ITERATIONS {
+        sink ^= hash_fn(nonce);
+    }
+    std::hint::black_box(sink);
+
+    ITERATIONS as f64 / started.elapsed().as_secs_f64()
+}
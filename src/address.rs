@@ -0,0 +1,474 @@
+use anyhow::{anyhow, Result};
+
+use crate::block::double_sha256;
+use crate::network::Network;
+use crate::script::{
+    is_p2sh, script_type, witness_program, OP_0, OP_1, OP_CHECKSIG, OP_DUP, OP_EQUAL,
+    OP_EQUALVERIFY, OP_HASH160,
+};
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_ALPHABET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Checksum constants of BIP173 (bech32, witness v0) and BIP350 (bech32m, witness v1+)
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+struct AddressParams {
+    pubkey_hash_prefix: u8,
+    script_hash_prefix: u8,
+    bech32_hrp: &'static str,
+}
+
+fn address_params(network: Network) -> AddressParams {
+    match network {
+        Network::Mainnet => AddressParams {
+            pubkey_hash_prefix: 0x00,
+            script_hash_prefix: 0x05,
+            bech32_hrp: "bc",
+        },
+        Network::Testnet => AddressParams {
+            pubkey_hash_prefix: 0x6f,
+            script_hash_prefix: 0xc4,
+            bech32_hrp: "tb",
+        },
+        Network::Regtest => AddressParams {
+            pubkey_hash_prefix: 0x6f,
+            script_hash_prefix: 0xc4,
+            bech32_hrp: "bcrt",
+        },
+    }
+}
+
+/// Address of an output script, `None` for scripts without a standard address
+/// (P2PK, OP_RETURN and non-standard scripts)
+pub fn script_to_address(script: &[u8], network: Network) -> Option<String> {
+    let params = address_params(network);
+
+    if script_type(script) == "p2pkh" {
+        return Some(base58check_encode(
+            params.pubkey_hash_prefix,
+            &script[3..23],
+        ));
+    }
+    if is_p2sh(script) {
+        return Some(base58check_encode(
+            params.script_hash_prefix,
+            &script[2..22],
+        ));
+    }
+
+    let (version, program) = witness_program(script)?;
+    segwit_encode(params.bech32_hrp, version, program)
+}
+
+/// Output script paying to `address`, the inverse of `script_to_address`
+pub fn address_to_script(address: &str, network: Network) -> Result<Vec<u8>> {
+    let params = address_params(network);
+
+    let hrp_prefix = format!("{}1", params.bech32_hrp);
+    if address.to_lowercase().starts_with(&hrp_prefix) {
+        let (version, program) = segwit_decode(params.bech32_hrp, address)?;
+        let version_opcode = match version {
+            0 => OP_0,
+            _ => OP_1 + version - 1,
+        };
+
+        let mut script = vec![version_opcode, program.len() as u8];
+        script.extend_from_slice(&program);
+        return Ok(script);
+    }
+
+    let payload = base58check_decode(address)?;
+    let (prefix, hash) = match payload.split_first() {
+        Some((prefix, hash)) if hash.len() == 20 => (*prefix, hash),
+        _ => return Err(anyhow!("{address} does not encode a 20 byte hash")),
+    };
+
+    if prefix == params.pubkey_hash_prefix {
+        Ok([
+            &[OP_DUP, OP_HASH160, 0x14][..],
+            hash,
+            &[OP_EQUALVERIFY, OP_CHECKSIG],
+        ]
+        .concat())
+    } else if prefix == params.script_hash_prefix {
+        Ok([&[OP_HASH160, 0x14][..], hash, &[OP_EQUAL]].concat())
+    } else {
+        Err(anyhow!("{address} is not a {network:?} address"))
+    }
+}
+
+fn base58check_encode(prefix: u8, payload: &[u8]) -> String {
+    let mut data = vec![prefix];
+    data.extend_from_slice(payload);
+    let checksum = double_sha256(&data);
+    data.extend_from_slice(&checksum[..4]);
+
+    base58_encode(&data)
+}
+
+fn base58_encode(data: &[u8]) -> String {
+    // Repeated division of the big endian number by 58, digits come out least significant first
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in data {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    // Every leading zero byte is written as a '1'
+    let leading_zeros = data.iter().take_while(|byte| **byte == 0).count();
+    std::iter::repeat_n(b'1', leading_zeros)
+        .chain(
+            digits
+                .iter()
+                .rev()
+                .map(|digit| BASE58_ALPHABET[*digit as usize]),
+        )
+        .map(char::from)
+        .collect()
+}
+
+fn base58check_decode(address: &str) -> Result<Vec<u8>> {
+    let data = base58_decode(address)?;
+    if data.len() < 4 {
+        return Err(anyhow!("{address} is too short"));
+    }
+
+    let (payload, checksum) = data.split_at(data.len() - 4);
+    if double_sha256(payload)[..4] != *checksum {
+        return Err(anyhow!("{address} has an invalid checksum"));
+    }
+    Ok(payload.to_vec())
+}
+
+fn base58_decode(encoded: &str) -> Result<Vec<u8>> {
+    // Repeated multiplication by 58, bytes come out least significant first
+    let mut bytes: Vec<u8> = Vec::new();
+    for character in encoded.bytes() {
+        let mut carry = BASE58_ALPHABET
+            .iter()
+            .position(|candidate| *candidate == character)
+            .ok_or_else(|| anyhow!("Invalid base58 character {:?}", character as char))?
+            as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+
+    // Every leading '1' is a zero byte
+    let leading_zeros = encoded.bytes().take_while(|byte| *byte == b'1').count();
+    Ok(std::iter::repeat_n(0, leading_zeros)
+        .chain(bytes.into_iter().rev())
+        .collect())
+}
+
+fn segwit_decode(hrp: &str, address: &str) -> Result<(u8, Vec<u8>)> {
+    if address.to_lowercase() != address && address.to_uppercase() != address {
+        return Err(anyhow!("{address} mixes upper and lower case"));
+    }
+    let address = address.to_lowercase();
+
+    let data = address[hrp.len() + 1..]
+        .bytes()
+        .map(|character| {
+            BECH32_ALPHABET
+                .iter()
+                .position(|candidate| *candidate == character)
+                .map(|value| value as u8)
+                .ok_or_else(|| anyhow!("Invalid bech32 character {:?}", character as char))
+        })
+        .collect::<Result<Vec<u8>>>()?;
+    if data.len() < 7 {
+        return Err(anyhow!("{address} is too short"));
+    }
+
+    let (payload, checksum) = data.split_at(data.len() - 6);
+    let version = payload[0];
+    let checksum_const = match version {
+        0 => BECH32_CONST,
+        _ => BECH32M_CONST,
+    };
+    if bech32_checksum(hrp, payload, checksum_const) != checksum {
+        return Err(anyhow!("{address} has an invalid checksum"));
+    }
+
+    let program = convert_bits(&payload[1..], 5, 8)
+        .ok_or_else(|| anyhow!("{address} has an invalid witness program"))?;
+    // Drop the zero padding convert_bits adds to the last group
+    let program = program[..(payload.len() - 1) * 5 / 8].to_vec();
+
+    // Round trip to reject invalid versions and program lengths
+    if segwit_encode(hrp, version, &program).as_deref() != Some(address.as_str()) {
+        return Err(anyhow!("{address} is not a valid segwit address"));
+    }
+    Ok((version, program))
+}
+
+fn segwit_encode(hrp: &str, version: u8, program: &[u8]) -> Option<String> {
+    if version > 16 || !(2..=40).contains(&program.len()) {
+        return None;
+    }
+    if version == 0 && program.len() != 20 && program.len() != 32 {
+        return None;
+    }
+
+    let mut data = vec![version];
+    data.extend(convert_bits(program, 8, 5)?);
+
+    let checksum_const = match version {
+        0 => BECH32_CONST,
+        _ => BECH32M_CONST,
+    };
+    let checksum = bech32_checksum(hrp, &data, checksum_const);
+
+    let mut address = format!("{hrp}1");
+    for value in data.iter().chain(checksum.iter()) {
+        address.push(BECH32_ALPHABET[*value as usize] as char);
+    }
+    Some(address)
+}
+
+/// Regroup `data` from `from`-bit to `to`-bit values, padding the last group with zeros
+fn convert_bits(data: &[u8], from: u32, to: u32) -> Option<Vec<u8>> {
+    let mut accumulator = 0u32;
+    let mut bits = 0u32;
+    let max_value = (1u32 << to) - 1;
+    let mut result = Vec::new();
+
+    for &value in data {
+        if (value as u32) >> from != 0 {
+            return None;
+        }
+        accumulator = (accumulator << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((accumulator >> bits) & max_value) as u8);
+        }
+    }
+    if bits > 0 {
+        result.push(((accumulator << (to - bits)) & max_value) as u8);
+    }
+
+    Some(result)
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+    let mut checksum = 1u32;
+    for &value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+fn bech32_checksum(hrp: &str, data: &[u8], checksum_const: u32) -> [u8; 6] {
+    // The human readable part is expanded into its high bits, a zero and its low bits
+    let mut values: Vec<u8> = hrp.bytes().map(|byte| byte >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|byte| byte & 0x1f));
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; 6]);
+
+    let polymod = bech32_polymod(&values) ^ checksum_const;
+    let mut checksum = [0u8; 6];
+    for (i, value) in checksum.iter_mut().enumerate() {
+        *value = ((polymod >> (5 * (5 - i))) & 0x1f) as u8;
+    }
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Valid addresses of BIP350 (which replaces the BIP173 list) and Bitcoin Core's
+    /// `key_io_valid.json`, with the script they pay to
+    const VALID_ADDRESSES: [(&str, Network, &str); 12] = [
+        (
+            "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4",
+            Network::Mainnet,
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+        ),
+        (
+            "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+            Network::Testnet,
+            "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+        ),
+        (
+            "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+            Network::Mainnet,
+            "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6",
+        ),
+        ("BC1SW50QGDZ25J", Network::Mainnet, "6002751e"),
+        (
+            "bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs",
+            Network::Mainnet,
+            "5210751e76e8199196d454941c45d1b3a323",
+        ),
+        (
+            "tb1qqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesrxh6hy",
+            Network::Testnet,
+            "0020000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433",
+        ),
+        (
+            "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+            Network::Testnet,
+            "5120000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433",
+        ),
+        (
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+            Network::Mainnet,
+            "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        ),
+        (
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH",
+            Network::Mainnet,
+            "76a914751e76e8199196d454941c45d1b3a323f1433bd688ac",
+        ),
+        (
+            "1AGNa15ZQXAZUgFiqJ2i7Z2DPU2J6hW62i",
+            Network::Mainnet,
+            "76a91465a16059864a2fdbc7c99a4723a8395bc6f188eb88ac",
+        ),
+        (
+            "3CMNFxN1oHBc4R1EpboAL5yzHGgE611Xou",
+            Network::Mainnet,
+            "a91474f209f6ea907e2ea48f74fae05782ae8a66525787",
+        ),
+        (
+            "mo9ncXisMeAoXwqcV5EWuyncbmCcQN4rVs",
+            Network::Testnet,
+            "76a91453c0307d6851aa0ce7825ba883c6bd9ad242b48688ac",
+        ),
+    ];
+
+    /// Invalid addresses of BIP350 and a few broken base58check ones
+    const INVALID_ADDRESSES: [(&str, Network); 19] = [
+        // Invalid human readable part
+        (
+            "tc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq5zuyut",
+            Network::Testnet,
+        ),
+        // bech32 checksum on a witness v1+ address
+        (
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+            Network::Mainnet,
+        ),
+        (
+            "tb1z0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqglt7rf",
+            Network::Testnet,
+        ),
+        (
+            "BC1S0XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ54WELL",
+            Network::Mainnet,
+        ),
+        // bech32m checksum on a witness v0 address
+        (
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+            Network::Mainnet,
+        ),
+        (
+            "tb1q0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq24jc47",
+            Network::Testnet,
+        ),
+        // Invalid character
+        (
+            "bc1p38j9r5y49hruaue7wxjce0updqjuyyx0kh56v8s25huc6995vvpql3jow4",
+            Network::Mainnet,
+        ),
+        // Witness version 17
+        (
+            "BC130XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ7ZWS8R",
+            Network::Mainnet,
+        ),
+        // Program of 1, 41 and (for v0) 16 bytes
+        ("bc1pw5dgrnzv", Network::Mainnet),
+        (
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7v8n0nx0muaewav253zgeav",
+            Network::Mainnet,
+        ),
+        ("BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P", Network::Mainnet),
+        // Mixed case
+        (
+            "tb1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq47Zagq",
+            Network::Testnet,
+        ),
+        // More than 4 bits of padding, non-zero padding
+        (
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7v07qwwzcrf",
+            Network::Mainnet,
+        ),
+        (
+            "tb1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vpggkg4j",
+            Network::Testnet,
+        ),
+        // Empty data
+        ("bc1gmk9yu", Network::Mainnet),
+        // Base58check with a changed last character, an invalid character and a testnet
+        // address on mainnet
+        ("1AGNa15ZQXAZUgFiqJ2i7Z2DPU2J6hW62j", Network::Mainnet),
+        ("1AGNa15ZQXAZUgFiqJ2i7Z2DPU2J6hW620", Network::Mainnet),
+        ("mo9ncXisMeAoXwqcV5EWuyncbmCcQN4rVs", Network::Mainnet),
+        ("", Network::Mainnet),
+    ];
+
+    #[test]
+    fn valid_addresses_round_trip() -> Result<()> {
+        for (address, network, script_hex) in VALID_ADDRESSES {
+            let script = address_to_script(address, network)?;
+            assert_eq!(hex::encode(&script), script_hex, "address {address}");
+
+            let encoded = script_to_address(&script, network);
+            let expected = match address.starts_with(['b', 'B', 't']) {
+                true => address.to_lowercase(),
+                false => address.to_string(),
+            };
+            assert_eq!(encoded, Some(expected), "script {script_hex}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_addresses_are_rejected() {
+        for (address, network) in INVALID_ADDRESSES {
+            assert!(
+                address_to_script(address, network).is_err(),
+                "address {address}"
+            );
+        }
+    }
+
+    #[test]
+    fn scripts_without_address() -> Result<()> {
+        // P2PK, OP_RETURN and a 1 byte witness program
+        let p2pk = format!("21{}ac", "02".repeat(33));
+        for script_hex in [p2pk.as_str(), "6a0401020304", "5101aa", ""] {
+            let script = hex::decode(script_hex)?;
+            assert_eq!(script_to_address(&script, Network::Mainnet), None);
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,30 @@
-use anyhow::Result;
-use byteorder::{LittleEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::io::Read;
+
+use anyhow::{anyhow, Result};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+use crate::encoding::{read_compact_size, write_compact_size};
+use crate::hash::{BlockHash, MerkleNode, OutPoint, Txid, Wtxid};
+use crate::merkle::compute_merkle_root;
 use crate::mine;
+use crate::network::Network;
+use crate::raw_tx::describe_output;
+use crate::script::{push_int, to_asm};
 use crate::validation::{Input, Output, PrevOut, Transaction};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Bytes reserved at the end of the coinbase scriptsig for the extranonce
+pub const EXTRANONCE_SIZE: usize = 8;
+
+/// OP_RETURN, push 36 bytes, then the commitment header 0xaa21a9ed (BIP141)
+pub const WITNESS_COMMITMENT_HEADER: &str = "6a24aa21a9ed";
+
+/// Witness reserved value placed in the coinbase witness
+pub(crate) const WITNESS_RESERVED_VALUE: [u8; 32] = [0u8; 32];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
     pub header: Header,
     pub transactions: Vec<Transaction>,
@@ -14,44 +32,274 @@ pub struct Block {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
-    version: u32,
-    previous_block_hash: String,
-    merkle_root: String,
-    time: u32,
-    pub(crate) bits: u32,
+    pub version: u32,
+    pub previous_block_hash: BlockHash,
+    pub merkle_root: MerkleNode,
+    pub time: u32,
+    pub bits: u32,
     pub nonce: u32,
 }
 impl Header {
-    pub(crate) fn to_hex(&self) -> Result<String> {
-        let mut header_bytes = Vec::with_capacity(80);
+    /// Serialize the header into its 80 byte wire format
+    pub fn serialize(&self) -> [u8; 80] {
+        let mut header_bytes = [0u8; 80];
+
+        header_bytes[0..4].copy_from_slice(&self.version.to_le_bytes());
+        header_bytes[4..36].copy_from_slice(self.previous_block_hash.as_bytes());
+        header_bytes[36..68].copy_from_slice(self.merkle_root.as_bytes());
+        header_bytes[68..72].copy_from_slice(&self.time.to_le_bytes());
+        header_bytes[72..76].copy_from_slice(&self.bits.to_le_bytes());
+        header_bytes[76..80].copy_from_slice(&self.nonce.to_le_bytes());
+
+        header_bytes
+    }
 
-        header_bytes.write_u32::<LittleEndian>(self.version)?;
-        header_bytes.extend_from_slice(&hex::decode(&self.previous_block_hash)?);
-        header_bytes.extend_from_slice(&hex::decode(&self.merkle_root)?);
-        header_bytes.write_u32::<LittleEndian>(self.time)?;
-        header_bytes.write_u32::<LittleEndian>(self.bits)?;
-        header_bytes.write_u32::<LittleEndian>(self.nonce)?;
+    /// Parse the 80 byte wire format
+    pub fn deserialize(header_bytes: &[u8; 80]) -> Header {
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(header_bytes[offset..offset + 4].try_into().unwrap())
+        };
+        let hash_at =
+            |offset: usize| -> [u8; 32] { header_bytes[offset..offset + 32].try_into().unwrap() };
 
-        Ok(hex::encode(header_bytes))
+        Header {
+            version: u32_at(0),
+            previous_block_hash: BlockHash::from_byte_array(hash_at(4)),
+            merkle_root: MerkleNode::from_byte_array(hash_at(36)),
+            time: u32_at(68),
+            bits: u32_at(72),
+            nonce: u32_at(76),
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.serialize())
+    }
+
+    pub fn block_hash(&self) -> BlockHash {
+        BlockHash::hash(&self.serialize())
     }
 }
 
+impl Block {
+    /// Raw block: header, transaction count and the witness serialized transactions,
+    /// as accepted by `submitblock`
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut bytes = self.header.serialize().to_vec();
+        write_compact_size(&mut bytes, self.transactions.len() as u64);
+        for transaction in &self.transactions {
+            bytes.extend_from_slice(&transaction.serialize(true)?);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Parse a raw block, all bytes have to be consumed
+    pub fn deserialize(bytes: &[u8]) -> Result<Block> {
+        let mut reader = bytes;
+
+        let mut header_bytes = [0u8; 80];
+        reader.read_exact(&mut header_bytes)?;
+        let header = Header::deserialize(&header_bytes);
+
+        let tx_count = read_compact_size(&mut reader)?;
+        let mut transactions = Vec::new();
+        for _ in 0..tx_count {
+            transactions.push(Transaction::deserialize(&mut reader)?);
+        }
+
+        if !reader.is_empty() {
+            return Err(anyhow!("{} trailing bytes after the block", reader.len()));
+        }
+
+        Ok(Block {
+            header,
+            transactions,
+        })
+    }
+
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(self.serialize()?))
+    }
+
+    /// Raw block hex split into annotated sections (header, tx count, one line per transaction)
+    /// for comparing blocks byte for byte with other implementations
+    pub fn hex_dump(&self) -> Result<String> {
+        let mut tx_count = Vec::new();
+        write_compact_size(&mut tx_count, self.transactions.len() as u64);
+
+        let mut lines = vec![
+            format!("header   {}", self.header.to_hex()),
+            format!("tx count {}", hex::encode(tx_count)),
+        ];
+        for (index, transaction) in self.transactions.iter().enumerate() {
+            lines.push(format!(
+                "tx {:<5} {} ({})",
+                index,
+                hex::encode(transaction.serialize(true)?),
+                transaction.id()?
+            ));
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    /// Write `extranonce` into the coinbase scriptsig and recompute the merkle root
+    pub fn roll_extranonce(&mut self, extranonce: u64) -> Result<()> {
+        if let Some(coinbase_input) = self
+            .transactions
+            .first_mut()
+            .and_then(|coinbase| coinbase.vin.first_mut())
+        {
+            // The extranonce push is always the last EXTRANONCE_SIZE bytes of the scriptsig
+            let prefix_len = coinbase_input
+                .scriptsig
+                .len()
+                .saturating_sub(EXTRANONCE_SIZE * 2);
+            coinbase_input.scriptsig.truncate(prefix_len);
+            coinbase_input
+                .scriptsig
+                .push_str(&hex::encode(extranonce.to_le_bytes()));
+            coinbase_input.scriptsig_asm = hex::decode(&coinbase_input.scriptsig)
+                .map(|scriptsig| to_asm(&scriptsig))
+                .unwrap_or_default();
+        }
+        self.header.merkle_root = calculate_merkle_root(&self.transactions)?.0;
+        Ok(())
+    }
+}
+
+/// Build a block from the coinbase followed by the selected transactions. Transactions are
+/// reordered so parents come before their children and the witness commitment is added to
+/// the coinbase when any transaction carries witness data.
 pub fn create_block(
     transactions: Vec<Transaction>,
-    previous_block_hash: String,
+    previous_block_hash: BlockHash,
     time: u32,
     bits_decompressed: primitive_types::U256,
-) -> Block {
-    let merkle_root = calculate_merkle_root(&transactions);
+) -> Result<Block> {
+    let mut transactions = order_parents_first(transactions);
+    add_witness_commitment(&mut transactions)?;
+
+    // Mutation is left to block validation, which rejects duplicated transactions
+    let (merkle_root, _) = calculate_merkle_root(&transactions)?;
     let bits_compressed = mine::compress_target(bits_decompressed);
+    let effective_target = mine::expand_target(bits_compressed);
+    if effective_target != bits_decompressed {
+        // The block is mined against the target encoded in `bits`, not the requested one
+        warn!(
+            "Target {:x} is not exactly representable as compact bits, using {:x}",
+            bits_decompressed, effective_target
+        );
+    }
     let header = create_header(previous_block_hash, merkle_root, time, bits_compressed);
-    Block {
+    Ok(Block {
         header,
         transactions,
+    })
+}
+
+/// Keep the coinbase first and move every transaction behind the in-block parents it spends
+fn order_parents_first(transactions: Vec<Transaction>) -> Vec<Transaction> {
+    let index_by_txid: HashMap<Txid, usize> = transactions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, tx)| Some((tx.id().ok()?, index)))
+        .collect();
+
+    let mut order = Vec::with_capacity(transactions.len());
+    let mut visited = vec![false; transactions.len()];
+    for index in 0..transactions.len() {
+        visit_parents_first(
+            index,
+            &transactions,
+            &index_by_txid,
+            &mut visited,
+            &mut order,
+        );
+    }
+
+    let mut slots: Vec<Option<Transaction>> = transactions.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .filter_map(|index| slots[index].take())
+        .collect()
+}
+
+fn visit_parents_first(
+    index: usize,
+    transactions: &[Transaction],
+    index_by_txid: &HashMap<Txid, usize>,
+    visited: &mut [bool],
+    order: &mut Vec<usize>,
+) {
+    if visited[index] {
+        return;
+    }
+    visited[index] = true;
+
+    for input in &transactions[index].vin {
+        if let Some(&parent) = index_by_txid.get(&input.txid) {
+            visit_parents_first(parent, transactions, index_by_txid, visited, order);
+        }
     }
+    order.push(index);
 }
 
-fn create_header(previous_block_hash: String, merkle_root: String, time: u32, bits: u32) -> Header {
+/// Append the BIP141 commitment to the witness merkle root as an OP_RETURN coinbase output
+fn add_witness_commitment(transactions: &mut [Transaction]) -> Result<()> {
+    if !transactions.iter().skip(1).any(|tx| tx.has_witness()) {
+        return Ok(());
+    }
+
+    let commitment = calculate_witness_commitment(transactions, &WITNESS_RESERVED_VALUE)?;
+    if let Some(coinbase) = transactions.first_mut() {
+        if let Some(coinbase_input) = coinbase.vin.first_mut() {
+            coinbase_input.witness = vec![hex::encode(WITNESS_RESERVED_VALUE)];
+        }
+        coinbase.vout.push(Output {
+            scriptpubkey: format!("{}{}", WITNESS_COMMITMENT_HEADER, hex::encode(&commitment)),
+            scriptpubkey_asm: format!(
+                "OP_RETURN OP_PUSHBYTES_36 {}{}",
+                &WITNESS_COMMITMENT_HEADER[4..],
+                hex::encode(&commitment)
+            ),
+            scriptpubkey_type: "op_return".to_string(),
+            scriptpubkey_address: String::new(),
+            value: 0,
+        });
+    }
+    Ok(())
+}
+
+/// HASH256(witness merkle root || witness reserved value)
+pub fn calculate_witness_commitment(
+    transactions: &[Transaction],
+    witness_reserved_value: &[u8],
+) -> Result<Vec<u8>> {
+    // The coinbase wtxid is defined as all zeros
+    let wtxids = transactions
+        .iter()
+        .enumerate()
+        .map(|(index, tx)| match index {
+            0 => Ok(Wtxid::ZERO.into()),
+            _ => Ok(tx.wtxid()?.into()),
+        })
+        .collect::<Result<Vec<MerkleNode>>>()?;
+
+    // Like Bitcoin Core, mutation only matters for the txid tree
+    let (witness_root, _) = compute_merkle_root(wtxids)?;
+    Ok(double_sha256(
+        &[witness_root.as_bytes(), witness_reserved_value].concat(),
+    ))
+}
+
+pub fn create_header(
+    previous_block_hash: BlockHash,
+    merkle_root: MerkleNode,
+    time: u32,
+    bits: u32,
+) -> Header {
     Header {
         version: 4,
         previous_block_hash,
@@ -62,89 +310,56 @@ fn create_header(previous_block_hash: String, merkle_root: String, time: u32, bi
     }
 }
 
-fn calculate_merkle_root(transactions: &[Transaction]) -> String {
-    if transactions.is_empty() {
-        return "".to_string();
-    }
-
-    // Reverse transaction IDs for the first level
-    let mut rev_txids_level = transactions
+/// Merkle root of the txids of `transactions`, and whether the tree was mutated by
+/// duplicated transactions (see `merkle::compute_merkle_root`)
+pub fn calculate_merkle_root(transactions: &[Transaction]) -> Result<(MerkleNode, bool)> {
+    let txids = transactions
         .iter()
-        .map(|tx| tx.id().unwrap())
-        .map(|txid_hex| hex::decode(txid_hex).unwrap())
-        .map(|txid_bytes| txid_bytes.iter().rev().cloned().collect::<Vec<u8>>())
-        .collect::<Vec<Vec<u8>>>();
-
-    while rev_txids_level.len() > 1 {
-        let mut rev_txids_next_level = Vec::new();
-
-        let len = rev_txids_level.len();
-        for i in (0..len).step_by(2) {
-            let pair = match i + 1 == len {
-                true => double_sha256(
-                    &[
-                        rev_txids_level.get(i).unwrap().as_slice(),
-                        rev_txids_level.get(i).unwrap().as_slice(),
-                    ]
-                    .concat(),
-                ),
-                false => double_sha256(
-                    &[
-                        rev_txids_level.get(i).unwrap().as_slice(),
-                        rev_txids_level.get(i + 1).unwrap().as_slice(),
-                    ]
-                    .concat(),
-                ),
-            };
-            rev_txids_next_level.push(pair);
-        }
-
-        rev_txids_level = rev_txids_next_level;
-    }
+        .map(|tx| Ok(tx.id()?.into()))
+        .collect::<Result<Vec<MerkleNode>>>()?;
 
-    hex::encode(rev_txids_level.first().unwrap())
+    compute_merkle_root(txids)
 }
 
-pub(crate) fn sha256(data: &[u8]) -> Vec<u8> {
+pub fn sha256(data: &[u8]) -> Vec<u8> {
     let mut hasher = Sha256::new();
     hasher.update(data);
     hasher.finalize().to_vec()
 }
 
-pub(crate) fn double_sha256(data: &[u8]) -> Vec<u8> {
+pub fn double_sha256(data: &[u8]) -> Vec<u8> {
     let first = sha256(data);
     sha256(&first)
 }
 
-pub(crate) fn create_coinbase_transaction(reward: u64, miner_address: &str) -> Transaction {
-    Transaction {
+/// Coinbase paying each `(script, value)` of `payouts`, the scriptsig holds the BIP34 height
+/// followed by an extranonce which `Block::roll_extranonce` updates
+pub fn create_coinbase_transaction(
+    block_height: u32,
+    payouts: &[(Vec<u8>, u64)],
+    network: Network,
+) -> Result<Transaction> {
+    let mut scriptsig = push_int(block_height as i64);
+    scriptsig.push(EXTRANONCE_SIZE as u8);
+    scriptsig.extend_from_slice(&[0u8; EXTRANONCE_SIZE]);
+
+    Ok(Transaction {
         version: 1,
         locktime: 0,
         vin: vec![Input {
-            txid: String::new(), // No input transaction (new coins)
-            vout: 0,
-            prevout: PrevOut {
-                scriptpubkey: String::new(),
-                scriptpubkey_asm: String::new(),
-                scriptpubkey_type: String::new(),
-                scriptpubkey_address: String::new(),
-                value: 0,
-            },
-            scriptsig: String::new(), // No signature script for coinbase
-            scriptsig_asm: String::new(),
+            // No input transaction (new coins)
+            txid: OutPoint::NULL.txid,
+            vout: OutPoint::NULL.vout,
+            prevout: PrevOut::default(),
+            scriptsig_asm: to_asm(&scriptsig),
+            scriptsig: hex::encode(scriptsig),
             witness: vec![],
             is_coinbase: true,
             sequence: 0xFFFFFFFF,
         }],
-        vout: vec![Output {
-            scriptpubkey: format!(
-                "OP_DUP OP_HASH160 {} OP_EQUALVERIFY OP_CHECKSIG",
-                miner_address
-            ),
-            scriptpubkey_asm: String::new(),
-            scriptpubkey_type: "pubkeyhash".to_string(),
-            scriptpubkey_address: miner_address.to_string(),
-            value: reward, // Block reward
-        }],
-    }
+        vout: payouts
+            .iter()
+            .map(|(script, value)| describe_output(&hex::encode(script), *value, network))
+            .collect::<Result<Vec<Output>>>()?,
+    })
 }
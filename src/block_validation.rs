@@ -0,0 +1,454 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+
+use crate::block::{
+    calculate_merkle_root, calculate_witness_commitment, Block, Header, WITNESS_COMMITMENT_HEADER,
+};
+use crate::encoding::compact_size_len;
+use crate::mine::derive_target;
+use crate::script::push_int;
+use crate::validation::{Transaction, WITNESS_SCALE_FACTOR};
+
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+pub const MAX_BLOCK_SIGOPS_COST: usize = 80_000;
+
+const COIN: u64 = 100_000_000;
+const SUBSIDY_HALVING_INTERVAL: u32 = 210_000;
+
+/// Block subsidy at `height`: 50 BTC halving every 210,000 blocks
+pub fn block_subsidy(height: u32) -> u64 {
+    let halvings = height / SUBSIDY_HALVING_INTERVAL;
+    if halvings >= 64 {
+        return 0;
+    }
+
+    (50 * COIN) >> halvings
+}
+
+/// Check a block mined (or received) at `height` against the consensus rules which can be
+/// verified without the chain: proof of work, coinbase, merkle root, witness commitment,
+/// weight and sigop limits, duplicate txids, in-block double spends, ordering and the
+/// coinbase value
+pub fn validate_block(block: &Block, height: u32) -> Result<()> {
+    validate_block_structure(block, height)?;
+    check_coinbase_value(&block.transactions, height)?;
+
+    Ok(())
+}
+
+/// All checks of `validate_block` which do not need the spent outputs, for raw blocks
+/// where the prevout values are unknown
+pub fn validate_block_structure(block: &Block, height: u32) -> Result<()> {
+    check_proof_of_work(&block.header)?;
+
+    let coinbase = block
+        .transactions
+        .first()
+        .ok_or_else(|| anyhow!("Block has no transactions"))?;
+    check_coinbase_structure(coinbase, height)?;
+    if block.transactions.iter().skip(1).any(|tx| tx.is_coinbase()) {
+        return Err(anyhow!("Block contains more than one coinbase"));
+    }
+
+    let (merkle_root, mutated) = calculate_merkle_root(&block.transactions)?;
+    if merkle_root != block.header.merkle_root {
+        return Err(anyhow!(
+            "Merkle root mismatch: header has {}, transactions give {}",
+            block.header.merkle_root,
+            merkle_root
+        ));
+    }
+    if mutated {
+        return Err(anyhow!(
+            "Merkle tree is mutated by duplicated transactions (CVE-2012-2459)"
+        ));
+    }
+
+    check_witness_commitment(&block.transactions)?;
+    check_weight_and_sigops(&block.transactions)?;
+    check_spends_and_ordering(&block.transactions)?;
+
+    Ok(())
+}
+
+pub fn check_proof_of_work(header: &Header) -> Result<()> {
+    let target = derive_target(header.bits)?;
+    let hash = header.block_hash();
+
+    if hash.to_u256() >= target {
+        return Err(anyhow!(
+            "Block hash {hash} does not meet the target of bits {:#010x}",
+            header.bits
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_coinbase_structure(coinbase: &Transaction, height: u32) -> Result<()> {
+    if !coinbase.is_coinbase() {
+        return Err(anyhow!("First transaction is not a coinbase"));
+    }
+
+    let input = &coinbase.vin[0];
+    if !input.outpoint().is_null() {
+        return Err(anyhow!("Coinbase input does not spend the null outpoint"));
+    }
+
+    let scriptsig = hex::decode(&input.scriptsig)?;
+    if !(2..=100).contains(&scriptsig.len()) {
+        return Err(anyhow!(
+            "Coinbase scriptsig length {} is outside 2..=100",
+            scriptsig.len()
+        ));
+    }
+
+    // BIP34: the scriptsig starts with the block height
+    if !scriptsig.starts_with(&push_int(height as i64)) {
+        return Err(anyhow!(
+            "Coinbase scriptsig does not start with height {height}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// The coinbase commitment has to match the witness merkle root of `transactions`
+pub fn check_witness_commitment(transactions: &[Transaction]) -> Result<()> {
+    let coinbase = transactions
+        .first()
+        .ok_or_else(|| anyhow!("Block has no transactions"))?;
+    let commitment_output = coinbase.vout.iter().rev().find(|output| {
+        output.scriptpubkey.len() >= 38 * 2
+            && output.scriptpubkey.starts_with(WITNESS_COMMITMENT_HEADER)
+    });
+
+    let Some(commitment_output) = commitment_output else {
+        // Without a commitment no transaction may carry witness data
+        if transactions.iter().any(|tx| tx.has_witness()) {
+            return Err(anyhow!("Block has witness data but no witness commitment"));
+        }
+        return Ok(());
+    };
+
+    let coinbase_witness = coinbase.vin.first().map(|input| input.witness.as_slice());
+    let reserved_value = match coinbase_witness {
+        Some([reserved_value]) => hex::decode(reserved_value)?,
+        _ => return Err(anyhow!("Coinbase witness must be a single reserved value")),
+    };
+    if reserved_value.len() != 32 {
+        return Err(anyhow!("Coinbase witness reserved value must be 32 bytes"));
+    }
+
+    let commitment = calculate_witness_commitment(transactions, &reserved_value)?;
+    let committed = &commitment_output.scriptpubkey
+        [WITNESS_COMMITMENT_HEADER.len()..WITNESS_COMMITMENT_HEADER.len() + 64];
+    if hex::encode(commitment) != committed {
+        return Err(anyhow!("Witness commitment mismatch"));
+    }
+
+    Ok(())
+}
+
+/// Weight of a block holding `transactions`, including header and transaction count
+pub fn block_weight(transactions: &[Transaction]) -> Result<usize> {
+    // Header and transaction count are not witness data
+    let mut weight = (80 + compact_size_len(transactions.len() as u64)) * WITNESS_SCALE_FACTOR;
+    for tx in transactions {
+        weight += tx.weight()?;
+    }
+    Ok(weight)
+}
+
+pub fn check_weight_and_sigops(transactions: &[Transaction]) -> Result<()> {
+    let weight = block_weight(transactions)?;
+    let mut sigop_cost = 0;
+    for tx in transactions {
+        sigop_cost += tx.sigop_cost()?;
+    }
+
+    if weight > MAX_BLOCK_WEIGHT {
+        return Err(anyhow!(
+            "Block weight {weight} exceeds the limit of {MAX_BLOCK_WEIGHT}"
+        ));
+    }
+    if sigop_cost > MAX_BLOCK_SIGOPS_COST {
+        return Err(anyhow!(
+            "Block sigop cost {sigop_cost} exceeds the limit of {MAX_BLOCK_SIGOPS_COST}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Every txid is unique, no outpoint is spent twice and parents come before their children
+pub fn check_spends_and_ordering(transactions: &[Transaction]) -> Result<()> {
+    let mut block_txids = HashSet::new();
+    for tx in transactions {
+        let txid = tx.id()?;
+        if !block_txids.insert(txid) {
+            return Err(anyhow!("Duplicate transaction {txid}"));
+        }
+    }
+
+    let mut seen_txids = HashSet::new();
+    let mut spent_outpoints = HashSet::new();
+    for tx in transactions.iter().skip(1) {
+        let txid = tx.id()?;
+        for input in &tx.vin {
+            if !spent_outpoints.insert(input.outpoint()) {
+                return Err(anyhow!(
+                    "Transaction {txid} double spends {}",
+                    input.outpoint()
+                ));
+            }
+            if block_txids.contains(&input.txid) && !seen_txids.contains(&input.txid) {
+                return Err(anyhow!(
+                    "Transaction {txid} spends {} which comes later in the block",
+                    input.txid
+                ));
+            }
+        }
+        seen_txids.insert(txid);
+    }
+
+    Ok(())
+}
+
+pub fn check_coinbase_value(transactions: &[Transaction], height: u32) -> Result<()> {
+    let coinbase = transactions
+        .first()
+        .ok_or_else(|| anyhow!("Block has no transactions"))?;
+
+    let mut fees = 0u64;
+    for tx in transactions.iter().skip(1) {
+        let total_input_value = sum_values(tx.vin.iter().map(|input| input.prevout.value))?;
+        let total_output_value = sum_values(tx.vout.iter().map(|output| output.value))?;
+        if total_output_value > total_input_value {
+            return Err(anyhow!(
+                "Transaction {} spends more than its inputs",
+                tx.id()?
+            ));
+        }
+        fees = sum_values([fees, total_input_value - total_output_value])?;
+    }
+
+    let allowed = sum_values([block_subsidy(height), fees])?;
+    let coinbase_value = sum_values(coinbase.vout.iter().map(|output| output.value))?;
+    if coinbase_value > allowed {
+        return Err(anyhow!(
+            "Coinbase pays {coinbase_value}, more than subsidy plus fees of {allowed}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Sum of the fees of `transactions`, an error on overflow
+pub fn total_fees<'a>(transactions: impl IntoIterator<Item = &'a Transaction>) -> Result<u64> {
+    transactions.into_iter().try_fold(0u64, |total, tx| {
+        total
+            .checked_add(tx.fee()?)
+            .ok_or_else(|| anyhow!("Value overflow"))
+    })
+}
+
+/// Sum of satoshi amounts, an error instead of wrapping around on overflow
+pub(crate) fn sum_values(values: impl IntoIterator<Item = u64>) -> Result<u64> {
+    values.into_iter().try_fold(0u64, |total, value| {
+        total
+            .checked_add(value)
+            .ok_or_else(|| anyhow!("Value overflow"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{create_block, create_coinbase_transaction};
+    use crate::hash::{BlockHash, Txid};
+    use crate::mine::{expand_target, mine_with_threads};
+    use crate::network::Network;
+    use crate::raw_tx::describe_output;
+    use crate::validation::{Input, PrevOut};
+
+    const REGTEST_BITS: u32 = 0x207fffff;
+
+    /// Transaction spending output `vout` of `txid`, worth `value`, paying a fee of 1,000
+    fn spend(txid: Txid, vout: u32, value: u64) -> Result<Transaction> {
+        Ok(Transaction {
+            version: 2,
+            locktime: 0,
+            vin: vec![Input {
+                txid,
+                vout,
+                prevout: PrevOut {
+                    scriptpubkey: "51".to_string(),
+                    value,
+                    ..PrevOut::default()
+                },
+                scriptsig: String::new(),
+                scriptsig_asm: String::new(),
+                witness: vec![],
+                is_coinbase: false,
+                sequence: 0xFFFFFFFF,
+            }],
+            vout: vec![describe_output("51", value - 1_000, Network::Regtest)?],
+        })
+    }
+
+    /// Mined regtest block at height 1 holding a coinbase and `transactions`
+    fn mined_block(transactions: Vec<Transaction>) -> Result<Block> {
+        let coinbase =
+            create_coinbase_transaction(1, &[(vec![0x51], block_subsidy(1))], Network::Regtest)?;
+        let transactions = [vec![coinbase], transactions].concat();
+        let block = create_block(
+            transactions,
+            BlockHash::ZERO,
+            1_700_000_000,
+            expand_target(REGTEST_BITS),
+        )?;
+        mine_with_threads(block, 1)
+    }
+
+    /// Recompute the merkle root of a changed block and mine it again
+    fn remine(mut block: Block) -> Result<Block> {
+        block.header.merkle_root = calculate_merkle_root(&block.transactions)?.0;
+        mine_with_threads(block, 1)
+    }
+
+    fn validation_error(block: &Block) -> String {
+        validate_block(block, 1).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn empty_block_is_an_error() {
+        assert!(check_coinbase_value(&[], 0).is_err());
+        assert!(check_witness_commitment(&[]).is_err());
+    }
+
+    #[test]
+    fn value_overflow_is_an_error() -> Result<()> {
+        let payout_script = vec![0x51];
+        let coinbase = create_coinbase_transaction(
+            1,
+            &[(payout_script.clone(), u64::MAX), (payout_script, 1)],
+            Network::Regtest,
+        )?;
+        assert!(check_coinbase_value(&[coinbase], 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fee_overflow_is_an_error() -> Result<()> {
+        let mut overflowing_inputs = spend(Txid::hash(b"first"), 0, u64::MAX)?;
+        overflowing_inputs
+            .vin
+            .push(overflowing_inputs.vin[0].clone());
+        overflowing_inputs.vin[1].vout = 1;
+        assert!(overflowing_inputs.fee().is_err());
+
+        let mut overspending = spend(Txid::hash(b"second"), 0, 10_000)?;
+        overspending.vout[0].value = 10_001;
+        assert!(overspending.fee().is_err());
+
+        let mut large_fee = spend(Txid::hash(b"third"), 0, u64::MAX)?;
+        large_fee.vout[0].value = 0;
+        assert_eq!(large_fee.fee()?, u64::MAX);
+        assert!(total_fees([&large_fee, &large_fee]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn duplicated_transactions_are_rejected() -> Result<()> {
+        let first = spend(Txid::hash(b"first"), 0, 10_000)?;
+        let second = spend(Txid::hash(b"second"), 0, 10_000)?;
+        let mut block = mined_block(vec![first, second.clone()])?;
+        validate_block(&block, 1)?;
+
+        // Repeating the last transaction keeps the merkle root and the proof of work
+        block.transactions.push(second);
+        assert_eq!(
+            calculate_merkle_root(&block.transactions)?.0,
+            block.header.merkle_root
+        );
+        assert!(validation_error(&block).contains("CVE-2012-2459"));
+        Ok(())
+    }
+
+    #[test]
+    fn insufficient_proof_of_work_is_rejected() -> Result<()> {
+        let mut block = mined_block(vec![])?;
+        while check_proof_of_work(&block.header).is_ok() {
+            block.header.nonce += 1;
+        }
+        assert!(validation_error(&block).contains("does not meet the target"));
+        Ok(())
+    }
+
+    #[test]
+    fn merkle_root_mismatch_is_rejected() -> Result<()> {
+        let mut block = mined_block(vec![spend(Txid::hash(b"first"), 0, 10_000)?])?;
+        block.header.merkle_root = Txid::hash(b"other").into();
+        let block = mine_with_threads(block, 1)?;
+        assert!(validation_error(&block).contains("Merkle root mismatch"));
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_txid_is_rejected() -> Result<()> {
+        // Not a duplicated last hash, so the merkle tree is not mutated
+        let first = spend(Txid::hash(b"first"), 0, 10_000)?;
+        let second = spend(Txid::hash(b"second"), 0, 10_000)?;
+        let block = mined_block(vec![first.clone(), second, first])?;
+        assert!(validation_error(&block).contains("Duplicate transaction"));
+        Ok(())
+    }
+
+    #[test]
+    fn in_block_double_spend_is_rejected() -> Result<()> {
+        let first = spend(Txid::hash(b"first"), 0, 10_000)?;
+        let mut conflict = first.clone();
+        conflict.locktime = 1;
+        let block = mined_block(vec![first, conflict])?;
+        assert!(validation_error(&block).contains("double spends"));
+        Ok(())
+    }
+
+    #[test]
+    fn child_before_parent_is_rejected() -> Result<()> {
+        let parent = spend(Txid::hash(b"parent"), 0, 10_000)?;
+        let child = spend(parent.id()?, 0, 9_000)?;
+        let mut block = mined_block(vec![parent, child])?;
+        validate_block(&block, 1)?;
+
+        block.transactions.swap(1, 2);
+        let block = remine(block)?;
+        assert!(validation_error(&block).contains("comes later in the block"));
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_witness_commitment_is_rejected() -> Result<()> {
+        let mut segwit = spend(Txid::hash(b"segwit"), 0, 10_000)?;
+        segwit.vin[0].witness = vec!["01".to_string()];
+        let mut block = mined_block(vec![segwit])?;
+        validate_block(&block, 1)?;
+
+        let commitment = &mut block.transactions[0].vout.last_mut().unwrap().scriptpubkey;
+        commitment.replace_range(commitment.len() - 2.., "00");
+        let block = remine(block)?;
+        assert!(validation_error(&block).contains("Witness commitment mismatch"));
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_bip34_height_is_rejected() -> Result<()> {
+        let block = mined_block(vec![])?;
+        validate_block(&block, 1)?;
+        let error = validate_block(&block, 2).unwrap_err();
+        assert!(error.to_string().contains("does not start with height 2"));
+        Ok(())
+    }
+}
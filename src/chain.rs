@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use log::info;
+
+use crate::block::Block;
+use crate::block_validation::{block_weight, total_fees, validate_block};
+use crate::config::Config;
+use crate::difficulty::{next_work_required, BlockInfo};
+use crate::hash::{BlockHash, Txid};
+use crate::mine::{expand_target, mine, mine_with_threads};
+use crate::network::{ChainParams, Network};
+use crate::pipeline::{assemble_block, current_time, load_valid_transactions};
+use crate::select::select_transactions;
+use crate::store::ChainStore;
+use crate::utxo::{UtxoDelta, UtxoSet};
+use crate::validation::Transaction;
+
+/// Number of blocks the median time past is taken over
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Tip, block history and UTXO set of a chain built on top of a base block
+#[derive(Debug, Clone)]
+pub struct ChainState {
+    pub params: ChainParams,
+    /// Hash of the last connected block, or of the base block
+    pub tip: BlockHash,
+    /// Height of the next block
+    pub height: u32,
+    /// Blocks connected on top of the base block, oldest first
+    pub blocks: Vec<BlockInfo>,
+    pub utxos: UtxoSet,
+}
+
+impl ChainState {
+    pub fn new(network: Network, tip: BlockHash, height: u32, utxos: UtxoSet) -> ChainState {
+        ChainState {
+            params: ChainParams::new(network),
+            tip,
+            height,
+            blocks: Vec::new(),
+            utxos,
+        }
+    }
+
+    /// Median time of the last 11 connected blocks, `None` before the first block
+    pub fn median_time_past(&self) -> Option<u32> {
+        let mut times: Vec<u32> = self
+            .blocks
+            .iter()
+            .rev()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|block| block.time)
+            .collect();
+        times.sort_unstable();
+        times.get(times.len() / 2).copied()
+    }
+
+    /// Compact target a block at `time` on top of the tip has to meet
+    pub fn next_work_required(&self, time: u32) -> u32 {
+        next_work_required(&self.blocks, time, &self.params)
+    }
+
+    /// Check `block` against the tip, the difficulty, the median time past and the UTXO set,
+    /// validate it and make it the new tip. The prevouts of its inputs are filled in from
+    /// the spent outputs. Returns the change of the UTXO set.
+    pub fn connect_block(&mut self, block: &mut Block) -> Result<UtxoDelta> {
+        let header = &block.header;
+        let hash = header.block_hash();
+        if header.previous_block_hash != self.tip {
+            return Err(anyhow!(
+                "Block {hash} builds on {}, the tip is {}",
+                header.previous_block_hash,
+                self.tip
+            ));
+        }
+
+        let bits = self.next_work_required(header.time);
+        if header.bits != bits {
+            return Err(anyhow!(
+                "Block {hash} has bits {:08x}, expected {bits:08x}",
+                header.bits
+            ));
+        }
+        if let Some(median_time_past) = self.median_time_past() {
+            if header.time <= median_time_past {
+                return Err(anyhow!(
+                    "Block {hash} time {} is not above the median time past {median_time_past}",
+                    header.time
+                ));
+            }
+        }
+
+        let delta = self.utxos.apply_block(block, self.height)?;
+        if let Err(e) = validate_block(block, self.height) {
+            self.utxos.undo(&delta);
+            return Err(e);
+        }
+
+        self.blocks.push(BlockInfo {
+            height: self.height,
+            time: block.header.time,
+            bits: block.header.bits,
+        });
+        self.tip = hash;
+        self.height += 1;
+        Ok(delta)
+    }
+
+    /// Undo `connect_block` of the tip `block`, which returned `delta`
+    pub fn disconnect_tip(&mut self, block: &Block, delta: &UtxoDelta) {
+        self.utxos.undo(delta);
+        self.blocks.pop();
+        self.tip = block.header.previous_block_hash;
+        self.height -= 1;
+    }
+}
+
+/// Mine `block_count` linked blocks on regtest difficulty. Every block takes the best
+/// transactions left in the mempool, its spends are applied to the UTXO set and whatever
+/// did not fit is carried forward to the next block.
+///
+/// With a `data_dir` the blocks and the UTXO set are stored there, and a chain already
+/// stored there is continued from its tip. Otherwise the chain starts on top of the
+/// configured chain tip and is kept in memory only.
+pub fn simulate_regtest_chain(
+    config: &Config,
+    block_count: u32,
+    data_dir: Option<&Path>,
+) -> Result<Vec<Block>> {
+    let mut mempool = load_valid_transactions(config)?;
+
+    let mut store = match data_dir {
+        Some(dir) if ChainStore::exists(dir) => {
+            let store = ChainStore::open(dir)?;
+            info!(
+                "Resuming the chain in {} at height {} on top of {}",
+                dir.display(),
+                store.state().height,
+                store.state().tip
+            );
+            store
+        }
+        Some(dir) => ChainStore::create(dir, initial_state(config, &mempool))?,
+        None => ChainStore::in_memory(initial_state(config, &mempool)),
+    };
+    if store.state().params.network != Network::Regtest {
+        return Err(anyhow!(
+            "The stored chain is on {:?}, not regtest",
+            store.state().params.network
+        ));
+    }
+    info!(
+        "Simulating {block_count} regtest blocks from height {}, {} UTXOs, {} mempool transactions",
+        store.state().height,
+        store.state().utxos.len(),
+        mempool.len()
+    );
+
+    let mut blocks = Vec::new();
+    let started = Instant::now();
+
+    for _ in 0..block_count {
+        let state = store.state();
+        let height = state.height;
+        let time = next_block_time(config, state);
+        let bits = state.next_work_required(time);
+
+        mempool = remove_unspendable(mempool, &state.utxos);
+        let selected = select_transactions(mempool.clone(), &config.policy);
+        let fees = total_fees(&selected)?;
+        let lowest_feerate = selected
+            .iter()
+            .filter_map(|tx| Some(tx.fee().ok()? as f64 * 4.0 / tx.weight().ok()? as f64))
+            .fold(None, |lowest: Option<f64>, rate| {
+                Some(lowest.map_or(rate, |lowest| lowest.min(rate)))
+            });
+
+        let block = assemble_block(
+            config,
+            selected,
+            height,
+            state.tip,
+            time,
+            expand_target(bits),
+        )?;
+        let mut block = match config.threads {
+            Some(threads) => mine_with_threads(block, threads)?,
+            None => mine(block)?,
+        };
+        store.append_block(&mut block)?;
+
+        let confirmed: HashSet<Txid> = block
+            .transactions
+            .iter()
+            .filter_map(|tx| tx.id().ok())
+            .collect();
+        mempool.retain(|tx| tx.id().is_ok_and(|txid| !confirmed.contains(&txid)));
+
+        info!(
+            "Block {height} {}: {} txs, {fees} sat fees, weight {}, lowest feerate {:.2} sat/vB, {} left in mempool",
+            store.state().tip,
+            block.transactions.len(),
+            block_weight(&block.transactions)?,
+            lowest_feerate.unwrap_or(0.0),
+            mempool.len()
+        );
+        blocks.push(block);
+    }
+
+    let state = store.state();
+    info!(
+        "Mined {} blocks in {:.2?}, {} UTXOs worth {} sat, {} transactions still unconfirmed",
+        blocks.len(),
+        started.elapsed(),
+        state.utxos.len(),
+        state.utxos.total_value(),
+        mempool.len()
+    );
+    Ok(blocks)
+}
+
+/// Regtest chain on top of the configured tip, owning the outputs the mempool spends
+fn initial_state(config: &Config, mempool: &[Transaction]) -> ChainState {
+    let mut utxos = UtxoSet::default();
+    utxos.add_mempool_prevouts(mempool, config.chain.height);
+    ChainState::new(
+        Network::Regtest,
+        config.chain.prev_hash,
+        config.chain.height,
+        utxos,
+    )
+}
+
+/// One target spacing after the previous block, but always above the median time past.
+/// The first block uses the configured time.
+fn next_block_time(config: &Config, state: &ChainState) -> u32 {
+    let (Some(last), Some(median_time_past)) = (state.blocks.last(), state.median_time_past())
+    else {
+        let time = config.chain.time.unwrap_or_else(current_time);
+        return match config.chain.median_time_past {
+            Some(median_time_past) => time.max(median_time_past + 1),
+            None => time,
+        };
+    };
+
+    (last.time + state.params.pow_target_spacing).max(median_time_past + 1)
+}
+
+/// Drop transactions spending outputs which are neither unspent nor created by another
+/// transaction left in the mempool, then their descendants, until nothing changes
+fn remove_unspendable(mut mempool: Vec<Transaction>, utxos: &UtxoSet) -> Vec<Transaction> {
+    let initial_count = mempool.len();
+    loop {
+        let mempool_txids: HashSet<Txid> = mempool.iter().filter_map(|tx| tx.id().ok()).collect();
+        let before = mempool.len();
+        mempool.retain(|tx| {
+            tx.vin.iter().all(|input| {
+                utxos.contains(&input.outpoint()) || mempool_txids.contains(&input.txid)
+            })
+        });
+
+        if mempool.len() == before {
+            break;
+        }
+    }
+
+    if mempool.len() < initial_count {
+        info!(
+            "Dropped {} transactions spending unavailable outputs",
+            initial_count - mempool.len()
+        );
+    }
+    mempool
+}
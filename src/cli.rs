@@ -0,0 +1,495 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Args, Parser, Subcommand};
+
+use code_challenge_2024_mirebella_v2::config::{parse_bits, parse_target, PayoutShare};
+use code_challenge_2024_mirebella_v2::{BlockHash, Config, Network, Txid, U256};
+
+/// Validate mempool transactions, build a block template from them and mine it.
+/// Without a subcommand the block is mined with the default settings.
+#[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+
+    #[command(flatten)]
+    pub(crate) mine: MineArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Validate the mempool and report how many transactions pass
+    Validate(InputArgs),
+    /// Report unparsable, non-JSON and duplicate mempool files and unknown fields
+    Diagnose {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check that every mempool file is named after the transaction it holds
+    VerifyFilenames(InputArgs),
+    /// Build the block without mining it and write it as raw hex
+    Build(BuildArgs),
+    /// Build the block and print it as getblocktemplate JSON
+    Template {
+        #[command(flatten)]
+        block: BlockArgs,
+        /// Write the template to this file instead of printing it
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Build and mine the block and write it in the grader format
+    Mine(MineArgs),
+    /// Check an output.txt against the mempool like the grader does and score it
+    VerifyBlock(VerifyBlockArgs),
+    /// Build the block and serve it to external miners over stratum v1 until it is solved
+    Stratum(StratumArgs),
+    /// Connect to a stratum server and mine on it, for trying the server locally
+    StratumMockClient {
+        #[arg(long, default_value = "127.0.0.1:3333")]
+        address: String,
+        #[arg(long, default_value = "mock")]
+        worker: String,
+        /// Stop after this many accepted shares instead of mining until disconnected
+        #[arg(long)]
+        max_shares: Option<u64>,
+    },
+    /// Parse a raw hex block, print its header and validate it
+    Inspect(InspectArgs),
+    /// Print merkle branches and a BIP37 merkleblock proving transactions of a raw hex block
+    MerkleProof {
+        /// File holding the raw block as hex
+        file: PathBuf,
+        /// Txids to prove
+        #[arg(required = true)]
+        txids: Vec<Txid>,
+    },
+    /// Simulate difficulty retargeting under growing hashrate
+    SimulateDifficulty {
+        #[arg(long, default_value = "mainnet")]
+        network: Network,
+        /// Number of retarget periods
+        #[arg(long, default_value_t = 10)]
+        periods: u32,
+        /// Hashrate factor per retarget period
+        #[arg(long, default_value_t = 1.5)]
+        growth: f64,
+    },
+    /// Mine consecutive linked blocks on regtest difficulty, carrying unconfirmed
+    /// transactions forward. The target flags are ignored.
+    SimulateChain {
+        #[command(flatten)]
+        block: BlockArgs,
+        /// Number of blocks to mine
+        #[arg(long, default_value_t = 10)]
+        blocks: u32,
+        /// Mining threads, defaults to the available parallelism
+        #[arg(long)]
+        threads: Option<usize>,
+        /// Store the blocks and the UTXO set in this directory, continuing the chain
+        /// already stored there
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+    },
+    /// Rebuild the block index and the UTXO set of a stored chain from its block file
+    Reindex {
+        #[arg(long)]
+        data_dir: PathBuf,
+    },
+}
+
+/// Where transactions come from and which of them are acceptable.
+/// Flags override the values of the config file.
+#[derive(Args, Debug)]
+pub(crate) struct InputArgs {
+    /// TOML or JSON config file
+    #[arg(long)]
+    pub(crate) config: Option<PathBuf>,
+
+    /// Directory with the mempool transactions [default: mempool]
+    #[arg(long)]
+    pub(crate) input_dir: Option<PathBuf>,
+
+    /// [default: mainnet]
+    #[arg(long)]
+    pub(crate) network: Option<Network>,
+
+    /// Minimum absolute fee in satoshis [default: 1]
+    #[arg(long)]
+    pub(crate) min_fee: Option<u64>,
+
+    /// Minimum fee rate in satoshis per virtual byte [default: 0]
+    #[arg(long)]
+    pub(crate) min_feerate: Option<f64>,
+
+    /// Smallest accepted output value in satoshis [default: 0]
+    #[arg(long)]
+    pub(crate) dust_limit: Option<u64>,
+
+    /// Weight limit for transaction selection [default: 4000000]
+    #[arg(long)]
+    pub(crate) max_block_weight: Option<usize>,
+}
+
+impl InputArgs {
+    /// The config file (or the defaults) with the flags applied on top
+    pub(crate) fn config(&self) -> Result<Config> {
+        let mut config = Config::load(self.config.as_deref())?;
+
+        if let Some(input_dir) = &self.input_dir {
+            config.input_dir = input_dir.clone();
+        }
+        if let Some(network) = self.network {
+            config.network = network;
+        }
+        if let Some(min_fee) = self.min_fee {
+            config.policy.min_fee = min_fee;
+        }
+        if let Some(min_feerate) = self.min_feerate {
+            config.policy.min_feerate = min_feerate;
+        }
+        if let Some(dust_limit) = self.dust_limit {
+            config.policy.dust_limit = dust_limit;
+        }
+        if let Some(max_block_weight) = self.max_block_weight {
+            config.policy.max_block_weight = max_block_weight;
+        }
+
+        Ok(config)
+    }
+}
+
+/// Everything that goes into the block besides its transactions
+#[derive(Args, Debug)]
+pub(crate) struct BlockArgs {
+    #[command(flatten)]
+    pub(crate) input: InputArgs,
+
+    /// Compact target (nBits) as hex, e.g. 1d00ffff
+    #[arg(long, value_parser = parse_bits, conflicts_with = "target")]
+    pub(crate) bits: Option<u32>,
+
+    /// Full 256 bit target as hex
+    #[arg(long, value_parser = parse_target)]
+    pub(crate) target: Option<U256>,
+
+    /// Hash of the previous block in display (RPC) byte order [default: all zeros]
+    #[arg(long)]
+    pub(crate) prev_hash: Option<BlockHash>,
+
+    /// Height of the block, committed to in the coinbase (BIP34) [default: 0]
+    #[arg(long)]
+    pub(crate) height: Option<u32>,
+
+    /// Median time past of the previous block, the block time is kept above it
+    #[arg(long)]
+    pub(crate) median_time_past: Option<u32>,
+
+    /// Block time, defaults to now
+    #[arg(long)]
+    pub(crate) time: Option<u32>,
+
+    /// Address the coinbase pays to, defaults to a P2PKH output of the zero hash
+    #[arg(long)]
+    pub(crate) payout_address: Option<String>,
+
+    /// Coinbase value in satoshis, defaults to subsidy plus fees
+    #[arg(long)]
+    pub(crate) reward: Option<u64>,
+}
+
+impl BlockArgs {
+    /// The config file (or the defaults) with the flags applied on top
+    pub(crate) fn config(&self) -> Result<Config> {
+        let mut config = self.input.config()?;
+
+        if let Some(bits) = self.bits {
+            config.chain.bits = Some(format!("{bits:08x}"));
+        }
+        if let Some(target) = self.target {
+            config.chain.bits = None;
+            config.chain.target = Some(format!("{target:x}"));
+        }
+        if let Some(prev_hash) = &self.prev_hash {
+            config.chain.prev_hash = *prev_hash;
+        }
+        if let Some(height) = self.height {
+            config.chain.height = height;
+        }
+        if let Some(median_time_past) = self.median_time_past {
+            config.chain.median_time_past = Some(median_time_past);
+        }
+        if let Some(time) = self.time {
+            config.chain.time = Some(time);
+        }
+        if let Some(address) = &self.payout_address {
+            config.payout.outputs = vec![PayoutShare {
+                address: address.clone(),
+                share: 1,
+            }];
+        }
+        if let Some(reward) = self.reward {
+            config.payout.reward = Some(reward);
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct BuildArgs {
+    #[command(flatten)]
+    pub(crate) block: BlockArgs,
+
+    /// File the unmined raw block is written to
+    #[arg(long, default_value = "block.hex")]
+    pub(crate) output: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct MineArgs {
+    #[command(flatten)]
+    pub(crate) block: BlockArgs,
+
+    /// File the mined block is written to
+    #[arg(long, default_value = "output.txt")]
+    pub(crate) output: PathBuf,
+
+    /// Mining threads, defaults to the available parallelism
+    #[arg(long)]
+    pub(crate) threads: Option<usize>,
+
+    /// Also write the mined block as raw hex to this file
+    #[arg(long)]
+    pub(crate) raw_block: Option<PathBuf>,
+
+    /// Print an annotated hex dump of the mined block
+    #[arg(long)]
+    pub(crate) hex_dump: bool,
+}
+
+impl MineArgs {
+    /// The config file (or the defaults) with the flags applied on top
+    pub(crate) fn config(&self) -> Result<Config> {
+        let mut config = self.block.config()?;
+        if let Some(threads) = self.threads {
+            config.threads = Some(threads);
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct StratumArgs {
+    #[command(flatten)]
+    pub(crate) block: BlockArgs,
+
+    /// Address the server listens on
+    #[arg(long, default_value = "127.0.0.1:3333")]
+    pub(crate) listen: String,
+
+    /// Difficulty of accepted shares, defaults to a 16th of the block difficulty
+    #[arg(long)]
+    pub(crate) share_difficulty: Option<f64>,
+
+    /// File the solved block is written to
+    #[arg(long, default_value = "output.txt")]
+    pub(crate) output: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct VerifyBlockArgs {
+    #[command(flatten)]
+    pub(crate) input: InputArgs,
+
+    /// Block in the grader format
+    #[arg(default_value = "output.txt")]
+    pub(crate) file: PathBuf,
+
+    /// Height the coinbase value is checked at [default: from the config, else 0]
+    #[arg(long)]
+    pub(crate) height: Option<u32>,
+
+    /// Target the block hash has to be below [default: from the config, else the challenge target]
+    #[arg(long, value_parser = parse_target)]
+    pub(crate) target: Option<U256>,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct InspectArgs {
+    /// File holding the raw block as hex
+    pub(crate) file: PathBuf,
+
+    /// Height the block is validated at
+    #[arg(long, default_value_t = 0)]
+    pub(crate) height: u32,
+
+    /// Print an annotated hex dump of the block
+    #[arg(long)]
+    pub(crate) hex_dump: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    const CONFIG_TOML: &str = r#"
+network = "regtest"
+input_dir = "from_file"
+threads = 2
+
+[chain]
+height = 5
+time = 1700000000
+bits = "207fffff"
+
+[payout]
+reward = 1000
+
+[[payout.outputs]]
+address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"
+share = 3
+
+[[payout.outputs]]
+address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"
+
+[policy]
+min_fee = 10
+dust_limit = 500
+"#;
+
+    fn mine_args(args: &[&str]) -> MineArgs {
+        let cli = Cli::try_parse_from(["prog"].iter().chain(args)).unwrap();
+        match cli.command {
+            Some(Command::Mine(args)) => args,
+            None => cli.mine,
+            Some(command) => panic!("Parsed {command:?}"),
+        }
+    }
+
+    #[test]
+    fn defaults_without_config_or_flags() -> Result<()> {
+        let config = mine_args(&[]).config()?;
+        assert_eq!(config.network, Network::Mainnet);
+        assert_eq!(config.input_dir, PathBuf::from("mempool"));
+        assert_eq!(config.chain.prev_hash, BlockHash::ZERO);
+        assert_eq!(config.chain.height, 0);
+        assert_eq!(config.threads, None);
+        assert_eq!(config.policy.min_fee, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn flags_override_the_config_file() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("config.toml");
+        fs::write(&path, CONFIG_TOML)?;
+        let path = path.to_str().unwrap();
+
+        // Without flags the file is used as is
+        let config = mine_args(&["mine", "--config", path]).config()?;
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(config.input_dir, PathBuf::from("from_file"));
+        assert_eq!(config.threads, Some(2));
+        assert_eq!(config.chain.height, 5);
+        assert_eq!(config.chain.bits.as_deref(), Some("207fffff"));
+        let values: Vec<u64> = config
+            .payout_outputs(1000)?
+            .iter()
+            .map(|(_, value)| *value)
+            .collect();
+        assert_eq!(values, [750, 250]);
+        assert_eq!(config.policy.min_fee, 10);
+
+        let target = format!("{:064x}", U256::MAX >> 8);
+        let config = mine_args(&[
+            "mine",
+            "--config",
+            path,
+            "--network",
+            "testnet",
+            "--threads",
+            "4",
+            "--height",
+            "7",
+            "--target",
+            &target,
+            "--payout-address",
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            "--min-fee",
+            "20",
+        ])
+        .config()?;
+        assert_eq!(config.network, Network::Testnet);
+        assert_eq!(config.threads, Some(4));
+        assert_eq!(config.chain.height, 7);
+        // A target flag replaces the bits of the file
+        assert_eq!(config.chain.bits, None);
+        assert_eq!(config.target()?, U256::MAX >> 8);
+        assert_eq!(config.payout_outputs(1000)?.len(), 1);
+        assert_eq!(config.policy.min_fee, 20);
+
+        // Values without a flag still come from the file
+        assert_eq!(config.input_dir, PathBuf::from("from_file"));
+        assert_eq!(config.chain.time, Some(1_700_000_000));
+        assert_eq!(config.payout.reward, Some(1000));
+        assert_eq!(config.policy.dust_limit, 500);
+        Ok(())
+    }
+
+    #[test]
+    fn bits_flag_overrides_the_target_of_the_file() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("config.json");
+        fs::write(&path, r#"{"chain": {"target": "ff"}}"#)?;
+
+        let config =
+            mine_args(&["--config", path.to_str().unwrap(), "--bits", "1d00ffff"]).config()?;
+        assert_eq!(config.target()?, U256::from(0xffff) << 208);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_flags_are_rejected() {
+        let invalid = [
+            vec!["mine", "--bits", "zz"],
+            // Zero and negative compact targets
+            vec!["mine", "--bits", "00000000"],
+            vec!["mine", "--bits", "04923456"],
+            vec!["mine", "--target", "0"],
+            vec!["mine", "--bits", "1d00ffff", "--target", "ff"],
+            vec!["mine", "--network", "signet"],
+            vec!["mine", "--height", "-1"],
+            vec!["mine", "--prev-hash", "00"],
+            vec!["mine", "--unknown-flag"],
+            vec!["--height", "1", "validate"],
+        ];
+        for args in invalid {
+            let result = Cli::try_parse_from(["prog"].iter().chain(&args));
+            assert!(result.is_err(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn config_errors_are_reported() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "[chain]\nheigth = 5\n")?;
+
+        let args = mine_args(&["--config", path.to_str().unwrap()]);
+        assert!(args.config().is_err());
+
+        let missing = dir.path().join("missing.toml");
+        let args = mine_args(&["--config", missing.to_str().unwrap()]);
+        assert!(args.config().is_err());
+        Ok(())
+    }
+}
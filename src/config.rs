@@ -0,0 +1,342 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use primitive_types::U256;
+use serde::Deserialize;
+
+use crate::address::address_to_script;
+use crate::hash::BlockHash;
+use crate::mine::{derive_target, expand_target};
+use crate::network::Network;
+use crate::policy::Policy;
+
+/// Target used when the config gives neither `bits` nor `target`
+const DEFAULT_TARGET: &str = "0000ffff00000000000000000000000000000000000000000000000000000000";
+
+/// Coinbase output script used without any payout address: P2PKH of the zero hash
+const DEFAULT_PAYOUT_SCRIPT: &str = "76a914000000000000000000000000000000000000000088ac";
+
+/// Everything the block builder needs besides the mempool itself. Read from a TOML or JSON
+/// file (chosen by extension), every field is optional and command line flags override it.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: Network,
+    /// Directory with the mempool transactions
+    pub input_dir: PathBuf,
+    /// Mining threads, defaults to the available parallelism
+    pub threads: Option<usize>,
+    pub chain: ChainTip,
+    pub payout: Payout,
+    pub policy: Policy,
+}
+
+/// The block we build on
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainTip {
+    /// Hash of the previous block, display (RPC) byte order hex in the file
+    pub prev_hash: BlockHash,
+    /// Height of the new block
+    pub height: u32,
+    /// Median time of the last 11 blocks, the new block time has to be above it
+    pub median_time_past: Option<u32>,
+    /// Block time, defaults to now
+    pub time: Option<u32>,
+    /// Compact target (nBits) as hex
+    pub bits: Option<String>,
+    /// Full 256 bit target as hex, ignored when `bits` is set
+    pub target: Option<String>,
+}
+
+/// Who the coinbase pays
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Payout {
+    /// Coinbase value in satoshis, defaults to subsidy plus fees
+    pub reward: Option<u64>,
+    /// The reward is split between these outputs in proportion to their shares
+    pub outputs: Vec<PayoutShare>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PayoutShare {
+    pub address: String,
+    #[serde(default = "default_share")]
+    pub share: u64,
+}
+
+fn default_share() -> u64 {
+    1
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            network: Network::Mainnet,
+            input_dir: PathBuf::from("mempool"),
+            threads: None,
+            chain: ChainTip::default(),
+            payout: Payout::default(),
+            policy: Policy::default(),
+        }
+    }
+}
+
+impl Default for ChainTip {
+    fn default() -> Self {
+        ChainTip {
+            prev_hash: BlockHash::ZERO,
+            height: 0,
+            median_time_past: None,
+            time: None,
+            bits: None,
+            target: None,
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file, or the defaults without one
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let Some(path) = path else {
+            return Ok(Config::default());
+        };
+
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read config {}: {e}", path.display()))?;
+        let config: Config = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => serde_json::from_str(&contents)?,
+            Some("toml") => toml::from_str(&contents)?,
+            _ => {
+                return Err(anyhow!(
+                    "Config {} must be a .toml or .json file",
+                    path.display()
+                ))
+            }
+        };
+
+        // Catch typos before mining for minutes on a wrong block
+        config.target()?;
+        Ok(config)
+    }
+
+    /// The target the block is mined against
+    pub fn target(&self) -> Result<U256> {
+        match (&self.chain.bits, &self.chain.target) {
+            (Some(bits), _) => Ok(expand_target(parse_bits(bits)?)),
+            (None, Some(target)) => parse_target(target),
+            (None, None) => Ok(U256::from(DEFAULT_TARGET)),
+        }
+    }
+
+    /// Coinbase output scripts and values splitting `reward` by the configured shares.
+    /// The rounding remainder goes to the first output.
+    pub fn payout_outputs(&self, reward: u64) -> Result<Vec<(Vec<u8>, u64)>> {
+        if self.payout.outputs.is_empty() {
+            return Ok(vec![(hex::decode(DEFAULT_PAYOUT_SCRIPT)?, reward)]);
+        }
+
+        let total_shares: u64 = self.payout.outputs.iter().map(|output| output.share).sum();
+        if total_shares == 0 {
+            return Err(anyhow!("Payout shares add up to zero"));
+        }
+
+        let mut outputs = self
+            .payout
+            .outputs
+            .iter()
+            .map(|output| {
+                let script = address_to_script(&output.address, self.network)?;
+                let value = (reward as u128 * output.share as u128 / total_shares as u128) as u64;
+                Ok((script, value))
+            })
+            .collect::<Result<Vec<(Vec<u8>, u64)>>>()?;
+        let paid: u64 = outputs.iter().map(|(_, value)| value).sum();
+        outputs[0].1 += reward - paid;
+
+        Ok(outputs)
+    }
+}
+
+pub fn parse_bits(s: &str) -> Result<u32> {
+    let bits = u32::from_str_radix(s.trim_start_matches("0x"), 16)?;
+    derive_target(bits)?;
+    Ok(bits)
+}
+
+pub fn parse_target(s: &str) -> Result<U256> {
+    let target = U256::from_str_radix(s.trim_start_matches("0x"), 16)?;
+    if target.is_zero() {
+        return Err(anyhow!("Target must not be zero"));
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    const CONFIG_TOML: &str = r#"
+network = "testnet"
+input_dir = "transactions"
+threads = 4
+
+[chain]
+prev_hash = "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"
+height = 1
+median_time_past = 1296688602
+bits = "1d00ffff"
+
+[payout]
+reward = 1001
+
+[[payout.outputs]]
+address = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+share = 3
+
+[[payout.outputs]]
+address = "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn"
+
+[policy]
+min_feerate = 2.5
+dust_limit = 546
+max_block_weight = 3000000
+"#;
+
+    const CONFIG_JSON: &str = r#"{
+        "network": "testnet",
+        "input_dir": "transactions",
+        "threads": 4,
+        "chain": {
+            "prev_hash": "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+            "height": 1,
+            "median_time_past": 1296688602,
+            "bits": "1d00ffff"
+        },
+        "payout": {
+            "reward": 1001,
+            "outputs": [
+                {"address": "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx", "share": 3},
+                {"address": "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn"}
+            ]
+        },
+        "policy": {"min_feerate": 2.5, "dust_limit": 546, "max_block_weight": 3000000}
+    }"#;
+
+    fn load(file_name: &str, contents: &str) -> Result<Config> {
+        let dir = tempdir()?;
+        let path = dir.path().join(file_name);
+        fs::write(&path, contents)?;
+        Config::load(Some(&path))
+    }
+
+    #[test]
+    fn toml_and_json_configs_load() -> Result<()> {
+        for config in [
+            load("config.toml", CONFIG_TOML)?,
+            load("config.json", CONFIG_JSON)?,
+        ] {
+            assert_eq!(config.network, Network::Testnet);
+            assert_eq!(config.input_dir, PathBuf::from("transactions"));
+            assert_eq!(config.threads, Some(4));
+            assert_eq!(
+                config.chain.prev_hash.to_string(),
+                "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"
+            );
+            assert_eq!(config.chain.height, 1);
+            assert_eq!(config.chain.median_time_past, Some(1_296_688_602));
+            assert_eq!(config.chain.time, None);
+            assert_eq!(config.target()?, U256::from(0xffff) << 208);
+            assert_eq!(config.payout.reward, Some(1001));
+
+            // 3:1 split, the rounding remainder goes to the first output
+            let outputs = config.payout_outputs(1001)?;
+            assert_eq!(outputs[0].1, 751);
+            assert_eq!(outputs[1].1, 250);
+            assert_eq!(hex::encode(&outputs[1].0[..3]), "76a914");
+
+            assert_eq!(config.policy.min_fee, 1);
+            assert_eq!(config.policy.min_feerate, 2.5);
+            assert_eq!(config.policy.dust_limit, 546);
+            assert_eq!(config.policy.max_block_weight, 3_000_000);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn example_config_loads() -> Result<()> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.example.toml");
+        let config = Config::load(Some(&path))?;
+        assert_eq!(config.network, Network::Mainnet);
+        assert_eq!(config.target()?, U256::from(DEFAULT_TARGET));
+        assert_eq!(
+            config.payout_outputs(100)?,
+            [(hex::decode(DEFAULT_PAYOUT_SCRIPT)?, 100)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() -> Result<()> {
+        for contents in [
+            "netwrok = \"mainnet\"",
+            "[chain]\nheigth = 1",
+            "[payout]\nrewards = 1",
+            "[[payout.outputs]]\naddress = \"1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2\"\nshares = 1",
+            "[policy]\nmin_fe = 1",
+            "[mining]\nthreads = 1",
+        ] {
+            assert!(load("config.toml", contents).is_err(), "{contents}");
+        }
+        assert!(load("config.json", r#"{"chain": {"heigth": 1}}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bad_values_are_rejected() -> Result<()> {
+        for contents in [
+            "network = \"signet\"",
+            "threads = -1",
+            "[chain]\nheight = \"1\"",
+            "[chain]\nprev_hash = \"00\"",
+            "[chain]\nbits = \"zz\"",
+            // Zero, negative and overflowing compact targets
+            "[chain]\nbits = \"1d000000\"",
+            "[chain]\nbits = \"04923456\"",
+            "[chain]\nbits = \"ff123456\"",
+            "[chain]\ntarget = \"0\"",
+            "[chain]\ntarget = \"not hex\"",
+            "[policy]\nmin_feerate = \"fast\"",
+            "[[payout.outputs]]\nshare = 1",
+        ] {
+            assert!(load("config.toml", contents).is_err(), "{contents}");
+        }
+
+        assert!(load("config.yaml", "network: mainnet").is_err());
+        assert!(load("config.json", "network = \"mainnet\"").is_err());
+        assert!(Config::load(Some(Path::new("does/not/exist.toml"))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bad_payouts_are_rejected() -> Result<()> {
+        let zero_shares = load(
+            "config.toml",
+            "[[payout.outputs]]\naddress = \"1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2\"\nshare = 0",
+        )?;
+        assert!(zero_shares.payout_outputs(100).is_err());
+
+        // Addresses are checked against the configured network
+        let wrong_network = load(
+            "config.toml",
+            "network = \"regtest\"\n[[payout.outputs]]\naddress = \"1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2\"",
+        )?;
+        assert!(wrong_network.payout_outputs(100).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt;
+
+use crate::address::script_to_address;
+use crate::network::Network;
+use crate::script::{from_asm, script_type, to_asm};
+use crate::validation::Transaction;
+
+/// A redundant JSON field which does not agree with the script it describes
+#[derive(Debug)]
+pub(crate) struct FieldMismatch {
+    /// Where the field is, e.g. `vin[0].prevout` or `vout[1]`
+    pub(crate) location: String,
+    pub(crate) field: &'static str,
+    pub(crate) claimed: String,
+    pub(crate) derived: String,
+}
+
+impl fmt::Display for FieldMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{} is {:?} but the script gives {:?}",
+            self.location, self.field, self.claimed, self.derived
+        )
+    }
+}
+
+/// Recompute type, address and ASM from the raw scripts of every prevout and output (and the
+/// ASM of every scriptsig) and list the fields which disagree with the claimed values
+pub(crate) fn find_mismatches(tx: &Transaction, network: Network) -> Vec<FieldMismatch> {
+    let mut mismatches = Vec::new();
+
+    for (index, input) in tx.vin.iter().enumerate() {
+        check_asm(
+            &mut mismatches,
+            format!("vin[{index}]"),
+            "scriptsig_asm",
+            &input.scriptsig,
+            &input.scriptsig_asm,
+        );
+
+        if input.is_coinbase {
+            continue;
+        }
+        let prevout = &input.prevout;
+        check_script_fields(
+            &mut mismatches,
+            format!("vin[{index}].prevout"),
+            &prevout.scriptpubkey,
+            &prevout.scriptpubkey_asm,
+            &prevout.scriptpubkey_type,
+            &prevout.scriptpubkey_address,
+            network,
+        );
+    }
+
+    for (index, output) in tx.vout.iter().enumerate() {
+        check_script_fields(
+            &mut mismatches,
+            format!("vout[{index}]"),
+            &output.scriptpubkey,
+            &output.scriptpubkey_asm,
+            &output.scriptpubkey_type,
+            &output.scriptpubkey_address,
+            network,
+        );
+    }
+
+    mismatches
+}
+
+fn check_script_fields(
+    mismatches: &mut Vec<FieldMismatch>,
+    location: String,
+    scriptpubkey: &str,
+    claimed_asm: &str,
+    claimed_type: &str,
+    claimed_address: &str,
+    network: Network,
+) {
+    let Ok(script) = hex::decode(scriptpubkey) else {
+        mismatches.push(FieldMismatch {
+            location,
+            field: "scriptpubkey",
+            claimed: scriptpubkey.to_string(),
+            derived: "invalid hex".to_string(),
+        });
+        return;
+    };
+
+    let derived_type = script_type(&script);
+    if claimed_type != derived_type {
+        mismatches.push(FieldMismatch {
+            location: location.clone(),
+            field: "scriptpubkey_type",
+            claimed: claimed_type.to_string(),
+            derived: derived_type.to_string(),
+        });
+    }
+
+    let derived_address = script_to_address(&script, network).unwrap_or_default();
+    if claimed_address != derived_address {
+        mismatches.push(FieldMismatch {
+            location: location.clone(),
+            field: "scriptpubkey_address",
+            claimed: claimed_address.to_string(),
+            derived: derived_address,
+        });
+    }
+
+    check_asm(
+        mismatches,
+        location,
+        "scriptpubkey_asm",
+        scriptpubkey,
+        claimed_asm,
+    );
+}
+
+/// The ASM has to assemble back into exactly the script bytes
+fn check_asm(
+    mismatches: &mut Vec<FieldMismatch>,
+    location: String,
+    field: &'static str,
+    script_hex: &str,
+    claimed_asm: &str,
+) {
+    let matches = match (from_asm(claimed_asm), hex::decode(script_hex)) {
+        (Ok(assembled), Ok(script)) => assembled == script,
+        _ => false,
+    };
+
+    if !matches {
+        mismatches.push(FieldMismatch {
+            location,
+            field,
+            claimed: claimed_asm.to_string(),
+            derived: hex::decode(script_hex)
+                .map(|script| to_asm(&script))
+                .unwrap_or_else(|_| "invalid hex".to_string()),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::convert_json_to_tx;
+
+    /// Mempool transaction 2f6f5c0e62d7d588185b138d243e1f9a4b527cd962b2f1f1bfc9c52b5ae0fe10
+    const TX_JSON: &str = r#"{
+        "version": 2,
+        "locktime": 834453,
+        "vin": [{
+            "txid": "be0e91c8161076830a5d35138868020ed10e58ea208b371b2e8c734fc3b9b694",
+            "vout": 0,
+            "prevout": {
+                "scriptpubkey": "5120e12efa737eefa3a1635084a88a959c92cbc4095a34f669b3ff3fb27eea9944bb",
+                "scriptpubkey_asm": "OP_PUSHNUM_1 OP_PUSHBYTES_32 e12efa737eefa3a1635084a88a959c92cbc4095a34f669b3ff3fb27eea9944bb",
+                "scriptpubkey_type": "v1_p2tr",
+                "scriptpubkey_address": "bc1puyh05um7a736zc6ssj5g49vujt9ugz26xnmxnvll87e8a65egjasft3s98",
+                "value": 105086
+            },
+            "scriptsig": "",
+            "scriptsig_asm": "",
+            "witness": ["378301008ff08fd55c3be8ee1ff3a770ad1799092e682c2148dba707a8980e9c64fea80accefdb6d3bf611f3f2145cbc776270fb3f5ca0516b0e3785f6449743"],
+            "is_coinbase": false,
+            "sequence": 0
+        }],
+        "vout": [{
+            "scriptpubkey": "001420d2699d10c0e0d712fe77e4447e4598a68e671a",
+            "scriptpubkey_asm": "OP_0 OP_PUSHBYTES_20 20d2699d10c0e0d712fe77e4447e4598a68e671a",
+            "scriptpubkey_type": "v0_p2wpkh",
+            "scriptpubkey_address": "bc1qyrfxn8gscrsdwyh7wljyglj9nznguec6nemwky",
+            "value": 103697
+        }]
+    }"#;
+
+    /// `TX_JSON` with the first `from` replaced by `to`
+    fn tampered(from: &str, to: &str) -> String {
+        assert!(TX_JSON.contains(from), "{from} is not in the fixture");
+        TX_JSON.replacen(from, to, 1)
+    }
+
+    /// Location and field of every mismatch of `tx_json`
+    fn mismatches(tx_json: &str, network: Network) -> Vec<(String, &'static str)> {
+        let tx = convert_json_to_tx(tx_json).unwrap();
+        find_mismatches(&tx, network)
+            .into_iter()
+            .map(|mismatch| (mismatch.location, mismatch.field))
+            .collect()
+    }
+
+    fn mismatch(location: &str, field: &'static str) -> Vec<(String, &'static str)> {
+        vec![(location.to_string(), field)]
+    }
+
+    #[test]
+    fn consistent_transaction_has_no_mismatches() {
+        assert!(mismatches(TX_JSON, Network::Mainnet).is_empty());
+    }
+
+    #[test]
+    fn claimed_fields_are_checked_against_the_scripts() {
+        assert_eq!(
+            mismatches(&tampered(r#""v1_p2tr""#, r#""v0_p2wsh""#), Network::Mainnet),
+            mismatch("vin[0].prevout", "scriptpubkey_type")
+        );
+        assert_eq!(
+            mismatches(
+                &tampered(
+                    "bc1qyrfxn8gscrsdwyh7wljyglj9nznguec6nemwky",
+                    "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+                ),
+                Network::Mainnet
+            ),
+            mismatch("vout[0]", "scriptpubkey_address")
+        );
+        assert_eq!(
+            mismatches(
+                &tampered("OP_0 OP_PUSHBYTES_20 20d2", "OP_0 OP_PUSHBYTES_20 30d2"),
+                Network::Mainnet
+            ),
+            mismatch("vout[0]", "scriptpubkey_asm")
+        );
+        assert_eq!(
+            mismatches(
+                &tampered(r#""scriptsig_asm": """#, r#""scriptsig_asm": "OP_0""#),
+                Network::Mainnet
+            ),
+            mismatch("vin[0]", "scriptsig_asm")
+        );
+    }
+
+    #[test]
+    fn invalid_script_hex_is_a_single_mismatch() {
+        assert_eq!(
+            mismatches(
+                &tampered(r#""scriptpubkey": "0014"#, r#""scriptpubkey": "zz14"#),
+                Network::Mainnet
+            ),
+            mismatch("vout[0]", "scriptpubkey")
+        );
+    }
+
+    #[test]
+    fn addresses_depend_on_the_network() {
+        assert_eq!(
+            mismatches(TX_JSON, Network::Testnet),
+            [
+                mismatch("vin[0].prevout", "scriptpubkey_address"),
+                mismatch("vout[0]", "scriptpubkey_address"),
+            ]
+            .concat()
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::error::Category;
+use serde_json::Value;
+
+use code_challenge_2024_mirebella_v2::hash::Txid;
+use code_challenge_2024_mirebella_v2::input::{
+    read_mempool_files, FileError, MempoolEntry, SkippedFile,
+};
+use code_challenge_2024_mirebella_v2::network::Network;
+
+/// What is wrong with the mempool folder
+#[derive(Debug, Serialize)]
+pub(crate) struct MempoolReport {
+    pub(crate) files: usize,
+    pub(crate) parsed: usize,
+    pub(crate) issues: Vec<Issue>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Issue {
+    /// The file is not JSON at all, or is cut off. Line and column are 0 when the file
+    /// could not be read as text.
+    NotJson {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        error: String,
+    },
+    /// Valid JSON which does not describe a transaction: missing fields, wrong types
+    Malformed {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        missing_field: Option<String>,
+        error: String,
+    },
+    /// A raw `.hex` transaction (or its prevouts) which does not decode
+    InvalidRawTransaction { path: PathBuf, error: String },
+    /// Fields the transaction format does not know, silently ignored on load
+    UnknownFields { path: PathBuf, fields: Vec<String> },
+    /// Several files holding the same transaction, only one of them is used
+    DuplicateTxid { txid: Txid, paths: Vec<PathBuf> },
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Issue::NotJson {
+                path,
+                line,
+                column,
+                error,
+            } => write!(f, "{}:{line}:{column}: not JSON: {error}", path.display()),
+            Issue::Malformed {
+                path,
+                line,
+                column,
+                error,
+                ..
+            } => write!(f, "{}:{line}:{column}: malformed: {error}", path.display()),
+            Issue::InvalidRawTransaction { path, error } => {
+                write!(f, "{}: invalid raw transaction: {error}", path.display())
+            }
+            Issue::UnknownFields { path, fields } => {
+                write!(
+                    f,
+                    "{}: unknown fields: {}",
+                    path.display(),
+                    fields.join(", ")
+                )
+            }
+            Issue::DuplicateTxid { txid, paths } => {
+                let paths: Vec<String> = paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                write!(f, "duplicate txid {txid} in {}", paths.join(", "))
+            }
+        }
+    }
+}
+
+/// Read the mempool folder and collect every file which is dropped or only partly used
+pub(crate) fn diagnose_mempool(mempool_dir: &Path, network: Network) -> Result<MempoolReport> {
+    let mempool = read_mempool_files(mempool_dir, network)?;
+    let mut issues: Vec<Issue> = mempool.skipped.iter().map(skipped_file_issue).collect();
+
+    for entry in &mempool.entries {
+        if let Some(issue) = unknown_fields_issue(entry)? {
+            issues.push(issue);
+        }
+    }
+
+    let mut paths_by_txid: BTreeMap<Txid, Vec<PathBuf>> = BTreeMap::new();
+    for entry in &mempool.entries {
+        paths_by_txid
+            .entry(entry.txid)
+            .or_default()
+            .push(entry.path.clone());
+    }
+    for (txid, paths) in paths_by_txid {
+        if paths.len() > 1 {
+            issues.push(Issue::DuplicateTxid { txid, paths });
+        }
+    }
+
+    Ok(MempoolReport {
+        files: mempool.entries.len() + mempool.skipped.len(),
+        parsed: mempool.entries.len(),
+        issues,
+    })
+}
+
+fn skipped_file_issue(skipped: &SkippedFile) -> Issue {
+    // Blame the file which failed, the prevouts of a raw transaction fail on their own
+    let path = match skipped.error.downcast_ref::<FileError>() {
+        Some(file_error) => file_error.path.clone(),
+        None => skipped.path.clone(),
+    };
+    let error = format!("{:#}", skipped.error);
+
+    // Unreadable, binary or non UTF-8 files
+    if skipped.error.downcast_ref::<io::Error>().is_some() {
+        return Issue::NotJson {
+            path,
+            line: 0,
+            column: 0,
+            error,
+        };
+    }
+
+    let Some(json_error) = skipped.error.downcast_ref::<serde_json::Error>() else {
+        return Issue::InvalidRawTransaction { path, error };
+    };
+    let (line, column) = (json_error.line(), json_error.column());
+    match json_error.classify() {
+        Category::Data => Issue::Malformed {
+            path,
+            line,
+            column,
+            missing_field: missing_field(&json_error.to_string()),
+            error,
+        },
+        Category::Syntax | Category::Eof | Category::Io => Issue::NotJson {
+            path,
+            line,
+            column,
+            error,
+        },
+    }
+}
+
+/// serde reports missing fields as "missing field `name` at line .. column .."
+fn missing_field(error: &str) -> Option<String> {
+    let rest = error.strip_prefix("missing field `")?;
+    Some(rest[..rest.find('`')?].to_string())
+}
+
+/// Compare the JSON of the file with the JSON of the parsed transaction, keys only present
+/// in the file were ignored when parsing
+fn unknown_fields_issue(entry: &MempoolEntry) -> Result<Option<Issue>> {
+    if entry
+        .path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        == Some("hex")
+    {
+        return Ok(None);
+    }
+
+    let file_json: Value = serde_json::from_str(&fs::read_to_string(&entry.path)?)?;
+    let parsed_json = serde_json::to_value(&entry.tx)?;
+    let mut fields = Vec::new();
+    collect_unknown_fields(&file_json, &parsed_json, String::new(), &mut fields);
+
+    Ok((!fields.is_empty()).then(|| Issue::UnknownFields {
+        path: entry.path.clone(),
+        fields,
+    }))
+}
+
+fn collect_unknown_fields(
+    file: &Value,
+    parsed: &Value,
+    location: String,
+    fields: &mut Vec<String>,
+) {
+    match (file, parsed) {
+        (Value::Object(file), Value::Object(parsed)) => {
+            for (key, value) in file {
+                let field = match location.is_empty() {
+                    true => key.clone(),
+                    false => format!("{location}.{key}"),
+                };
+                match parsed.get(key) {
+                    Some(parsed_value) => {
+                        collect_unknown_fields(value, parsed_value, field, fields)
+                    }
+                    None => fields.push(field),
+                }
+            }
+        }
+        (Value::Array(file), Value::Array(parsed)) => {
+            for (index, (value, parsed_value)) in file.iter().zip(parsed).enumerate() {
+                collect_unknown_fields(value, parsed_value, format!("{location}[{index}]"), fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn issue_of<'a>(report: &'a MempoolReport, path: &Path) -> &'a Issue {
+        report
+            .issues
+            .iter()
+            .find(|issue| match issue {
+                Issue::NotJson {
+                    path: issue_path, ..
+                }
+                | Issue::Malformed {
+                    path: issue_path, ..
+                }
+                | Issue::InvalidRawTransaction {
+                    path: issue_path, ..
+                }
+                | Issue::UnknownFields {
+                    path: issue_path, ..
+                } => issue_path == path,
+                Issue::DuplicateTxid { .. } => false,
+            })
+            .unwrap_or_else(|| panic!("No issue for {}", path.display()))
+    }
+
+    #[test]
+    fn unreadable_files_are_not_json() -> Result<()> {
+        let dir = tempdir()?;
+        let binary = dir.path().join("binary.json");
+        fs::write(&binary, [0xff, 0xfe, 0x00, 0x01])?;
+
+        let report = diagnose_mempool(dir.path(), Network::Mainnet)?;
+        assert!(matches!(
+            issue_of(&report, &binary),
+            Issue::NotJson {
+                line: 0,
+                column: 0,
+                ..
+            }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn json_errors_are_classified() -> Result<()> {
+        let dir = tempdir()?;
+        let cut_off = dir.path().join("cut_off.json");
+        fs::write(&cut_off, r#"{"version": 1, "#)?;
+        let malformed = dir.path().join("malformed.json");
+        fs::write(&malformed, r#"{"version": 1}"#)?;
+
+        let report = diagnose_mempool(dir.path(), Network::Mainnet)?;
+        assert!(matches!(issue_of(&report, &cut_off), Issue::NotJson { .. }));
+        assert!(matches!(
+            issue_of(&report, &malformed),
+            Issue::Malformed { missing_field: Some(field), .. } if field == "locktime"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn raw_transaction_errors_name_the_failing_file() -> Result<()> {
+        let dir = tempdir()?;
+        let invalid_hex = dir.path().join("invalid.hex");
+        fs::write(&invalid_hex, "zz")?;
+        fs::write(dir.path().join("spends.hex"), "00")?;
+        let prevouts = dir.path().join("spends.prevouts.json");
+        fs::write(&prevouts, r#"[{"value": 1}]"#)?;
+
+        let report = diagnose_mempool(dir.path(), Network::Mainnet)?;
+        assert!(matches!(
+            issue_of(&report, &invalid_hex),
+            Issue::InvalidRawTransaction { .. }
+        ));
+        assert!(matches!(
+            issue_of(&report, &prevouts),
+            Issue::Malformed { missing_field: Some(field), .. } if field == "scriptpubkey"
+        ));
+        Ok(())
+    }
+}
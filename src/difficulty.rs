@@ -0,0 +1,344 @@
+use primitive_types::{U256, U512};
+
+use crate::mine::{compress_target, expand_target, expand_target_with_flags};
+use crate::network::ChainParams;
+
+/// Compact target of difficulty 1, the easiest mainnet target
+const DIFFICULTY_1_BITS: u32 = 0x1d00ffff;
+
+/// The parts of a block header the retarget rules look at
+#[derive(Debug, Clone, Copy)]
+pub struct BlockInfo {
+    pub height: u32,
+    pub time: u32,
+    pub bits: u32,
+}
+
+/// Compact target the block following the tip of `chain` has to meet.
+/// `chain` holds all blocks from genesis up to the tip, indexed by height.
+pub fn next_work_required(chain: &[BlockInfo], new_block_time: u32, params: &ChainParams) -> u32 {
+    let pow_limit_bits = compress_target(params.pow_limit);
+    let last = match chain.last() {
+        Some(last) => last,
+        None => return pow_limit_bits,
+    };
+
+    let interval = params.difficulty_adjustment_interval();
+
+    // Only change once per difficulty adjustment interval
+    if (last.height + 1) % interval != 0 {
+        if params.pow_allow_min_difficulty_blocks {
+            // If the new block's timestamp is more than 2 * 10 minutes after the previous one,
+            // allow mining of a min-difficulty block
+            if new_block_time > last.time.saturating_add(params.pow_target_spacing * 2) {
+                return pow_limit_bits;
+            }
+
+            // Otherwise return the bits of the last block which was not a min-difficulty block
+            return chain
+                .iter()
+                .rev()
+                .find(|block| {
+                    block.height == 0
+                        || block.height % interval == 0
+                        || block.bits != pow_limit_bits
+                })
+                .map(|block| block.bits)
+                .unwrap_or(last.bits);
+        }
+
+        return last.bits;
+    }
+
+    // Go back by what we want to be 14 days worth of blocks
+    let first_height = last.height + 1 - interval;
+    let first_time = chain
+        .iter()
+        .find(|block| block.height == first_height)
+        .map(|block| block.time)
+        .unwrap_or(last.time);
+
+    calculate_next_work_required(last, first_time, params)
+}
+
+/// The 2016-block retarget: scale the last target by the actual timespan of the period,
+/// clamped to a factor of 4 in either direction and capped at the pow limit
+pub fn calculate_next_work_required(
+    last: &BlockInfo,
+    first_block_time: u32,
+    params: &ChainParams,
+) -> u32 {
+    if params.pow_no_retargeting {
+        return last.bits;
+    }
+
+    let timespan = params.pow_target_timespan as i64;
+    let actual_timespan =
+        (last.time as i64 - first_block_time as i64).clamp(timespan / 4, timespan * 4);
+
+    // Widen to 512 bits so the multiplication cannot overflow
+    let new_target = U512::from(expand_target(last.bits)) * U512::from(actual_timespan as u64)
+        / U512::from(timespan as u64);
+    let new_target = match U256::try_from(new_target) {
+        Ok(target) if target <= params.pow_limit => target,
+        _ => params.pow_limit,
+    };
+
+    compress_target(new_target)
+}
+
+/// Difficulty as a multiple of the minimum difficulty (bits 0x1d00ffff), as Bitcoin Core reports it
+pub fn difficulty(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = 0x0000ffff as f64 / (bits & 0x00ffffff) as f64;
+
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+
+    difficulty
+}
+
+/// Target of a pool (share) difficulty, the difficulty 1 target divided by `difficulty`.
+/// Unlike `difficulty` this works on full targets, as stratum miners do.
+pub fn target_from_difficulty(difficulty: f64) -> U256 {
+    let target = u256_to_f64(expand_target(DIFFICULTY_1_BITS)) / difficulty;
+    if !target.is_finite() || target >= 2f64.powi(256) {
+        return U256::MAX;
+    }
+
+    // Keep the 53 significant bits of the float and shift them into place
+    let exponent = (target.log2().floor() as i32 - 52).max(0);
+    let mantissa = (target / 2f64.powi(exponent)) as u64;
+    U256::from(mantissa) << exponent as usize
+}
+
+/// Pool difficulty of a full target, the inverse of `target_from_difficulty`
+pub fn difficulty_from_target(target: U256) -> f64 {
+    u256_to_f64(expand_target(DIFFICULTY_1_BITS)) / u256_to_f64(target)
+}
+
+/// Expected number of hashes needed to find a block meeting `bits`: 2^256 / (target + 1)
+pub fn block_proof(bits: u32) -> U256 {
+    let (target, negative, overflow) = expand_target_with_flags(bits);
+    if negative || overflow || target.is_zero() {
+        return U256::zero();
+    }
+
+    // 2^256 does not fit, but (~target / (target + 1)) + 1 is equal to it
+    (!target / (target + 1)) + 1
+}
+
+/// Total work of all blocks in `chain`
+pub fn chain_work(chain: &[BlockInfo]) -> U256 {
+    chain
+        .iter()
+        .fold(U256::zero(), |work, block| work + block_proof(block.bits))
+}
+
+/// Simulate `blocks` blocks on top of a genesis block at `genesis_time`, every block taking
+/// the expected time for the given hashrate (in hashes per second) at the current difficulty
+pub fn simulate_chain(
+    params: &ChainParams,
+    genesis_time: u32,
+    blocks: u32,
+    hashrate: impl Fn(u32) -> f64,
+) -> Vec<BlockInfo> {
+    let mut chain = vec![BlockInfo {
+        height: 0,
+        time: genesis_time,
+        bits: compress_target(params.pow_limit),
+    }];
+
+    for height in 1..=blocks {
+        let last = chain[chain.len() - 1];
+        let hashrate = hashrate(height).max(1.0);
+
+        // The candidate timestamp is when the block is found at the regular difficulty, the
+        // rules for that timestamp may allow the pow limit instead (testnet and regtest)
+        let regular_bits = next_work_required(&chain, last.time, params);
+        let mut time = last
+            .time
+            .saturating_add(expected_block_time(regular_bits, hashrate));
+        let bits = next_work_required(&chain, time, params);
+        if bits != regular_bits {
+            // Miners switch to the pow limit as soon as it is allowed
+            let allowed_from = last.time.saturating_add(params.pow_target_spacing * 2);
+            time = time.min(allowed_from.saturating_add(expected_block_time(bits, hashrate)));
+        }
+
+        chain.push(BlockInfo { height, time, bits });
+    }
+
+    chain
+}
+
+/// Seconds a block at `bits` takes on average at `hashrate` hashes per second, at least one
+fn expected_block_time(bits: u32, hashrate: f64) -> u32 {
+    (u256_to_f64(block_proof(bits)) / hashrate).round().max(1.0) as u32
+}
+
+fn u256_to_f64(value: U256) -> f64 {
+    value
+        .0
+        .iter()
+        .rev()
+        .fold(0.0, |acc, limb| acc * 2f64.powi(64) + *limb as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+
+    const MAINNET_LIMIT_BITS: u32 = 0x1d00ffff;
+
+    fn block(height: u32, time: u32, bits: u32) -> BlockInfo {
+        BlockInfo { height, time, bits }
+    }
+
+    /// Bitcoin Core's `pow_tests`: (last block, time of the first block of the period, bits)
+    #[test]
+    fn retargets_match_bitcoin_core() {
+        let params = ChainParams::new(Network::Mainnet);
+        let vectors = [
+            // Regular retarget
+            (block(32255, 1262152739, 0x1d00ffff), 1261130161, 0x1d00d86a),
+            // Capped at the pow limit
+            (block(2015, 1233061996, 0x1d00ffff), 1231006505, 0x1d00ffff),
+            // Clamped to a quarter of the timespan, the difficulty rises by at most 4x
+            (block(68543, 1279297671, 0x1c05a3f4), 1279008237, 0x1c0168fd),
+            // Clamped to four times the timespan, the difficulty falls by at most 4x
+            (block(46367, 1269211443, 0x1c387f6f), 1263163443, 0x1d00e1fd),
+        ];
+
+        for (last, first_block_time, expected) in vectors {
+            assert_eq!(
+                calculate_next_work_required(&last, first_block_time, &params),
+                expected,
+                "retarget at height {}",
+                last.height + 1
+            );
+        }
+    }
+
+    #[test]
+    fn clamp_limits_the_change_to_four_times() {
+        let params = ChainParams::new(Network::Mainnet);
+        let bits = 0x1c05a3f4;
+        let target = expand_target(bits);
+        let timespan = params.pow_target_timespan;
+        let last = block(4031, 1_600_000_000, bits);
+
+        // A period of one second or of ten timespans moves as far as a quarter or four times
+        let fastest = calculate_next_work_required(&last, last.time - 1, &params);
+        let quarter = calculate_next_work_required(&last, last.time - timespan / 4, &params);
+        assert_eq!(fastest, quarter);
+        assert_eq!(
+            expand_target(fastest),
+            expand_target(compress_target(target / 4))
+        );
+
+        let slowest = calculate_next_work_required(&last, last.time - timespan * 10, &params);
+        let four_times = calculate_next_work_required(&last, last.time - timespan * 4, &params);
+        assert_eq!(slowest, four_times);
+        assert_eq!(
+            expand_target(slowest),
+            expand_target(compress_target(target * 4))
+        );
+
+        // Regtest never retargets
+        let regtest = ChainParams::new(Network::Regtest);
+        assert_eq!(calculate_next_work_required(&last, 0, &regtest), bits);
+    }
+
+    #[test]
+    fn retarget_happens_at_the_interval() {
+        let params = ChainParams::new(Network::Mainnet);
+        let mut chain: Vec<BlockInfo> = (0..2015)
+            .map(|height| block(height, 1231006505 + height * 600, 0x1c05a3f4))
+            .collect();
+        assert_eq!(
+            next_work_required(&chain, 1_300_000_000, &params),
+            0x1c05a3f4
+        );
+
+        // The first period of mainnet took longer than two weeks
+        chain.push(block(2015, 1233061996, MAINNET_LIMIT_BITS));
+        chain[0].bits = MAINNET_LIMIT_BITS;
+        assert_eq!(
+            next_work_required(&chain, 1233062000, &params),
+            MAINNET_LIMIT_BITS
+        );
+        assert_eq!(next_work_required(&[], 0, &params), MAINNET_LIMIT_BITS);
+    }
+
+    #[test]
+    fn testnet_allows_min_difficulty_after_twenty_minutes() {
+        let params = ChainParams::new(Network::Testnet);
+        let bits = 0x1c05a3f4;
+        let mut chain: Vec<BlockInfo> = (0..10)
+            .map(|height| block(height, 1_600_000_000 + height * 600, bits))
+            .collect();
+        let last_time = chain[9].time;
+
+        assert_eq!(next_work_required(&chain, last_time + 1200, &params), bits);
+        assert_eq!(
+            next_work_required(&chain, last_time + 1201, &params),
+            MAINNET_LIMIT_BITS
+        );
+
+        // After min-difficulty blocks the last regular bits apply again
+        chain.push(block(10, last_time + 1201, MAINNET_LIMIT_BITS));
+        chain.push(block(11, last_time + 2402, MAINNET_LIMIT_BITS));
+        assert_eq!(next_work_required(&chain, last_time + 2403, &params), bits);
+
+        // A timestamp at the end of time does not overflow
+        chain.push(block(12, u32::MAX - 10, bits));
+        assert_eq!(next_work_required(&chain, u32::MAX, &params), bits);
+    }
+
+    #[test]
+    fn difficulty_matches_bitcoin_core() {
+        // Bitcoin Core's `blockchain_tests`
+        let vectors = [
+            (0x1f111111, 0.000001),
+            (0x1ef88f6f, 0.000016),
+            (0x1df88f6f, 0.004023),
+            (0x1cf88f6f, 1.029916),
+            (0x12345678, 5913134931067755359633408.0),
+            (MAINNET_LIMIT_BITS, 1.0),
+        ];
+
+        for (bits, expected) in vectors {
+            // Core compares with the same absolute tolerance
+            assert!(
+                (difficulty(bits) - expected).abs() < 0.00001,
+                "difficulty of {bits:#010x}"
+            );
+        }
+    }
+
+    #[test]
+    fn block_proof_and_chain_work() {
+        assert_eq!(block_proof(MAINNET_LIMIT_BITS), U256::from(0x100010001u64));
+        assert_eq!(block_proof(0x207fffff), U256::from(2));
+        // Negative, overflowing and zero targets prove nothing
+        assert_eq!(block_proof(0x01fedcba), U256::zero());
+        assert_eq!(block_proof(0xff123456), U256::zero());
+        assert_eq!(block_proof(0), U256::zero());
+
+        // Mainnet chainwork at height 1
+        let chain = [
+            block(0, 1231006505, MAINNET_LIMIT_BITS),
+            block(1, 1231469665, MAINNET_LIMIT_BITS),
+        ];
+        assert_eq!(chain_work(&chain), U256::from(0x200020002u64));
+        assert_eq!(chain_work(&[]), U256::zero());
+    }
+}
@@ -0,0 +1,69 @@
+use std::io::Read;
+
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+/// Append `n` as a Bitcoin CompactSize unsigned integer (varint)
+pub(crate) fn write_compact_size(bytes: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => bytes.push(n as u8),
+        0xfd..=0xffff => {
+            bytes.push(0xfd);
+            bytes.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            bytes.push(0xfe);
+            bytes.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            bytes.push(0xff);
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+/// Number of bytes `n` takes when encoded as CompactSize
+pub(crate) fn compact_size_len(n: u64) -> usize {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x10000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+/// Append a length prefixed byte string
+pub(crate) fn write_var_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    write_compact_size(bytes, data.len() as u64);
+    bytes.extend_from_slice(data);
+}
+
+/// Read a Bitcoin CompactSize unsigned integer (varint)
+pub(crate) fn read_compact_size(reader: &mut impl Read) -> Result<u64> {
+    let value = match reader.read_u8()? {
+        0xfd => reader.read_u16::<LittleEndian>()? as u64,
+        0xfe => reader.read_u32::<LittleEndian>()? as u64,
+        0xff => reader.read_u64::<LittleEndian>()?,
+        n => n as u64,
+    };
+    Ok(value)
+}
+
+/// Read a length prefixed byte string
+pub(crate) fn read_var_bytes(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let len = read_compact_size(reader)?;
+    read_bytes(reader, len)
+}
+
+/// Read exactly `len` bytes, without trusting `len` for the allocation up front
+pub(crate) fn read_bytes(reader: &mut impl Read, len: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(anyhow!(
+            "Unexpected end of data: wanted {len} bytes, got {}",
+            bytes.len()
+        ));
+    }
+    Ok(bytes)
+}
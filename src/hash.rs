@@ -0,0 +1,192 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::block::double_sha256;
+
+/// 32 byte double SHA256 hash stored in internal (serialization) byte order. Displayed,
+/// parsed and serialized as hex in display (RPC) order, which is the reverse.
+macro_rules! hash_newtype {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name([u8; 32]);
+
+        impl $name {
+            /// All zeros, the same in both byte orders
+            pub const ZERO: $name = $name([0u8; 32]);
+
+            /// HASH256 of `data`
+            pub fn hash(data: &[u8]) -> $name {
+                let mut bytes = [0u8; 32];
+                bytes.copy_from_slice(&double_sha256(data));
+                $name(bytes)
+            }
+
+            /// From bytes in internal byte order
+            pub fn from_byte_array(bytes: [u8; 32]) -> $name {
+                $name(bytes)
+            }
+
+            /// From a slice in internal byte order, which has to be 32 bytes long
+            pub fn from_slice(bytes: &[u8]) -> Result<$name> {
+                let bytes: [u8; 32] = bytes.try_into().map_err(|_| {
+                    anyhow!("{} must be 32 bytes, got {}", stringify!($name), bytes.len())
+                })?;
+                Ok($name(bytes))
+            }
+
+            /// Bytes in internal byte order, as serialized and hashed
+            pub fn as_bytes(&self) -> &[u8; 32] {
+                &self.0
+            }
+
+            /// Bytes in display byte order
+            pub fn to_display_bytes(self) -> [u8; 32] {
+                let mut bytes = self.0;
+                bytes.reverse();
+                bytes
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&hex::encode(self.to_display_bytes()))
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            /// Parse display order hex
+            fn from_str(s: &str) -> Result<$name> {
+                let mut bytes = [0u8; 32];
+                hex::decode_to_slice(s, &mut bytes)
+                    .map_err(|e| anyhow!("Invalid {} {s}: {e}", stringify!($name)))?;
+                bytes.reverse();
+                Ok($name(bytes))
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$name, D::Error> {
+                String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+hash_newtype!(
+    /// Transaction id: HASH256 of the transaction without witness data
+    ///
+    /// ```
+    /// use code_challenge_2024_mirebella_v2::{OutPoint, Txid};
+    ///
+    /// # fn main() -> anyhow::Result<()> {
+    /// let txid: Txid = "2f6f5c0e62d7d588185b138d243e1f9a4b527cd962b2f1f1bfc9c52b5ae0fe10".parse()?;
+    /// // Serialized and hashed in the reverse of the display order
+    /// assert_eq!(txid.as_bytes()[0], 0x10);
+    ///
+    /// let outpoint: OutPoint = format!("{txid}:1").parse()?;
+    /// assert_eq!(outpoint, OutPoint::new(txid, 1));
+    /// assert_eq!(serde_json::to_string(&txid)?, format!("\"{txid}\""));
+    /// # Ok(())
+    /// # }
+    /// ```
+    Txid
+);
+hash_newtype!(
+    /// Witness transaction id: HASH256 of the transaction with witness data (BIP141)
+    Wtxid
+);
+hash_newtype!(
+    /// Block hash: HASH256 of the 80 byte header
+    BlockHash
+);
+hash_newtype!(
+    /// Node of a merkle tree of txids or wtxids, including the root
+    MerkleNode
+);
+
+impl BlockHash {
+    /// The hash as a number, for comparing it with the target
+    pub fn to_u256(self) -> primitive_types::U256 {
+        primitive_types::U256::from_little_endian(&self.0)
+    }
+}
+
+impl MerkleNode {
+    /// Parent of `left` and `right`: HASH256 of their concatenation
+    pub fn combine(left: &MerkleNode, right: &MerkleNode) -> MerkleNode {
+        MerkleNode::hash(&[left.0, right.0].concat())
+    }
+}
+
+impl From<Txid> for MerkleNode {
+    fn from(txid: Txid) -> MerkleNode {
+        MerkleNode(txid.0)
+    }
+}
+
+impl From<Wtxid> for MerkleNode {
+    fn from(wtxid: Wtxid) -> MerkleNode {
+        MerkleNode(wtxid.0)
+    }
+}
+
+/// Reference to an output: the txid of the transaction creating it and its index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub txid: Txid,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    /// Outpoint spent by coinbase inputs
+    pub const NULL: OutPoint = OutPoint {
+        txid: Txid::ZERO,
+        vout: u32::MAX,
+    };
+
+    pub fn new(txid: Txid, vout: u32) -> OutPoint {
+        OutPoint { txid, vout }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == OutPoint::NULL
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
+}
+
+impl FromStr for OutPoint {
+    type Err = Error;
+
+    /// Parse `txid:vout`
+    fn from_str(s: &str) -> Result<OutPoint> {
+        let (txid, vout) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Outpoint {s} is not txid:vout"))?;
+        Ok(OutPoint::new(txid.parse()?, vout.parse()?))
+    }
+}
@@ -1,27 +1,168 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::path::Path;
-
-use crate::validation::convert_json_to_tx;
-use anyhow::{anyhow, Result};
-
-/// Read transaction jsons from mempool folder and build the hashmap
-pub(crate) fn read_txs_into_hashmap() -> Result<HashMap<String, String>> {
-    let mut result = HashMap::new();
-
-    let path = "mempool";
-    for entry in (fs::read_dir(Path::new(path))?).flatten() {
-        let path = entry.path();
-        if path.is_file() {
-            let tx_json =
-                fs::read_to_string(&path).map_err(|e| anyhow!("Failed to read file: {e}"))?;
-
-            if let Ok(tx) = convert_json_to_tx(&tx_json) {
-                let txid = tx.id()?;
-                result.insert(txid, tx_json);
-            }
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::block::sha256;
+use crate::hash::Txid;
+use crate::network::Network;
+use crate::raw_tx::{read_raw_transaction_file, PREVOUTS_FILE_SUFFIX};
+use crate::validation::{convert_json_to_tx, Transaction};
+use anyhow::{Context, Result};
+use log::warn;
+
+/// A mempool transaction, parsed once on load
+pub struct MempoolEntry {
+    pub path: PathBuf,
+    pub txid: Txid,
+    pub tx: Transaction,
+    /// Size of the transaction JSON in bytes, which the policy size checks look at
+    pub json_size: usize,
+}
+
+/// A mempool file which could not be turned into a transaction
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+/// Context of an error caused by the contents of `path`, which can be a file next to the
+/// mempool file it was read for, e.g. the prevouts of a raw transaction
+#[derive(Debug)]
+pub struct FileError {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to read {}", self.path.display())
+    }
+}
+
+impl FileError {
+    pub(crate) fn new(path: &Path) -> FileError {
+        FileError {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+/// Everything read from the mempool folder
+pub struct LoadedMempool {
+    /// One entry per parsable file, so the same txid can occur more than once
+    pub entries: Vec<MempoolEntry>,
+    pub skipped: Vec<SkippedFile>,
+}
+
+/// Read transaction jsons (and raw `.hex` transactions) from the mempool folder in parallel
+/// and key them by txid
+pub fn read_mempool(mempool_dir: &Path, network: Network) -> Result<HashMap<Txid, MempoolEntry>> {
+    let mempool = read_mempool_files(mempool_dir, network)?;
+    let file_count = mempool.entries.len();
+
+    let txs: HashMap<Txid, MempoolEntry> = mempool
+        .entries
+        .into_iter()
+        .map(|entry| (entry.txid, entry))
+        .collect();
+
+    if !mempool.skipped.is_empty() || txs.len() != file_count {
+        warn!(
+            "Skipped {} unparsable files and {} duplicate transactions, run `diagnose` for details",
+            mempool.skipped.len(),
+            file_count - txs.len()
+        );
+    }
+    Ok(txs)
+}
+
+/// Parse every file of the mempool folder in parallel
+pub fn read_mempool_files(mempool_dir: &Path, network: Network) -> Result<LoadedMempool> {
+    let paths: Vec<PathBuf> = fs::read_dir(mempool_dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            // Read together with the raw transaction it belongs to
+            let file_name = path.file_name().and_then(|name| name.to_str());
+            !file_name.is_some_and(|name| name.ends_with(PREVOUTS_FILE_SUFFIX))
+        })
+        .collect();
+
+    let results: Vec<(PathBuf, Result<MempoolEntry>)> = paths
+        .into_par_iter()
+        .map(|path| {
+            let result = read_mempool_file(&path, network);
+            (path, result)
+        })
+        .collect();
+
+    let mut mempool = LoadedMempool {
+        entries: Vec::new(),
+        skipped: Vec::new(),
+    };
+    for (path, result) in results {
+        match result {
+            Ok(entry) => mempool.entries.push(entry),
+            Err(error) => mempool.skipped.push(SkippedFile { path, error }),
+        }
+    }
+
+    Ok(mempool)
+}
+
+fn read_mempool_file(path: &Path, network: Network) -> Result<MempoolEntry> {
+    let (tx, json_size) = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("hex") => {
+            let tx = read_raw_transaction_file(path, network)?;
+            let json_size = serde_json::to_string(&tx)?.len();
+            (tx, json_size)
+        }
+        _ => {
+            let tx_json = fs::read_to_string(path).context(FileError::new(path))?;
+            (convert_json_to_tx(&tx_json)?, tx_json.len())
+        }
+    };
+
+    Ok(MempoolEntry {
+        path: path.to_path_buf(),
+        txid: tx.id()?,
+        tx,
+        json_size,
+    })
+}
+
+/// A mempool file whose name is neither its txid nor the SHA256 of it
+pub struct FilenameMismatch {
+    pub path: PathBuf,
+    pub txid: Txid,
+    /// The name the file should have, SHA256 of the txid bytes in display order
+    pub expected: String,
+}
+
+/// Compare every file name with the txid computed from its contents. The challenge names
+/// files by the SHA256 of the txid, raw transactions may also be named by the txid itself.
+pub fn find_filename_mismatches(entries: &[MempoolEntry]) -> Vec<FilenameMismatch> {
+    let mut mismatches = Vec::new();
+    for entry in entries {
+        let stem = entry
+            .path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let expected = hex::encode(sha256(&entry.txid.to_display_bytes()));
+
+        if stem != expected && stem != entry.txid.to_string() {
+            mismatches.push(FilenameMismatch {
+                path: entry.path.clone(),
+                txid: entry.txid,
+                expected,
+            });
         }
     }
 
-    Ok(result)
+    mismatches
 }
@@ -0,0 +1,106 @@
+//! Bitcoin block builder: parse mempool transactions, validate them, select the most
+//! profitable set, build a block around a coinbase, mine it and write it out.
+//!
+//! Nothing is printed, progress is reported through the `log` crate.
+//!
+//! The whole pipeline driven by a [`Config`]:
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use code_challenge_2024_mirebella_v2::{
+//!     build_block, mine, validate_block, write_block_to_file, Config,
+//! };
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let config = Config::load(Some(Path::new("config.toml")))?;
+//! let block = mine(build_block(&config)?)?;
+//! validate_block(&block, config.chain.height)?;
+//! write_block_to_file(&block.header, &block.transactions, Path::new("output.txt"))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Or step by step, here a block holding only its coinbase mined at the regtest target:
+//!
+//! ```
+//! use code_challenge_2024_mirebella_v2::block_validation::block_subsidy;
+//! use code_challenge_2024_mirebella_v2::{
+//!     create_block, create_coinbase_transaction, mine_with_threads, select_transactions,
+//!     validate_block, BlockHash, Network, Policy, U256,
+//! };
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let height = 1;
+//! let transactions = select_transactions(Vec::new(), &Policy::default());
+//!
+//! let payout_script = hex::decode("76a914000000000000000000000000000000000000000088ac")?;
+//! let coinbase = create_coinbase_transaction(
+//!     height,
+//!     &[(payout_script, block_subsidy(height))],
+//!     Network::Regtest,
+//! )?;
+//!
+//! let target = U256::from(0x7fffffu32) << 232;
+//! let block = create_block(
+//!     [vec![coinbase], transactions].concat(),
+//!     BlockHash::ZERO,
+//!     1_700_000_000,
+//!     target,
+//! )?;
+//! let block = mine_with_threads(block, 1)?;
+//!
+//! validate_block(&block, height)?;
+//! assert!(block.header.block_hash().to_u256() < target);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod address;
+pub mod block;
+pub mod block_validation;
+pub mod chain;
+pub mod config;
+mod consistency;
+pub mod difficulty;
+mod encoding;
+pub mod hash;
+pub mod input;
+pub mod merkle;
+mod midstate;
+pub mod mine;
+pub mod network;
+pub mod output;
+mod pipeline;
+pub mod policy;
+mod raw_tx;
+mod script;
+pub mod select;
+pub mod store;
+pub mod stratum;
+pub mod template;
+pub mod utxo;
+pub mod validation;
+
+pub use primitive_types::U256;
+
+pub use crate::block::{create_block, create_coinbase_transaction, Block, Header};
+pub use crate::block_validation::validate_block;
+pub use crate::chain::{simulate_regtest_chain, ChainState};
+pub use crate::config::Config;
+pub use crate::hash::{BlockHash, MerkleNode, OutPoint, Txid, Wtxid};
+pub use crate::input::read_mempool;
+pub use crate::midstate::HeaderHasher;
+pub use crate::mine::{mine, mine_with_threads};
+pub use crate::network::Network;
+pub use crate::output::{write_block_to_file, write_raw_block_to_file};
+pub use crate::pipeline::{build_block, load_valid_transactions};
+pub use crate::policy::Policy;
+pub use crate::select::select_transactions;
+pub use crate::store::ChainStore;
+pub use crate::stratum::{run_stratum_server, serve_stratum_job};
+pub use crate::template::{create_block_template, BlockTemplate};
+pub use crate::utxo::{Utxo, UtxoDelta, UtxoSet};
+pub use crate::validation::{
+    convert_json_to_tx, validate_all_transactions, Input, Output, PrevOut, Transaction,
+};
@@ -1,56 +1,352 @@
-mod block;
-mod input;
-mod mine;
-mod output;
-mod validation;
+mod cli;
+mod diagnostics;
+mod stratum_client;
+mod verify;
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::Parser;
 
-use crate::block::{create_block, create_coinbase_transaction};
-use crate::mine::mine;
-use crate::output::write_block_to_file;
-use crate::validation::Transaction;
+use code_challenge_2024_mirebella_v2::block_validation::validate_block_structure;
+use code_challenge_2024_mirebella_v2::network::ChainParams;
+use code_challenge_2024_mirebella_v2::output::{check_output_file, read_output_file};
+use code_challenge_2024_mirebella_v2::{
+    build_block, difficulty, input, load_valid_transactions, merkle, mine, mine_with_threads,
+    simulate_regtest_chain, stratum, template, validate_block, write_block_to_file,
+    write_raw_block_to_file, Block, ChainStore, Config, MerkleNode, Txid,
+};
+
+use crate::cli::{BuildArgs, Cli, Command, InspectArgs, MineArgs, StratumArgs, VerifyBlockArgs};
 
 fn main() -> Result<()> {
-    // input
-    let txs = input::read_txs_into_hashmap()?;
-    println!("All tx count: {:?}", txs.len());
-
-    // validation
-    let validated_txs_hashmap = validation::validate_all_transactions(txs);
-    let mut validated_txs: Vec<Transaction> = validated_txs_hashmap.into_values().collect();
-    println!("Validated tx count: {:?}", validated_txs.len());
-
-    // block
-    let previous_block_hash =
-        "0000000000000000000000000000000000000000000000000000000000000000".to_string();
-    let time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as u32;
-    let bits_u256 = primitive_types::U256::from(
-        "0000ffff00000000000000000000000000000000000000000000000000000000",
-    );
-
-    // Add to validated tx the coinbase transaction
-    let block_reward = 50;
-    let miner_address = "my_miner_address"; // TODO
-    let coinbase_tx = create_coinbase_transaction(block_reward, miner_address);
-    validated_txs.insert(0, coinbase_tx);
-
-    let block = create_block(validated_txs, previous_block_hash, time, bits_u256);
-    println!("Block header (before mining): {:?}", block.header);
-    println!("Block tx count: {:?}", block.transactions.len());
+    // The library logs its progress, which goes to stderr so stdout only holds results
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
+
+    let cli = Cli::parse();
+    match cli.command {
+        None => run_mine(&cli.mine),
+        Some(Command::Validate(args)) => {
+            load_valid_transactions(&args.config()?)?;
+            Ok(())
+        }
+        Some(Command::Diagnose { input, json }) => run_diagnose(&input.config()?, json),
+        Some(Command::VerifyFilenames(args)) => run_verify_filenames(&args.config()?),
+        Some(Command::Build(args)) => run_build(&args),
+        Some(Command::Template { block, output }) => run_template(&block.config()?, output),
+        Some(Command::Mine(args)) => run_mine(&args),
+        Some(Command::Stratum(args)) => run_stratum(&args),
+        Some(Command::StratumMockClient {
+            address,
+            worker,
+            max_shares,
+        }) => {
+            let accepted = stratum_client::run_mock_client(&address, &worker, max_shares)?;
+            println!("{accepted} shares accepted");
+            Ok(())
+        }
+        Some(Command::VerifyBlock(args)) => run_verify_block(&args),
+        Some(Command::Inspect(args)) => run_inspect(&args),
+        Some(Command::MerkleProof { file, txids }) => run_merkle_proof(&file, &txids),
+        Some(Command::SimulateDifficulty {
+            network,
+            periods,
+            growth,
+        }) => {
+            run_simulate_difficulty(&ChainParams::new(network), periods, growth);
+            Ok(())
+        }
+        Some(Command::SimulateChain {
+            block,
+            blocks,
+            threads,
+            data_dir,
+        }) => {
+            let mut config = block.config()?;
+            if threads.is_some() {
+                config.threads = threads;
+            }
+            simulate_regtest_chain(&config, blocks, data_dir.as_deref())?;
+            Ok(())
+        }
+        Some(Command::Reindex { data_dir }) => run_reindex(&data_dir),
+    }
+}
+
+fn run_reindex(data_dir: &Path) -> Result<()> {
+    let stage_start = Instant::now();
+    let store = ChainStore::reindex(data_dir)?;
+    let state = store.state();
+    println!(
+        "Reindexed {} blocks in {:.2?}, tip {} at height {}, {} UTXOs worth {} sat",
+        store.index().len(),
+        stage_start.elapsed(),
+        state.tip,
+        state.height.saturating_sub(1),
+        state.utxos.len(),
+        state.utxos.total_value()
+    );
+    Ok(())
+}
+
+fn run_diagnose(config: &Config, json: bool) -> Result<()> {
+    let report = diagnostics::diagnose_mempool(&config.input_dir, config.network)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    for issue in &report.issues {
+        println!("{issue}");
+    }
+    println!(
+        "{} files, {} parsed, {} issues",
+        report.files,
+        report.parsed,
+        report.issues.len()
+    );
+    Ok(())
+}
+
+fn run_verify_filenames(config: &Config) -> Result<()> {
+    let entries = input::read_mempool_files(&config.input_dir, config.network)?.entries;
+    let mismatches = input::find_filename_mismatches(&entries);
+    for mismatch in &mismatches {
+        println!(
+            "{} holds transaction {}, expected file name {}",
+            mismatch.path.display(),
+            mismatch.txid,
+            mismatch.expected
+        );
+    }
+
+    println!(
+        "Checked {} files, {} named correctly",
+        entries.len(),
+        entries.len() - mismatches.len()
+    );
+    if !mismatches.is_empty() {
+        return Err(anyhow!(
+            "{} files do not match their transaction",
+            mismatches.len()
+        ));
+    }
+    Ok(())
+}
+
+fn run_build(args: &BuildArgs) -> Result<()> {
+    let block = build_block(&args.block.config()?)?;
+    write_raw_block_to_file(&block, &args.output)?;
+    println!("Unmined block written to {}", args.output.display());
+    Ok(())
+}
+
+fn run_template(config: &Config, output: Option<PathBuf>) -> Result<()> {
+    let block = build_block(config)?;
+    let template = template::create_block_template(
+        &block,
+        config.chain.height,
+        config.chain.median_time_past,
+    )?;
+    let template_json = serde_json::to_string_pretty(&template)?;
+
+    match output {
+        Some(path) => {
+            fs::write(&path, template_json)?;
+            println!("Block template written to {}", path.display());
+        }
+        None => println!("{template_json}"),
+    }
+    Ok(())
+}
+
+fn run_mine(args: &MineArgs) -> Result<()> {
+    let config = args.config()?;
+    let block = build_block(&config)?;
 
     // mine
-    let mined_block = mine(block, bits_u256)?;
-    println!("Block was successfully mined!");
+    let stage_start = Instant::now();
+    let mined_block = match config.threads {
+        Some(threads) => mine_with_threads(block, threads)?,
+        None => mine(block)?,
+    };
+    println!(
+        "Block was successfully mined in {:.2?}!",
+        stage_start.elapsed()
+    );
+    write_mined_block(config.chain.height, &mined_block, &args.output)?;
+
+    if args.hex_dump || args.raw_block.is_some() {
+        // the raw block has to survive a parse round trip byte for byte
+        let raw_block = mined_block.serialize()?;
+        if Block::deserialize(&raw_block)?.serialize()? != raw_block {
+            return Err(anyhow!(
+                "Raw block does not survive a serialization round trip"
+            ));
+        }
+    }
+    if args.hex_dump {
+        println!("{}", mined_block.hex_dump()?);
+    }
+    if let Some(path) = &args.raw_block {
+        write_raw_block_to_file(&mined_block, path)?;
+    }
+
+    Ok(())
+}
+
+fn run_stratum(args: &StratumArgs) -> Result<()> {
+    let config = args.block.config()?;
+    let block = build_block(&config)?;
+
+    let solved_block = stratum::run_stratum_server(&args.listen, block, args.share_difficulty)?;
+    println!("Block was solved by a stratum miner!");
+    write_mined_block(config.chain.height, &solved_block, &args.output)
+}
+
+/// Validate a mined block, write it in the grader format and read the file back to check it
+fn write_mined_block(height: u32, mined_block: &Block, output: &Path) -> Result<()> {
     println!("Block header (after mining): {:?}", &mined_block.header);
 
+    // check our own block before writing it
+    let stage_start = Instant::now();
+    validate_block(mined_block, height)?;
+    println!("Block passed validation in {:.2?}", stage_start.elapsed());
+
     // write to file
-    write_block_to_file(&mined_block.header, &mined_block.transactions, "output.txt")?;
+    write_block_to_file(&mined_block.header, &mined_block.transactions, output)?;
+
+    // and make sure what was written reads back as the same block
+    let output_file = read_output_file(output)?;
+    check_output_file(&output_file, &mined_block.transactions[1..])?;
+    println!("{} passed the self-check", output.display());
+    Ok(())
+}
+
+fn run_verify_block(args: &VerifyBlockArgs) -> Result<()> {
+    let config = args.input.config()?;
+    let height = args.height.unwrap_or(config.chain.height);
+    let target = match args.target {
+        Some(target) => target,
+        None => config.target()?,
+    };
+
+    let score = verify::verify_output_file(
+        &args.file,
+        &config.input_dir,
+        config.network,
+        &config.policy,
+        height,
+        target,
+    )?;
+    println!("{} is a valid block", args.file.display());
+    println!("Transactions: {}", score.tx_count);
+    println!(
+        "Fees: {} of {} available ({:.2}%)",
+        score.fees,
+        score.available_fees,
+        score.fee_share * 100.0
+    );
+    println!(
+        "Weight: {} ({:.2}% of the block space)",
+        score.weight,
+        score.utilisation * 100.0
+    );
+    println!("Score: {:.2}", score.score);
+    Ok(())
+}
 
+fn run_merkle_proof(file: &Path, txids: &[Txid]) -> Result<()> {
+    let raw_hex = fs::read_to_string(file)?;
+    let block = Block::deserialize(&hex::decode(raw_hex.trim())?)?;
+    let block_txids = block
+        .transactions
+        .iter()
+        .map(|tx| tx.id())
+        .collect::<Result<Vec<Txid>>>()?;
+    let hashes: Vec<MerkleNode> = block_txids.iter().map(|txid| (*txid).into()).collect();
+
+    for txid in txids {
+        let index = block_txids
+            .iter()
+            .position(|block_txid| block_txid == txid)
+            .ok_or_else(|| anyhow!("Transaction {txid} is not in the block"))?;
+        let branch = merkle::merkle_branch(&hashes, index)?;
+        if !merkle::verify_merkle_branch(*txid, index, &branch, block.header.merkle_root) {
+            return Err(anyhow!("Merkle branch of {txid} does not verify"));
+        }
+
+        // Branch hashes in display byte order, from the leaves up
+        let branch: Vec<String> = branch.iter().map(|hash| hash.to_string()).collect();
+        println!("{txid} at index {index}: {}", branch.join(" "));
+    }
+
+    let merkle_block = merkle::create_merkle_block(&block, &txids.iter().cloned().collect())?;
+    let mut reader = &merkle_block[80..];
+    let (root, matches) = merkle::PartialMerkleTree::deserialize(&mut reader)?.extract_matches()?;
+    if root != block.header.merkle_root || matches.len() != txids.len() {
+        return Err(anyhow!(
+            "Merkle block does not prove the requested transactions"
+        ));
+    }
+    println!("merkleblock {}", hex::encode(merkle_block));
     Ok(())
 }
+
+fn run_inspect(args: &InspectArgs) -> Result<()> {
+    let raw_hex = fs::read_to_string(&args.file)?;
+    let block = Block::deserialize(&hex::decode(raw_hex.trim())?)?;
+
+    println!("Block hash: {}", block.header.block_hash());
+    println!("Block header: {:?}", block.header);
+    println!(
+        "Block difficulty: {}",
+        difficulty::difficulty(block.header.bits)
+    );
+    println!("Block tx count: {:?}", block.transactions.len());
+    if args.hex_dump {
+        println!("{}", block.hex_dump()?);
+    }
+
+    // A raw block does not carry the outputs it spends, so fees cannot be checked
+    validate_block_structure(&block, args.height)?;
+    println!("Block passed validation (coinbase value not checked)");
+    Ok(())
+}
+
+/// Print how the difficulty adjusts over `periods` retarget periods while the hashrate
+/// grows by `growth` per period
+fn run_simulate_difficulty(params: &ChainParams, periods: u32, growth: f64) {
+    let interval = params.difficulty_adjustment_interval();
+    let initial_hashrate = 10_000_000.0;
+    let chain = difficulty::simulate_chain(params, 1_231_006_505, periods * interval, |height| {
+        initial_hashrate * growth.powi((height / interval) as i32)
+    });
+
+    println!(
+        "Simulating {} retarget periods on {:?}, hashrate growing {}x per period",
+        periods, params.network, growth
+    );
+    println!(
+        "{:>8} {:>10} {:>16} {:>14} {:>20}",
+        "height", "bits", "difficulty", "avg spacing", "chainwork"
+    );
+    for period_start in chain.iter().step_by(interval as usize).skip(1) {
+        let period =
+            &chain[(period_start.height - interval) as usize..=period_start.height as usize];
+        let avg_spacing = (period[period.len() - 1].time - period[0].time) as f64 / interval as f64;
+        println!(
+            "{:>8} {:>#10x} {:>16.4} {:>13.1}s {:>20}",
+            period_start.height,
+            period_start.bits,
+            difficulty::difficulty(period_start.bits),
+            avg_spacing,
+            difficulty::chain_work(&chain[..=period_start.height as usize])
+        );
+    }
+}
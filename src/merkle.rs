@@ -0,0 +1,517 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+
+use crate::block::Block;
+use crate::block_validation::MAX_BLOCK_WEIGHT;
+use crate::encoding::{read_bytes, read_compact_size, write_compact_size};
+use crate::hash::{MerkleNode, Txid};
+
+/// Smallest possible transaction weight, bounds the transaction count of a partial tree
+const MIN_TRANSACTION_WEIGHT: usize = 60 * 4;
+
+/// Position in the block and txid of a transaction proven by a partial merkle tree
+pub type MerkleMatch = (usize, Txid);
+
+/// Merkle root of `hashes`, the last hash of an odd level is paired with itself. Like
+/// Bitcoin Core's `ComputeMerkleRoot` it also reports whether the tree was mutated: two
+/// identical hashes paired on any level give the same root as a tree without the duplicate
+/// (CVE-2012-2459), so a block with repeated transactions could share the merkle root of a
+/// valid block.
+pub fn compute_merkle_root(hashes: Vec<MerkleNode>) -> Result<(MerkleNode, bool)> {
+    if hashes.is_empty() {
+        return Err(anyhow!("Merkle tree without hashes"));
+    }
+
+    let mut mutated = false;
+    let mut level = hashes;
+    while level.len() > 1 {
+        mutated |= level
+            .chunks(2)
+            .any(|pair| pair.len() == 2 && pair[0] == pair[1]);
+        level = next_level(&level);
+    }
+
+    Ok((level[0], mutated))
+}
+
+/// Hashes combined with the hash at `index`, from the leaves up, to reach the merkle root
+pub fn merkle_branch(hashes: &[MerkleNode], index: usize) -> Result<Vec<MerkleNode>> {
+    if index >= hashes.len() {
+        return Err(anyhow!(
+            "Index {index} is outside a tree of {} hashes",
+            hashes.len()
+        ));
+    }
+
+    let mut branch = Vec::new();
+    let mut level = hashes.to_vec();
+    let mut index = index;
+    while level.len() > 1 {
+        // The last hash of an odd level is paired with itself
+        let sibling = (index ^ 1).min(level.len() - 1);
+        branch.push(level[sibling]);
+
+        level = next_level(&level);
+        index /= 2;
+    }
+
+    Ok(branch)
+}
+
+/// Merkle root reached from `hash` at `index` with its `branch`
+pub fn merkle_root_from_branch(
+    hash: MerkleNode,
+    index: usize,
+    branch: &[MerkleNode],
+) -> MerkleNode {
+    let mut index = index;
+    branch.iter().fold(hash, |hash, sibling| {
+        let parent = match index & 1 {
+            0 => MerkleNode::combine(&hash, sibling),
+            _ => MerkleNode::combine(sibling, &hash),
+        };
+        index >>= 1;
+        parent
+    })
+}
+
+/// Whether `txid` at `index` belongs to `merkle_root`
+pub fn verify_merkle_branch(
+    txid: Txid,
+    index: usize,
+    branch: &[MerkleNode],
+    merkle_root: MerkleNode,
+) -> bool {
+    merkle_root_from_branch(txid.into(), index, branch) == merkle_root
+}
+
+/// Pair up the hashes of a level, the last hash of an odd level with itself
+fn next_level(level: &[MerkleNode]) -> Vec<MerkleNode> {
+    level
+        .chunks(2)
+        .map(|pair| MerkleNode::combine(&pair[0], &pair[pair.len() - 1]))
+        .collect()
+}
+
+/// BIP37 partial merkle tree: the hashes and flag bits needed to prove that some of the
+/// transactions of a block are in its merkle root, in the depth first order of Bitcoin
+/// Core's `CPartialMerkleTree`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialMerkleTree {
+    pub total_transactions: u32,
+    pub hashes: Vec<MerkleNode>,
+    pub flags: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    /// Tree proving the txids whose `matches` entry is set
+    pub fn from_txids(txids: &[Txid], matches: &[bool]) -> Result<PartialMerkleTree> {
+        if txids.is_empty() || txids.len() != matches.len() {
+            return Err(anyhow!(
+                "Need one match flag per txid, got {} txids and {} flags",
+                txids.len(),
+                matches.len()
+            ));
+        }
+
+        let mut tree = PartialMerkleTree {
+            total_transactions: txids.len() as u32,
+            hashes: Vec::new(),
+            flags: Vec::new(),
+        };
+        let height = tree.height();
+        tree.traverse_and_build(height, 0, txids, matches);
+        Ok(tree)
+    }
+
+    /// The merkle root and the `(index, txid)` of every matched transaction. Fails on trees
+    /// which are malformed or could hide a mutation.
+    pub fn extract_matches(&self) -> Result<(MerkleNode, Vec<MerkleMatch>)> {
+        let total = self.total_transactions as usize;
+        if total == 0 {
+            return Err(anyhow!("Partial merkle tree without transactions"));
+        }
+        if total > MAX_BLOCK_WEIGHT / MIN_TRANSACTION_WEIGHT {
+            return Err(anyhow!("Partial merkle tree has too many transactions"));
+        }
+        if self.hashes.len() > total {
+            return Err(anyhow!(
+                "Partial merkle tree has more hashes than transactions"
+            ));
+        }
+        if self.flags.len() < self.hashes.len() {
+            return Err(anyhow!("Partial merkle tree has fewer flags than hashes"));
+        }
+
+        let mut cursor = (0usize, 0usize);
+        let mut matches = Vec::new();
+        let root = self.traverse_and_extract(self.height(), 0, &mut cursor, &mut matches)?;
+
+        let (flags_used, hashes_used) = cursor;
+        // Only the padding of the last flag byte may be left over, and it has to be zero
+        if flags_used.div_ceil(8) != self.flags.len().div_ceil(8) {
+            return Err(anyhow!("Partial merkle tree has unused flags"));
+        }
+        if hashes_used != self.hashes.len() {
+            return Err(anyhow!("Partial merkle tree has unused hashes"));
+        }
+        if self.flags[flags_used..].iter().any(|flag| *flag) {
+            return Err(anyhow!("Partial merkle tree has set padding bits"));
+        }
+
+        Ok((root, matches))
+    }
+
+    /// Wire format: transaction count, hashes and the flag bits packed least significant first
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = self.total_transactions.to_le_bytes().to_vec();
+
+        write_compact_size(&mut bytes, self.hashes.len() as u64);
+        for hash in &self.hashes {
+            bytes.extend_from_slice(hash.as_bytes());
+        }
+
+        let mut flag_bytes = vec![0u8; self.flags.len().div_ceil(8)];
+        for (i, flag) in self.flags.iter().enumerate() {
+            flag_bytes[i / 8] |= (*flag as u8) << (i % 8);
+        }
+        write_compact_size(&mut bytes, flag_bytes.len() as u64);
+        bytes.extend_from_slice(&flag_bytes);
+
+        bytes
+    }
+
+    pub fn deserialize(reader: &mut &[u8]) -> Result<PartialMerkleTree> {
+        let total_transactions = u32::from_le_bytes(
+            read_bytes(reader, 4)?
+                .try_into()
+                .map_err(|_| anyhow!("Invalid transaction count"))?,
+        );
+
+        let hash_count = read_compact_size(reader)?;
+        let hashes = (0..hash_count)
+            .map(|_| MerkleNode::from_slice(&read_bytes(reader, 32)?))
+            .collect::<Result<Vec<MerkleNode>>>()?;
+
+        let flag_len = read_compact_size(reader)?;
+        let flags = read_bytes(reader, flag_len)?
+            .iter()
+            .flat_map(|byte| (0..8).map(move |bit| (byte >> bit) & 1 == 1))
+            .collect();
+
+        Ok(PartialMerkleTree {
+            total_transactions,
+            hashes,
+            flags,
+        })
+    }
+
+    /// Number of nodes at `height`, the leaves being at height 0
+    fn width(&self, height: u32) -> usize {
+        (self.total_transactions as usize + (1 << height) - 1) >> height
+    }
+
+    fn height(&self) -> u32 {
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    fn node_hash(&self, height: u32, position: usize, txids: &[Txid]) -> MerkleNode {
+        if height == 0 {
+            return txids[position].into();
+        }
+
+        let left = self.node_hash(height - 1, position * 2, txids);
+        let right = match position * 2 + 1 < self.width(height - 1) {
+            true => self.node_hash(height - 1, position * 2 + 1, txids),
+            false => left,
+        };
+        MerkleNode::combine(&left, &right)
+    }
+
+    fn traverse_and_build(
+        &mut self,
+        height: u32,
+        position: usize,
+        txids: &[Txid],
+        matches: &[bool],
+    ) {
+        // Whether any leaf below this node matched
+        let first = position << height;
+        let last = ((position + 1) << height).min(txids.len());
+        let parent_of_match = matches[first..last].iter().any(|matched| *matched);
+        self.flags.push(parent_of_match);
+
+        if height == 0 || !parent_of_match {
+            let hash = self.node_hash(height, position, txids);
+            self.hashes.push(hash);
+        } else {
+            self.traverse_and_build(height - 1, position * 2, txids, matches);
+            if position * 2 + 1 < self.width(height - 1) {
+                self.traverse_and_build(height - 1, position * 2 + 1, txids, matches);
+            }
+        }
+    }
+
+    /// `cursor` counts the flags and hashes used so far
+    fn traverse_and_extract(
+        &self,
+        height: u32,
+        position: usize,
+        cursor: &mut (usize, usize),
+        matches: &mut Vec<MerkleMatch>,
+    ) -> Result<MerkleNode> {
+        let parent_of_match = *self
+            .flags
+            .get(cursor.0)
+            .ok_or_else(|| anyhow!("Partial merkle tree ran out of flags"))?;
+        cursor.0 += 1;
+
+        if height == 0 || !parent_of_match {
+            let hash = *self
+                .hashes
+                .get(cursor.1)
+                .ok_or_else(|| anyhow!("Partial merkle tree ran out of hashes"))?;
+            cursor.1 += 1;
+            if height == 0 && parent_of_match {
+                matches.push((position, Txid::from_byte_array(*hash.as_bytes())));
+            }
+            return Ok(hash);
+        }
+
+        let left = self.traverse_and_extract(height - 1, position * 2, cursor, matches)?;
+        let right = match position * 2 + 1 < self.width(height - 1) {
+            true => {
+                let right =
+                    self.traverse_and_extract(height - 1, position * 2 + 1, cursor, matches)?;
+                // Identical siblings would let two different trees share a root (CVE-2012-2459)
+                if right == left {
+                    return Err(anyhow!("Partial merkle tree has identical siblings"));
+                }
+                right
+            }
+            false => left,
+        };
+        Ok(MerkleNode::combine(&left, &right))
+    }
+}
+
+/// BIP37 `merkleblock` message: the block header followed by a partial merkle tree proving
+/// `txids`
+pub fn create_merkle_block(block: &Block, txids: &HashSet<Txid>) -> Result<Vec<u8>> {
+    let block_txids = block
+        .transactions
+        .iter()
+        .map(|tx| tx.id())
+        .collect::<Result<Vec<Txid>>>()?;
+    let matches: Vec<bool> = block_txids
+        .iter()
+        .map(|txid| txids.contains(txid))
+        .collect();
+
+    let tree = PartialMerkleTree::from_txids(&block_txids, &matches)?;
+    Ok([block.header.serialize().to_vec(), tree.serialize()].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Txids of mainnet block 100000
+    const BLOCK_100000_TXIDS: [&str; 4] = [
+        "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+        "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+        "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+        "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+    ];
+    const BLOCK_100000_MERKLE_ROOT: &str =
+        "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766";
+
+    fn txids(hexes: &[&str]) -> Result<Vec<Txid>> {
+        hexes.iter().map(|txid| txid.parse()).collect()
+    }
+
+    fn nodes(txids: &[Txid]) -> Vec<MerkleNode> {
+        txids.iter().map(|txid| (*txid).into()).collect()
+    }
+
+    fn root_node(hex: &str) -> Result<MerkleNode> {
+        Ok(hex.parse::<Txid>()?.into())
+    }
+
+    #[test]
+    fn merkle_roots_match_mainnet() -> Result<()> {
+        // Genesis, a single coinbase whose txid is the merkle root
+        let genesis_root = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let genesis = txids(&[genesis_root])?;
+        assert_eq!(
+            compute_merkle_root(nodes(&genesis))?,
+            (root_node(genesis_root)?, false)
+        );
+
+        let block = txids(&BLOCK_100000_TXIDS)?;
+        assert_eq!(
+            compute_merkle_root(nodes(&block))?,
+            (root_node(BLOCK_100000_MERKLE_ROOT)?, false)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn odd_levels_pair_the_last_hash_with_itself() -> Result<()> {
+        // Five leaves: the fifth is paired with itself twice before meeting the first four
+        let mut block = txids(&BLOCK_100000_TXIDS)?;
+        block.push(Txid::hash(b"fifth"));
+        let last = MerkleNode::from(block[4]);
+        let right = MerkleNode::combine(&last, &last);
+        let expected = MerkleNode::combine(
+            &root_node(BLOCK_100000_MERKLE_ROOT)?,
+            &MerkleNode::combine(&right, &right),
+        );
+
+        assert_eq!(compute_merkle_root(nodes(&block))?, (expected, false));
+        assert!(compute_merkle_root(vec![]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn duplicated_last_hashes_are_mutated() -> Result<()> {
+        let block = nodes(&txids(&BLOCK_100000_TXIDS)?);
+
+        // [a, b, c] and [a, b, c, c] share a root, only the second is mutated
+        let (root, mutated) = compute_merkle_root(block[..3].to_vec())?;
+        assert!(!mutated);
+        let duplicated = [&block[..3], &block[2..3]].concat();
+        assert_eq!(compute_merkle_root(duplicated)?, (root, true));
+
+        // Duplicating the last pair mutates the level above the leaves
+        let six = [&block[..], &block[..2]].concat();
+        let (root, mutated) = compute_merkle_root(six.clone())?;
+        assert!(!mutated);
+        let duplicated = [&six[..], &six[4..]].concat();
+        assert_eq!(compute_merkle_root(duplicated)?, (root, true));
+        Ok(())
+    }
+
+    #[test]
+    fn branches_verify_against_the_root() -> Result<()> {
+        for count in 1..=BLOCK_100000_TXIDS.len() + 1 {
+            let mut block = txids(&BLOCK_100000_TXIDS)?;
+            block.resize(count, Txid::hash(b"fifth"));
+            let hashes = nodes(&block);
+            let (root, _) = compute_merkle_root(hashes.clone())?;
+
+            for (index, txid) in block.iter().enumerate() {
+                let branch = merkle_branch(&hashes, index)?;
+                assert!(verify_merkle_branch(*txid, index, &branch, root));
+                if count > 1 {
+                    let other = (index + 1) % count;
+                    assert!(!verify_merkle_branch(*txid, other, &branch, root));
+                }
+            }
+            assert!(merkle_branch(&hashes, count).is_err());
+        }
+        Ok(())
+    }
+
+    /// Partial merkle tree of block 100000 proving its second transaction, as Bitcoin Core
+    /// encodes it in `gettxoutproof`: 4 transactions, 3 hashes and the flags 1, 1, 0, 1, 0
+    const BLOCK_100000_PARTIAL_TREE: &str = concat!(
+        "04000000",
+        "03",
+        "876dd0a3ef4a2816ffd1c12ab649825a958b0ff3bb3d6f3e1250f13ddbf0148c",
+        "c40297f730dd7b5a99567eb8d27b78758f607507c52292d02d4031895b52f2ff",
+        "49aef42d78e3e9999c9e6ec9e1dddd6cb880bf3b076a03be1318ca789089308e",
+        "01",
+        "0b",
+    );
+
+    fn block_100000_partial_tree() -> Result<PartialMerkleTree> {
+        let bytes = hex::decode(BLOCK_100000_PARTIAL_TREE)?;
+        let mut reader = bytes.as_slice();
+        let tree = PartialMerkleTree::deserialize(&mut reader)?;
+        assert!(reader.is_empty());
+        Ok(tree)
+    }
+
+    fn extract_error(tree: &PartialMerkleTree) -> String {
+        tree.extract_matches().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn partial_tree_round_trips() -> Result<()> {
+        let block = txids(&BLOCK_100000_TXIDS)?;
+        let tree = PartialMerkleTree::from_txids(&block, &[false, true, false, false])?;
+        assert_eq!(hex::encode(tree.serialize()), BLOCK_100000_PARTIAL_TREE);
+
+        let decoded = block_100000_partial_tree()?;
+        assert_eq!(hex::encode(decoded.serialize()), BLOCK_100000_PARTIAL_TREE);
+        assert_eq!(
+            decoded.extract_matches()?,
+            (root_node(BLOCK_100000_MERKLE_ROOT)?, vec![(1, block[1])])
+        );
+
+        // Odd counts, no matches and every transaction matched
+        for count in 1..=5 {
+            let mut block = block.clone();
+            block.resize(count, Txid::hash(b"fifth"));
+            let (root, _) = compute_merkle_root(nodes(&block))?;
+            for matches in [vec![false; count], vec![true; count]] {
+                let tree = PartialMerkleTree::from_txids(&block, &matches)?;
+                let bytes = tree.serialize();
+                let (extracted_root, extracted) =
+                    PartialMerkleTree::deserialize(&mut bytes.as_slice())?.extract_matches()?;
+                assert_eq!(extracted_root, root);
+                let expected: Vec<MerkleMatch> = match matches[0] {
+                    true => block.iter().copied().enumerate().collect(),
+                    false => vec![],
+                };
+                assert_eq!(extracted, expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_hashes_are_rejected() -> Result<()> {
+        let mut tree = block_100000_partial_tree()?;
+        tree.hashes.push(tree.hashes[0]);
+        assert!(extract_error(&tree).contains("unused hashes"));
+
+        let mut tree = block_100000_partial_tree()?;
+        tree.hashes.pop();
+        assert!(extract_error(&tree).contains("ran out of hashes"));
+        Ok(())
+    }
+
+    #[test]
+    fn unused_flags_are_rejected() -> Result<()> {
+        let mut tree = block_100000_partial_tree()?;
+        tree.flags.extend([false; 8]);
+        assert!(extract_error(&tree).contains("unused flags"));
+        Ok(())
+    }
+
+    #[test]
+    fn set_padding_bits_are_rejected() -> Result<()> {
+        let mut tree = block_100000_partial_tree()?;
+        tree.flags[7] = true;
+        assert!(extract_error(&tree).contains("padding bits"));
+        Ok(())
+    }
+
+    #[test]
+    fn more_nodes_than_transactions_are_rejected() -> Result<()> {
+        let mut tree = block_100000_partial_tree()?;
+        tree.total_transactions = 2;
+        assert!(extract_error(&tree).contains("more hashes than transactions"));
+
+        tree.total_transactions = 0;
+        assert!(extract_error(&tree).contains("without transactions"));
+        Ok(())
+    }
+}
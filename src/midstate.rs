@@ -0,0 +1,106 @@
+use sha2::compress256;
+use sha2::digest::generic_array::GenericArray;
+
+/// SHA-256 initial hash values
+const SHA256_IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Offset of the nonce inside the second 64 byte chunk of the header
+const TAIL_NONCE_OFFSET: usize = 12;
+
+/// Double SHA-256 hasher for block headers which only differ in their nonce.
+///
+/// The first 64 bytes of the header (version, previous block hash and most of the merkle root)
+/// never change while searching the nonce space, so their compression is done once up front.
+/// Each nonce then only costs the compression of the padded last 16 bytes plus the second pass.
+pub struct HeaderHasher {
+    midstate: [u32; 8],
+    tail: [u8; 64],
+}
+
+impl HeaderHasher {
+    /// Compress the first 64 bytes of the serialized header once
+    pub fn new(header_bytes: &[u8; 80]) -> Self {
+        let mut midstate = SHA256_IV;
+        compress256(
+            &mut midstate,
+            &[*GenericArray::from_slice(&header_bytes[..64])],
+        );
+
+        // Last 16 header bytes followed by the SHA-256 padding for an 80 byte message
+        let mut tail = [0u8; 64];
+        tail[..16].copy_from_slice(&header_bytes[64..]);
+        tail[16] = 0x80;
+        tail[56..].copy_from_slice(&(80u64 * 8).to_be_bytes());
+
+        HeaderHasher { midstate, tail }
+    }
+
+    /// Double SHA-256 of the header with `nonce` filled in, in internal byte order
+    pub fn hash(&mut self, nonce: u32) -> [u8; 32] {
+        self.tail[TAIL_NONCE_OFFSET..TAIL_NONCE_OFFSET + 4].copy_from_slice(&nonce.to_le_bytes());
+
+        let mut first_state = self.midstate;
+        compress256(&mut first_state, &[*GenericArray::from_slice(&self.tail)]);
+
+        // Second pass: the 32 byte first hash plus padding for a 32 byte message
+        let mut second_block = [0u8; 64];
+        write_state(&first_state, &mut second_block[..32]);
+        second_block[32] = 0x80;
+        second_block[56..].copy_from_slice(&(32u64 * 8).to_be_bytes());
+
+        let mut second_state = SHA256_IV;
+        compress256(
+            &mut second_state,
+            &[*GenericArray::from_slice(&second_block)],
+        );
+
+        let mut hash = [0u8; 32];
+        write_state(&second_state, &mut hash);
+        hash
+    }
+}
+
+fn write_state(state: &[u32; 8], out: &mut [u8]) {
+    for (word, chunk) in state.iter().zip(out.chunks_exact_mut(4)) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use primitive_types::U256;
+
+    use super::*;
+    use crate::block::{create_block, create_coinbase_transaction, double_sha256};
+    use crate::hash::BlockHash;
+    use crate::network::Network;
+
+    #[test]
+    fn hash_matches_double_sha256_of_the_header() -> Result<()> {
+        let coinbase = create_coinbase_transaction(1, &[(vec![0x51], 50)], Network::Regtest)?;
+        let target = U256::from(0x7fffffu32) << 232;
+        let mut block = create_block(vec![coinbase], BlockHash::ZERO, 1_700_000_000, target)?;
+
+        for extranonce in [0, 1, u64::MAX] {
+            block.roll_extranonce(extranonce)?;
+            for time in [1_700_000_000, 1_700_000_001, u32::MAX] {
+                block.header.time = time;
+                let mut hasher = HeaderHasher::new(&block.header.serialize());
+
+                for nonce in [0, 1, 0xdeadbeef, u32::MAX] {
+                    let mut header = block.header.clone();
+                    header.nonce = nonce;
+                    assert_eq!(
+                        hasher.hash(nonce).to_vec(),
+                        double_sha256(&header.serialize()),
+                        "extranonce {extranonce}, time {time}, nonce {nonce}"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,28 +1,201 @@
-use crate::block::double_sha256;
-use crate::block::{Block, Header};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use crate::block::Block;
+use crate::midstate::HeaderHasher;
 
-pub fn mine(mut block: Block, target_difficulty_u256: primitive_types::U256) -> Result<Block> {
-    loop {
-        let hash = calculate_block_hash(&block.header)?;
-        let hash_u256 = primitive_types::U256::from_big_endian(&hash);
+use anyhow::{anyhow, Result};
+use log::info;
 
-        if hash_u256 < target_difficulty_u256 {
-            let mut target_difficulty_bytes = [0; 32];
-            target_difficulty_u256.to_big_endian(&mut target_difficulty_bytes);
+/// How many hashes a worker does before checking whether another worker already succeeded
+const STOP_CHECK_INTERVAL: u64 = 1 << 12;
 
-            println!("hash:   {}", hex::encode(hash));
-            println!("target: {}", hex::encode(target_difficulty_bytes));
+/// How far (in seconds) a block time may be ahead of the current time
+const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
 
-            return Ok(block);
+pub fn mine(block: Block) -> Result<Block> {
+    mine_with_threads(block, default_thread_count())
+}
+
+/// Search the nonce space in parallel, every worker gets its own contiguous nonce range.
+/// When the whole nonce space is exhausted the header time is rolled forward, and once the
+/// time reaches its upper bound the coinbase extranonce is bumped instead.
+pub fn mine_with_threads(mut block: Block, threads: usize) -> Result<Block> {
+    let target_difficulty_u256 = derive_target(block.header.bits)?;
+    let threads = threads.max(1);
+    let max_time = max_allowed_block_time().max(block.header.time);
+
+    let started = Instant::now();
+    let total_hashes = search_rolling(
+        &mut block,
+        target_difficulty_u256,
+        threads,
+        u32::MAX,
+        max_time,
+        &AtomicBool::new(false),
+    )?;
+
+    report_hashrate(total_hashes, started, threads);
+
+    let mut target_difficulty_bytes = [0; 32];
+    target_difficulty_u256.to_big_endian(&mut target_difficulty_bytes);
+
+    info!("hash:   {}", block.header.block_hash());
+    info!("target: {}", hex::encode(target_difficulty_bytes));
+
+    Ok(block)
+}
+
+/// Search the nonces `0..=last_nonce` of every header, rolling the time up to `max_time` and
+/// then the extranonce, until a nonce meets the target or `stop` is set. Returns the number
+/// of hashes computed, the block holds the winning nonce unless the search was stopped.
+fn search_rolling(
+    block: &mut Block,
+    target_difficulty_u256: primitive_types::U256,
+    threads: usize,
+    last_nonce: u32,
+    max_time: u32,
+    stop: &AtomicBool,
+) -> Result<u64> {
+    let start_time = block.header.time;
+    let mut extranonce = 0u64;
+    let mut total_hashes = 0u64;
+
+    while !stop.load(Ordering::Relaxed) {
+        let header_bytes = block.header.serialize();
+        let (winning_nonce, hashes) = search_nonce_space(
+            &header_bytes,
+            target_difficulty_u256,
+            threads,
+            last_nonce,
+            stop,
+        );
+        total_hashes += hashes;
+
+        if let Some(nonce) = winning_nonce {
+            block.header.nonce = nonce;
+            break;
+        }
+        // A stopped round did not try every nonce, leave the header as it was searched
+        if hashes <= last_nonce as u64 {
+            break;
+        }
+
+        if block.header.time < max_time {
+            block.header.time += 1;
+        } else {
+            extranonce = extranonce
+                .checked_add(1)
+                .ok_or_else(|| anyhow!("Extranonce space exhausted"))?;
+            block.header.time = start_time;
+            block.roll_extranonce(extranonce)?;
         }
-        block.header.nonce += 1;
     }
+
+    Ok(total_hashes)
 }
 
-pub(crate) fn compress_target(target: primitive_types::U256) -> u32 {
-    let mut size = (target.bits() + 7) / 8; // Calculate size in bytes
+/// Try the nonces `0..=last_nonce` for the given serialized header, returns the winning
+/// nonce (if any) and the number of hashes computed. Workers give up early once `stop` is set.
+fn search_nonce_space(
+    header_bytes: &[u8; 80],
+    target_difficulty_u256: primitive_types::U256,
+    threads: usize,
+    last_nonce: u32,
+    stop: &AtomicBool,
+) -> (Option<u32>, u64) {
+    let found = AtomicBool::new(false);
+    let hashes_done = AtomicU64::new(0);
+    let winning_nonce = Mutex::new(None);
+
+    thread::scope(|scope| {
+        for (start, end) in partition_nonce_space(last_nonce, threads) {
+            let mut hasher = HeaderHasher::new(header_bytes);
+            let found = &found;
+            let hashes_done = &hashes_done;
+            let winning_nonce = &winning_nonce;
+
+            scope.spawn(move || {
+                let mut local_hashes = 0u64;
+                for nonce in start..=end {
+                    if local_hashes.is_multiple_of(STOP_CHECK_INTERVAL)
+                        && (found.load(Ordering::Relaxed) || stop.load(Ordering::Relaxed))
+                    {
+                        break;
+                    }
+
+                    let hash = hasher.hash(nonce);
+                    local_hashes += 1;
+
+                    if primitive_types::U256::from_little_endian(&hash) < target_difficulty_u256 {
+                        found.store(true, Ordering::Relaxed);
+                        winning_nonce.lock().unwrap().get_or_insert(nonce);
+                        break;
+                    }
+                }
+                hashes_done.fetch_add(local_hashes, Ordering::Relaxed);
+            });
+        }
+    });
+
+    (
+        winning_nonce.into_inner().unwrap(),
+        hashes_done.load(Ordering::Relaxed),
+    )
+}
+
+/// Latest block time accepted by nodes: now + 2 hours
+pub fn max_allowed_block_time() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    (now + MAX_FUTURE_BLOCK_TIME).min(u32::MAX as u64) as u32
+}
+
+fn default_thread_count() -> usize {
+    thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+}
+
+/// Split `0..=last_nonce` into at most `parts` inclusive ranges of (almost) equal size
+fn partition_nonce_space(last_nonce: u32, parts: usize) -> Vec<(u32, u32)> {
+    let total = last_nonce as u64 + 1;
+    let chunk = total.div_ceil(parts as u64);
+
+    (0..parts as u64)
+        .map(|i| i * chunk)
+        .take_while(|start| *start < total)
+        .map(|start| {
+            (
+                start as u32,
+                (start + chunk - 1).min(last_nonce as u64) as u32,
+            )
+        })
+        .collect()
+}
+
+fn report_hashrate(hashes: u64, started: Instant, threads: usize) {
+    let elapsed = started.elapsed().as_secs_f64();
+    let hashrate = match elapsed > 0.0 {
+        true => hashes as f64 / elapsed,
+        false => 0.0,
+    };
+
+    info!(
+        "Mining: {} hashes in {:.3}s on {} threads ({:.2} MH/s)",
+        hashes,
+        elapsed,
+        threads,
+        hashrate / 1_000_000.0
+    );
+}
+
+pub fn compress_target(target: primitive_types::U256) -> u32 {
+    let mut size = target.bits().div_ceil(8); // Calculate size in bytes
     let mut compact = if size <= 3 {
         // If the target is small enough to fit in 3 bytes
         target.low_u32() << (8 * (3 - size))
@@ -42,9 +215,251 @@ pub(crate) fn compress_target(target: primitive_types::U256) -> u32 {
     ((size as u32) << 24) | (compact & 0x00ffffff)
 }
 
-fn calculate_block_hash(header: &Header) -> Result<Vec<u8>> {
-    let header_bytes = hex::decode(header.to_hex()?)?;
-    let mut double_hash = double_sha256(&header_bytes);
-    double_hash.reverse();
-    Ok(double_hash)
+/// Decode compact `bits` into the full target, as Bitcoin Core's `SetCompact`.
+/// Returns the target together with the negative and overflow flags.
+pub fn expand_target_with_flags(bits: u32) -> (primitive_types::U256, bool, bool) {
+    let size = bits >> 24;
+    let mut word = bits & 0x007fffff;
+
+    let target = if size <= 3 {
+        word >>= 8 * (3 - size);
+        primitive_types::U256::from(word)
+    } else if size - 3 < 32 {
+        primitive_types::U256::from(word) << (8 * (size - 3))
+    } else {
+        // Everything is shifted out, the overflow flag below covers it
+        primitive_types::U256::zero()
+    };
+
+    let negative = word != 0 && (bits & 0x00800000) != 0;
+    let overflow =
+        word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32));
+
+    (target, negative, overflow)
+}
+
+/// Decode compact `bits` into the full target, ignoring the negative and overflow flags
+pub fn expand_target(bits: u32) -> primitive_types::U256 {
+    expand_target_with_flags(bits).0
+}
+
+/// Target a block hash has to be below, rejects negative, zero and overflowing `bits`
+pub fn derive_target(bits: u32) -> Result<primitive_types::U256> {
+    let (target, negative, overflow) = expand_target_with_flags(bits);
+    if negative || overflow || target.is_zero() {
+        return Err(anyhow!("Invalid compact target bits: {:#010x}", bits));
+    }
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use primitive_types::U256;
+
+    use super::*;
+    use crate::block::{calculate_merkle_root, create_block, create_coinbase_transaction};
+    use crate::hash::BlockHash;
+    use crate::network::Network;
+
+    /// Bitcoin Core's `SetCompact` vectors from arith_uint256_tests: bits, target, negative,
+    /// overflow
+    const SET_COMPACT_VECTORS: [(u32, u64, bool, bool); 12] = [
+        (0x00000000, 0, false, false),
+        (0x00123456, 0, false, false),
+        (0x01003456, 0, false, false),
+        (0x02000056, 0, false, false),
+        (0x03000000, 0, false, false),
+        (0x04000000, 0, false, false),
+        (0x00923456, 0, false, false),
+        (0x01803456, 0, false, false),
+        (0x01123456, 0x12, false, false),
+        (0x01fedcba, 0x7e, true, false),
+        (0x04923456, 0x12345600, true, false),
+        (0x05009234, 0x92340000, false, false),
+    ];
+
+    #[test]
+    fn nonce_partitions_cover_the_nonce_space() {
+        for parts in [1, 2, 3, 4, 7, 8, 16, 33, 64, 1000] {
+            assert_eq!(partition_nonce_space(u32::MAX, parts).len(), parts);
+        }
+
+        let cases = [
+            (u32::MAX, 1),
+            (u32::MAX, 3),
+            (u32::MAX, 64),
+            (9, 4),
+            (99, 7),
+        ];
+        for (last_nonce, parts) in cases.into_iter().chain([(0, 1), (0, 3), (2, 8)]) {
+            let ranges = partition_nonce_space(last_nonce, parts);
+            assert!(!ranges.is_empty() && ranges.len() <= parts);
+            assert_eq!(ranges[0].0, 0);
+            assert_eq!(ranges[ranges.len() - 1].1, last_nonce);
+
+            // Every range starts right after the previous one ends, no gaps and no overlaps
+            for (start, end) in &ranges {
+                assert!(start <= end, "{last_nonce} in {parts} parts");
+            }
+            for pair in ranges.windows(2) {
+                assert_eq!(
+                    pair[0].1 as u64 + 1,
+                    pair[1].0 as u64,
+                    "{last_nonce} in {parts} parts"
+                );
+            }
+
+            // Sizes differ by at most the rounding of the last range
+            let sizes: Vec<u64> = ranges
+                .iter()
+                .map(|(start, end)| (end - start) as u64 + 1)
+                .collect();
+            assert_eq!(sizes.iter().sum::<u64>(), last_nonce as u64 + 1);
+            assert!(sizes[..sizes.len() - 1]
+                .iter()
+                .all(|size| *size == sizes[0]));
+        }
+    }
+
+    #[test]
+    fn search_returns_a_nonce_meeting_the_target() {
+        let header = [0u8; 80];
+        let target = U256::MAX >> 4;
+        let (nonce, hashes) =
+            search_nonce_space(&header, target, 4, u32::MAX, &AtomicBool::new(false));
+
+        let nonce = nonce.unwrap();
+        let hash = HeaderHasher::new(&header).hash(nonce);
+        assert!(U256::from_little_endian(&hash) < target);
+        // Workers stop soon after the first success instead of searching their whole range
+        assert!(hashes < 4 * STOP_CHECK_INTERVAL, "{hashes} hashes");
+    }
+
+    #[test]
+    fn exhausted_headers_roll_the_time_and_then_the_extranonce() -> Result<()> {
+        let coinbase = create_coinbase_transaction(1, &[(vec![0x51], 1)], Network::Regtest)?;
+        let mut block = create_block(
+            vec![coinbase],
+            BlockHash::ZERO,
+            1_700_000_000,
+            expand_target(0x207fffff),
+        )?;
+        let start_time = block.header.time;
+        let merkle_root = block.header.merkle_root;
+        let max_time = start_time + 3;
+        let last_nonce = 255;
+        // Only a hash of zero is below a target of one, so no nonce is ever found
+        let target = U256::one();
+
+        let stopped = AtomicBool::new(true);
+        assert_eq!(
+            search_rolling(&mut block, target, 2, last_nonce, max_time, &stopped)?,
+            0
+        );
+        assert_eq!(block.header.time, start_time);
+
+        let stop = AtomicBool::new(false);
+        let hashes = thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(300));
+                stop.store(true, Ordering::Relaxed);
+            });
+            search_rolling(&mut block, target, 2, last_nonce, max_time, &stop)
+        })?;
+
+        // Every finished round rolled the header once: the time up to `max_time`, then the
+        // extranonce with the time back at the start
+        let rounds = hashes / (last_nonce as u64 + 1);
+        let times = (max_time - start_time + 1) as u64;
+        assert!(rounds > 2 * times, "{rounds} rounds");
+        assert!(block.header.time <= max_time);
+        assert_eq!(block.header.time as u64, start_time as u64 + rounds % times);
+
+        let scriptsig = &block.transactions[0].vin[0].scriptsig;
+        let extranonce: [u8; 8] = hex::decode(&scriptsig[scriptsig.len() - 16..])?
+            .try_into()
+            .unwrap();
+        assert_eq!(u64::from_le_bytes(extranonce), rounds / times);
+        assert_ne!(block.header.merkle_root, merkle_root);
+        assert_eq!(
+            block.header.merkle_root,
+            calculate_merkle_root(&block.transactions)?.0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn block_time_is_capped_two_hours_ahead() -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let max_time = max_allowed_block_time() as u64;
+        assert!(
+            (now + MAX_FUTURE_BLOCK_TIME..=now + MAX_FUTURE_BLOCK_TIME + 1).contains(&max_time)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn expand_target_matches_set_compact() {
+        for (bits, target, negative, overflow) in SET_COMPACT_VECTORS {
+            assert_eq!(
+                expand_target_with_flags(bits),
+                (U256::from(target), negative, overflow),
+                "bits {bits:#010x}"
+            );
+        }
+
+        let (target, negative, overflow) = expand_target_with_flags(0x20123456);
+        assert_eq!(target, U256::from(0x123456) << (8 * 29));
+        assert!(!negative && !overflow);
+
+        let (_, negative, overflow) = expand_target_with_flags(0xff123456);
+        assert!(!negative && overflow);
+    }
+
+    #[test]
+    fn compress_target_matches_get_compact() {
+        assert_eq!(compress_target(U256::zero()), 0);
+        assert_eq!(compress_target(U256::from(0x80)), 0x02008000);
+        assert_eq!(compress_target(U256::from(0x12)), 0x01120000);
+        assert_eq!(compress_target(U256::from(0x1234)), 0x02123400);
+        assert_eq!(compress_target(U256::from(0x123456)), 0x03123456);
+        assert_eq!(compress_target(U256::from(0x12345600)), 0x04123456);
+        assert_eq!(compress_target(U256::from(0x92340000u64)), 0x05009234);
+        assert_eq!(
+            compress_target(U256::from(0x123456) << (8 * 29)),
+            0x20123456
+        );
+    }
+
+    #[test]
+    fn compact_round_trip_over_random_targets() {
+        // xorshift64, deterministic so a failure can be reproduced
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..10_000 {
+            let words = [next(), next(), next(), next()];
+            let target = U256(words) >> (next() % 256);
+            if target.is_zero() {
+                continue;
+            }
+
+            let bits = compress_target(target);
+            let (expanded, negative, overflow) = expand_target_with_flags(bits);
+            assert!(!negative && !overflow, "target {target:x}");
+
+            // Only the bytes of the 23 bit mantissa survive
+            let dropped_bits = (bits >> 24).saturating_sub(3) as usize * 8;
+            assert_eq!(expanded, target >> dropped_bits << dropped_bits);
+            assert_eq!(compress_target(expanded), bits);
+        }
+    }
 }
@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Result};
+use primitive_types::U256;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl std::str::FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mainnet" | "main" | "bitcoin" => Ok(Network::Mainnet),
+            "testnet" | "test" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(anyhow!("Unknown network: {s}")),
+        }
+    }
+}
+
+impl Network {
+    /// Message start bytes, which also separate the blocks in block files
+    pub fn magic(self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+            Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+            Network::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+        }
+    }
+}
+
+/// Consensus parameters of a network which are relevant for proof of work
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub network: Network,
+    pub pow_limit: U256,
+    /// Expected time between two blocks in seconds
+    pub pow_target_spacing: u32,
+    /// Expected duration of one retarget period in seconds
+    pub pow_target_timespan: u32,
+    /// Testnet rule: a block more than 2 * spacing after its parent may use the pow limit
+    pub pow_allow_min_difficulty_blocks: bool,
+    /// Regtest rule: the difficulty never changes
+    pub pow_no_retargeting: bool,
+}
+
+impl ChainParams {
+    pub fn new(network: Network) -> Self {
+        match network {
+            Network::Mainnet => ChainParams {
+                network,
+                pow_limit: U256::from(
+                    "00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+                ),
+                pow_target_spacing: 10 * 60,
+                pow_target_timespan: 14 * 24 * 60 * 60,
+                pow_allow_min_difficulty_blocks: false,
+                pow_no_retargeting: false,
+            },
+            Network::Testnet => ChainParams {
+                network,
+                pow_limit: U256::from(
+                    "00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+                ),
+                pow_target_spacing: 10 * 60,
+                pow_target_timespan: 14 * 24 * 60 * 60,
+                pow_allow_min_difficulty_blocks: true,
+                pow_no_retargeting: false,
+            },
+            Network::Regtest => ChainParams {
+                network,
+                pow_limit: U256::from(
+                    "7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+                ),
+                pow_target_spacing: 10 * 60,
+                pow_target_timespan: 14 * 24 * 60 * 60,
+                pow_allow_min_difficulty_blocks: true,
+                pow_no_retargeting: true,
+            },
+        }
+    }
+
+    /// Number of blocks between two retargets (2016 on all networks)
+    pub fn difficulty_adjustment_interval(&self) -> u32 {
+        self.pow_target_timespan / self.pow_target_spacing
+    }
+}
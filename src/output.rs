@@ -1,27 +1,127 @@
-use crate::block::Header;
+use crate::block::{Block, Header};
+use crate::block_validation::{check_proof_of_work, check_witness_commitment};
+use crate::hash::Txid;
+use crate::merkle::compute_merkle_root;
 use crate::validation::Transaction;
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufWriter, Write};
+use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-pub(crate) fn write_block_to_file(
+/// Write the block in the grader format: the header hex, the witness serialized coinbase hex
+/// and one txid per line in display (RPC) order, starting with the coinbase
+pub fn write_block_to_file(
     header: &Header,
     transactions: &[Transaction],
-    path: &str,
+    path: &Path,
 ) -> Result<()> {
     let file = File::create(path)?;
     let mut writer = BufWriter::new(file);
 
-    writeln!(writer, "{}", header.to_hex()?)?;
+    writeln!(writer, "{}", header.to_hex())?;
 
     if let Some(coinbase) = transactions.first() {
-        writeln!(writer, "{}", serde_json::to_string(coinbase)?)?;
+        writeln!(writer, "{}", hex::encode(coinbase.serialize(true)?))?;
         for transaction in transactions {
-            writeln!(writer, "{}", transaction.id()?)?; // TODO id() correct?
+            writeln!(writer, "{}", transaction.id()?)?;
         }
     }
 
     Ok(())
 }
+
+/// Write the raw block as a single hex line, ready for `bitcoin-cli submitblock`
+pub fn write_raw_block_to_file(block: &Block, path: &Path) -> Result<()> {
+    let mut file = File::create(path)?;
+    writeln!(file, "{}", block.to_hex()?)?;
+    Ok(())
+}
+
+/// The contents of `output.txt`
+pub struct OutputFile {
+    pub header: Header,
+    pub coinbase: Transaction,
+    /// The coinbase first
+    pub txids: Vec<Txid>,
+}
+
+/// Parse a file written by `write_block_to_file`
+pub fn read_output_file(path: &Path) -> Result<OutputFile> {
+    let contents = fs::read_to_string(path)?;
+    let mut lines = contents.lines();
+
+    let mut header_bytes = [0u8; 80];
+    let header_hex = lines
+        .next()
+        .ok_or_else(|| anyhow!("Missing block header"))?;
+    hex::decode_to_slice(header_hex.trim(), &mut header_bytes)
+        .map_err(|e| anyhow!("Invalid block header: {e}"))?;
+    let header = Header::deserialize(&header_bytes);
+
+    let coinbase_hex = lines.next().ok_or_else(|| anyhow!("Missing coinbase"))?;
+    let coinbase_bytes = hex::decode(coinbase_hex.trim())?;
+    let mut reader = coinbase_bytes.as_slice();
+    let coinbase = Transaction::deserialize(&mut reader)?;
+    if !reader.is_empty() {
+        return Err(anyhow!(
+            "{} trailing bytes after the coinbase",
+            reader.len()
+        ));
+    }
+
+    let txids = lines
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<Txid>>>()?;
+
+    Ok(OutputFile {
+        header,
+        coinbase,
+        txids,
+    })
+}
+
+/// Check an output file the way the grader does: proof of work of the header, merkle root
+/// of the listed txids, the coinbase listed first and its witness commitment over
+/// `transactions`, the non-coinbase transactions of the block in order
+pub fn check_output_file(output: &OutputFile, transactions: &[Transaction]) -> Result<()> {
+    check_proof_of_work(&output.header)?;
+
+    if !output.coinbase.is_coinbase() {
+        return Err(anyhow!("Second line is not a coinbase transaction"));
+    }
+    if output.txids.first() != Some(&output.coinbase.id()?) {
+        return Err(anyhow!("First txid is not the coinbase txid"));
+    }
+
+    let (merkle_root, mutated) =
+        compute_merkle_root(output.txids.iter().map(|txid| (*txid).into()).collect())?;
+    if merkle_root != output.header.merkle_root {
+        return Err(anyhow!(
+            "Merkle root of the listed txids does not match the header"
+        ));
+    }
+    if mutated {
+        return Err(anyhow!("Merkle tree of the listed txids is mutated"));
+    }
+
+    if output.txids.len() != transactions.len() + 1 {
+        return Err(anyhow!(
+            "{} txids listed for {} transactions",
+            output.txids.len(),
+            transactions.len() + 1
+        ));
+    }
+    for (txid, tx) in output.txids[1..].iter().zip(transactions) {
+        if *txid != tx.id()? {
+            return Err(anyhow!("Listed txid {txid} does not match the block"));
+        }
+    }
+
+    let mut block_transactions = vec![output.coinbase.clone()];
+    block_transactions.extend_from_slice(transactions);
+    check_witness_commitment(&block_transactions)
+}
@@ -0,0 +1,108 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::info;
+use primitive_types::U256;
+
+use crate::block::{create_block, create_coinbase_transaction, Block};
+use crate::block_validation::{block_subsidy, sum_values, total_fees};
+use crate::config::Config;
+use crate::hash::BlockHash;
+use crate::select::select_transactions;
+use crate::validation::Transaction;
+use crate::{difficulty, input, validation};
+
+/// Read and validate the mempool
+pub fn load_valid_transactions(config: &Config) -> Result<Vec<Transaction>> {
+    // input
+    let stage_start = Instant::now();
+    let txs = input::read_mempool(&config.input_dir, config.network)?;
+    info!(
+        "All tx count: {:?} (loaded in {:.2?})",
+        txs.len(),
+        stage_start.elapsed()
+    );
+
+    // validation
+    let stage_start = Instant::now();
+    let validated_txs_hashmap =
+        validation::validate_all_transactions(txs, config.network, &config.policy);
+    let validated_txs: Vec<Transaction> = validated_txs_hashmap.into_values().collect();
+    info!(
+        "Validated tx count: {:?} (validated in {:.2?})",
+        validated_txs.len(),
+        stage_start.elapsed()
+    );
+
+    Ok(validated_txs)
+}
+
+/// Validate and select mempool transactions and build the (unmined) block around them
+pub fn build_block(config: &Config) -> Result<Block> {
+    let validated_txs = load_valid_transactions(config)?;
+
+    // selection
+    let stage_start = Instant::now();
+    let selected_txs = select_transactions(validated_txs, &config.policy);
+    info!(
+        "Selected tx count: {:?} (selected in {:.2?})",
+        selected_txs.len(),
+        stage_start.elapsed()
+    );
+
+    let stage_start = Instant::now();
+
+    let mut time = config.chain.time.unwrap_or_else(current_time);
+    if let Some(median_time_past) = config.chain.median_time_past {
+        time = time.max(median_time_past + 1);
+    }
+
+    let block = assemble_block(
+        config,
+        selected_txs,
+        config.chain.height,
+        config.chain.prev_hash,
+        time,
+        config.target()?,
+    )?;
+    info!("Block built in {:.2?}", stage_start.elapsed());
+    info!("Block header (before mining): {:?}", block.header);
+    info!(
+        "Block difficulty: {}",
+        difficulty::difficulty(block.header.bits)
+    );
+    info!("Block tx count: {:?}", block.transactions.len());
+
+    Ok(block)
+}
+
+/// Put the coinbase paying the configured outputs in front of the selected `transactions`
+/// and build the block at `height` on top of `previous_block_hash`
+pub fn assemble_block(
+    config: &Config,
+    mut transactions: Vec<Transaction>,
+    height: u32,
+    previous_block_hash: BlockHash,
+    time: u32,
+    target: U256,
+) -> Result<Block> {
+    let block_reward = match config.payout.reward {
+        Some(reward) => reward,
+        None => sum_values([block_subsidy(height), total_fees(&transactions)?])?,
+    };
+    let coinbase_tx = create_coinbase_transaction(
+        height,
+        &config.payout_outputs(block_reward)?,
+        config.network,
+    )?;
+    transactions.insert(0, coinbase_tx);
+
+    create_block(transactions, previous_block_hash, time, target)
+}
+
+pub(crate) fn current_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
@@ -0,0 +1,38 @@
+use serde::Deserialize;
+
+use crate::block_validation::MAX_BLOCK_WEIGHT;
+
+/// No amount (single output or sum) may reach this many satoshis
+pub const TOTAL_MONEY_CAP: u64 = 21_000_000 * 100_000_000;
+
+/// Largest transaction (JSON size in bytes) we look at
+const DEFAULT_MAX_TX_SIZE: usize = 1_000_000;
+
+/// Local thresholds on top of consensus: which valid transactions we accept into the
+/// candidate set and how full we build blocks
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Minimum absolute fee in satoshis
+    pub min_fee: u64,
+    /// Minimum fee rate in satoshis per virtual byte
+    pub min_feerate: f64,
+    /// Outputs below this value (other than OP_RETURN) make a transaction unacceptable
+    pub dust_limit: u64,
+    /// Largest accepted transaction, in bytes of its JSON
+    pub max_tx_size: usize,
+    /// Weight limit used for transaction selection, never above the consensus limit
+    pub max_block_weight: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            min_fee: 1,
+            min_feerate: 0.0,
+            dust_limit: 0,
+            max_tx_size: DEFAULT_MAX_TX_SIZE,
+            max_block_weight: MAX_BLOCK_WEIGHT,
+        }
+    }
+}
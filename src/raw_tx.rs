@@ -0,0 +1,200 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::address::script_to_address;
+use crate::input::FileError;
+use crate::network::Network;
+use crate::script::{script_type, to_asm};
+use crate::validation::{Output, PrevOut, Transaction};
+
+/// Suffix of the file holding the prevouts of a `<name>.hex` raw transaction
+pub(crate) const PREVOUTS_FILE_SUFFIX: &str = ".prevouts.json";
+
+/// An output spent by a raw transaction. Raw transactions only reference their inputs,
+/// so script and value have to be supplied separately.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RawPrevOut {
+    pub(crate) scriptpubkey: String,
+    pub(crate) value: u64,
+}
+
+/// Decode a legacy or segwit raw transaction into the esplora-style `Transaction`,
+/// deriving the `_asm`, `scriptpubkey_type` and `scriptpubkey_address` fields.
+/// `prevouts` are matched to the inputs by position, coinbase inputs take none.
+pub(crate) fn decode_raw_transaction(
+    raw_hex: &str,
+    prevouts: Vec<RawPrevOut>,
+    network: Network,
+) -> Result<Transaction> {
+    let raw = hex::decode(raw_hex.trim())?;
+    let mut reader = raw.as_slice();
+    let mut tx = Transaction::deserialize(&mut reader)?;
+    if !reader.is_empty() {
+        return Err(anyhow!(
+            "{} trailing bytes after the transaction",
+            reader.len()
+        ));
+    }
+
+    let spending_inputs = tx.vin.iter().filter(|input| !input.is_coinbase).count();
+    if prevouts.len() != spending_inputs {
+        return Err(anyhow!(
+            "Got {} prevouts for {} inputs",
+            prevouts.len(),
+            spending_inputs
+        ));
+    }
+
+    let mut prevouts = prevouts.into_iter();
+    for input in &mut tx.vin {
+        input.scriptsig_asm = to_asm(&hex::decode(&input.scriptsig)?);
+
+        if !input.is_coinbase {
+            let prevout = prevouts.next().unwrap();
+            let output = describe_output(&prevout.scriptpubkey, prevout.value, network)?;
+            input.prevout = PrevOut {
+                scriptpubkey: output.scriptpubkey,
+                scriptpubkey_asm: output.scriptpubkey_asm,
+                scriptpubkey_type: output.scriptpubkey_type,
+                scriptpubkey_address: output.scriptpubkey_address,
+                value: output.value,
+            };
+        }
+    }
+
+    for output in &mut tx.vout {
+        *output = describe_output(&output.scriptpubkey, output.value, network)?;
+    }
+
+    Ok(tx)
+}
+
+/// Output with all fields derived from its script
+pub(crate) fn describe_output(scriptpubkey: &str, value: u64, network: Network) -> Result<Output> {
+    let script = hex::decode(scriptpubkey)?;
+
+    Ok(Output {
+        scriptpubkey: scriptpubkey.to_string(),
+        scriptpubkey_asm: to_asm(&script),
+        scriptpubkey_type: script_type(&script).to_string(),
+        scriptpubkey_address: script_to_address(&script, network).unwrap_or_default(),
+        value,
+    })
+}
+
+/// Read `<name>.hex` and the prevouts next to it in `<name>.prevouts.json`
+pub(crate) fn read_raw_transaction_file(path: &Path, network: Network) -> Result<Transaction> {
+    let raw_hex = fs::read_to_string(path).context(FileError::new(path))?;
+
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| anyhow!("Invalid file name: {}", path.display()))?;
+    let prevouts_path = path.with_file_name(format!("{stem}{PREVOUTS_FILE_SUFFIX}"));
+    let prevouts = match prevouts_path.exists() {
+        true => {
+            let prevouts_json =
+                fs::read_to_string(&prevouts_path).context(FileError::new(&prevouts_path))?;
+            serde_json::from_str(&prevouts_json).context(FileError::new(&prevouts_path))?
+        }
+        false => vec![],
+    };
+
+    decode_raw_transaction(&raw_hex, prevouts, network)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consistency::find_mismatches;
+
+    /// The first bitcoin payment, from block 170
+    const LEGACY_TX: &str = "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000";
+    const LEGACY_PREVOUT: &str = "410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac";
+
+    /// BIP143's native P2WPKH example: a P2PK input followed by a P2WPKH input
+    const SEGWIT_TX: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+
+    fn legacy_prevouts() -> Vec<RawPrevOut> {
+        vec![RawPrevOut {
+            scriptpubkey: LEGACY_PREVOUT.to_string(),
+            value: 5_000_000_000,
+        }]
+    }
+
+    fn segwit_prevouts() -> Vec<RawPrevOut> {
+        vec![
+            RawPrevOut {
+                scriptpubkey:
+                    "2103c9f4836b9a4f77fc0d81f7bcb01b7f1b35916864b9476c241ce9fc198bd25432ac"
+                        .to_string(),
+                value: 625_000_000,
+            },
+            RawPrevOut {
+                scriptpubkey: "00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1".to_string(),
+                value: 600_000_000,
+            },
+        ]
+    }
+
+    #[test]
+    fn legacy_transaction_decodes() -> Result<()> {
+        let tx = decode_raw_transaction(LEGACY_TX, legacy_prevouts(), Network::Mainnet)?;
+
+        assert_eq!(
+            tx.id()?.to_string(),
+            "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16"
+        );
+        assert_eq!(hex::encode(tx.serialize(true)?), LEGACY_TX);
+        assert!(!tx.has_witness());
+        assert!(tx.vin[0].scriptsig_asm.starts_with("OP_PUSHBYTES_71 3044"));
+        assert_eq!(tx.vin[0].prevout.scriptpubkey_type, "p2pk");
+        assert_eq!(tx.vin[0].prevout.value, 5_000_000_000);
+
+        let values: Vec<u64> = tx.vout.iter().map(|output| output.value).collect();
+        assert_eq!(values, [1_000_000_000, 4_000_000_000]);
+        assert_eq!(tx.vout[1].scriptpubkey, LEGACY_PREVOUT);
+        assert_eq!(tx.vout[1].scriptpubkey_type, "p2pk");
+        assert_eq!(tx.vout[1].scriptpubkey_address, "");
+        assert!(find_mismatches(&tx, Network::Mainnet).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn segwit_transaction_decodes() -> Result<()> {
+        let tx = decode_raw_transaction(SEGWIT_TX, segwit_prevouts(), Network::Mainnet)?;
+
+        assert_eq!(hex::encode(tx.serialize(true)?), SEGWIT_TX);
+        assert_ne!(tx.id()?.as_bytes(), tx.wtxid()?.as_bytes());
+        assert!(tx.vin[0].witness.is_empty());
+        assert_eq!(tx.vin[1].witness.len(), 2);
+        assert_eq!(tx.vin[1].prevout.scriptpubkey_type, "v0_p2wpkh");
+        assert_eq!(
+            tx.vin[1].prevout.scriptpubkey_address,
+            "bc1qr583w2swedy2acd7rung055k8t3n7udp7vyzyg"
+        );
+        assert_eq!(tx.locktime, 17);
+
+        let values: Vec<u64> = tx.vout.iter().map(|output| output.value).collect();
+        assert_eq!(values, [112_340_000, 223_450_000]);
+        assert_eq!(tx.vout[0].scriptpubkey_type, "p2pkh");
+        assert!(find_mismatches(&tx, Network::Mainnet).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_raw_transactions_are_rejected() {
+        // Prevouts have to match the spending inputs
+        assert!(decode_raw_transaction(LEGACY_TX, vec![], Network::Mainnet).is_err());
+        assert!(decode_raw_transaction(SEGWIT_TX, legacy_prevouts(), Network::Mainnet).is_err());
+
+        let trailing = format!("{LEGACY_TX}00");
+        assert!(decode_raw_transaction(&trailing, legacy_prevouts(), Network::Mainnet).is_err());
+        let truncated = &LEGACY_TX[..LEGACY_TX.len() - 2];
+        assert!(decode_raw_transaction(truncated, legacy_prevouts(), Network::Mainnet).is_err());
+        assert!(decode_raw_transaction("zz", vec![], Network::Mainnet).is_err());
+    }
+}
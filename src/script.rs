@@ -0,0 +1,531 @@
+use anyhow::{anyhow, Result};
+
+pub(crate) const OP_0: u8 = 0x00;
+pub(crate) const OP_PUSHDATA1: u8 = 0x4c;
+pub(crate) const OP_PUSHDATA2: u8 = 0x4d;
+pub(crate) const OP_PUSHDATA4: u8 = 0x4e;
+pub(crate) const OP_1NEGATE: u8 = 0x4f;
+pub(crate) const OP_1: u8 = 0x51;
+pub(crate) const OP_16: u8 = 0x60;
+pub(crate) const OP_RETURN: u8 = 0x6a;
+pub(crate) const OP_DUP: u8 = 0x76;
+pub(crate) const OP_EQUAL: u8 = 0x87;
+pub(crate) const OP_EQUALVERIFY: u8 = 0x88;
+pub(crate) const OP_HASH160: u8 = 0xa9;
+pub(crate) const OP_CHECKSIG: u8 = 0xac;
+pub(crate) const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub(crate) const OP_CHECKMULTISIG: u8 = 0xae;
+pub(crate) const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+
+/// Names of the opcodes 0x61 (OP_NOP) to 0xba (OP_CHECKSIGADD), as esplora prints them
+const OPCODE_NAMES: [&str; 90] = [
+    "OP_NOP",
+    "OP_VER",
+    "OP_IF",
+    "OP_NOTIF",
+    "OP_VERIF",
+    "OP_VERNOTIF",
+    "OP_ELSE",
+    "OP_ENDIF",
+    "OP_VERIFY",
+    "OP_RETURN",
+    "OP_TOALTSTACK",
+    "OP_FROMALTSTACK",
+    "OP_2DROP",
+    "OP_2DUP",
+    "OP_3DUP",
+    "OP_2OVER",
+    "OP_2ROT",
+    "OP_2SWAP",
+    "OP_IFDUP",
+    "OP_DEPTH",
+    "OP_DROP",
+    "OP_DUP",
+    "OP_NIP",
+    "OP_OVER",
+    "OP_PICK",
+    "OP_ROLL",
+    "OP_ROT",
+    "OP_SWAP",
+    "OP_TUCK",
+    "OP_CAT",
+    "OP_SUBSTR",
+    "OP_LEFT",
+    "OP_RIGHT",
+    "OP_SIZE",
+    "OP_INVERT",
+    "OP_AND",
+    "OP_OR",
+    "OP_XOR",
+    "OP_EQUAL",
+    "OP_EQUALVERIFY",
+    "OP_RESERVED1",
+    "OP_RESERVED2",
+    "OP_1ADD",
+    "OP_1SUB",
+    "OP_2MUL",
+    "OP_2DIV",
+    "OP_NEGATE",
+    "OP_ABS",
+    "OP_NOT",
+    "OP_0NOTEQUAL",
+    "OP_ADD",
+    "OP_SUB",
+    "OP_MUL",
+    "OP_DIV",
+    "OP_MOD",
+    "OP_LSHIFT",
+    "OP_RSHIFT",
+    "OP_BOOLAND",
+    "OP_BOOLOR",
+    "OP_NUMEQUAL",
+    "OP_NUMEQUALVERIFY",
+    "OP_NUMNOTEQUAL",
+    "OP_LESSTHAN",
+    "OP_GREATERTHAN",
+    "OP_LESSTHANOREQUAL",
+    "OP_GREATERTHANOREQUAL",
+    "OP_MIN",
+    "OP_MAX",
+    "OP_WITHIN",
+    "OP_RIPEMD160",
+    "OP_SHA1",
+    "OP_SHA256",
+    "OP_HASH160",
+    "OP_HASH256",
+    "OP_CODESEPARATOR",
+    "OP_CHECKSIG",
+    "OP_CHECKSIGVERIFY",
+    "OP_CHECKMULTISIG",
+    "OP_CHECKMULTISIGVERIFY",
+    "OP_NOP1",
+    "OP_CLTV",
+    "OP_CSV",
+    "OP_NOP4",
+    "OP_NOP5",
+    "OP_NOP6",
+    "OP_NOP7",
+    "OP_NOP8",
+    "OP_NOP9",
+    "OP_NOP10",
+    "OP_CHECKSIGADD",
+];
+
+/// Sigops a bare OP_CHECKMULTISIG counts for when the key count is unknown
+const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+/// A single script element: the opcode and, for push opcodes, the pushed data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Instruction<'a> {
+    pub(crate) opcode: u8,
+    pub(crate) push_data: Option<&'a [u8]>,
+}
+
+/// Iterates over the instructions of a script, yields an `Err` (and stops) when a push
+/// runs past the end of the script
+pub(crate) struct Instructions<'a> {
+    script: &'a [u8],
+    position: usize,
+}
+
+pub(crate) fn instructions(script: &[u8]) -> Instructions<'_> {
+    Instructions {
+        script,
+        position: 0,
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let opcode = *self.script.get(self.position)?;
+        self.position += 1;
+
+        let push_len = match opcode {
+            0x01..=0x4b => Some(opcode as usize),
+            OP_PUSHDATA1 => self.read_push_len(1),
+            OP_PUSHDATA2 => self.read_push_len(2),
+            OP_PUSHDATA4 => self.read_push_len(4),
+            _ => {
+                return Some(Ok(Instruction {
+                    opcode,
+                    push_data: (opcode == OP_0).then_some(&[][..]),
+                }))
+            }
+        };
+
+        let data = push_len.and_then(|len| self.script.get(self.position..self.position + len));
+        match data {
+            Some(data) => {
+                self.position += data.len();
+                Some(Ok(Instruction {
+                    opcode,
+                    push_data: Some(data),
+                }))
+            }
+            None => {
+                self.position = self.script.len();
+                Some(Err(()))
+            }
+        }
+    }
+}
+
+impl Instructions<'_> {
+    fn read_push_len(&mut self, size: usize) -> Option<usize> {
+        let len_bytes = self.script.get(self.position..self.position + size)?;
+        self.position += size;
+
+        let mut le_bytes = [0u8; 4];
+        le_bytes[..size].copy_from_slice(len_bytes);
+        Some(u32::from_le_bytes(le_bytes) as usize)
+    }
+}
+
+/// Number of signature operations in `script`, as Bitcoin Core's `GetSigOpCount`.
+/// With `accurate` a multisig preceded by OP_1..OP_16 counts for that many keys.
+pub(crate) fn count_sigops(script: &[u8], accurate: bool) -> usize {
+    let mut count = 0;
+    let mut last_opcode = None;
+
+    for instruction in instructions(script) {
+        let Ok(instruction) = instruction else {
+            break;
+        };
+
+        match instruction.opcode {
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => count += 1,
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                count += match last_opcode {
+                    Some(opcode @ OP_1..=OP_16) if accurate => (opcode - OP_1 + 1) as usize,
+                    _ => MAX_PUBKEYS_PER_MULTISIG,
+                }
+            }
+            _ => {}
+        }
+        last_opcode = Some(instruction.opcode);
+    }
+
+    count
+}
+
+/// Esplora style name of `opcode`, e.g. OP_PUSHBYTES_20, OP_PUSHNUM_1 or OP_CHECKSIG
+pub(crate) fn opcode_name(opcode: u8) -> String {
+    match opcode {
+        OP_0 => "OP_0".to_string(),
+        0x01..=0x4b => format!("OP_PUSHBYTES_{opcode}"),
+        OP_PUSHDATA1 => "OP_PUSHDATA1".to_string(),
+        OP_PUSHDATA2 => "OP_PUSHDATA2".to_string(),
+        OP_PUSHDATA4 => "OP_PUSHDATA4".to_string(),
+        OP_1NEGATE => "OP_PUSHNUM_NEG1".to_string(),
+        0x50 => "OP_RESERVED".to_string(),
+        OP_1..=OP_16 => format!("OP_PUSHNUM_{}", opcode - OP_1 + 1),
+        0x61..=0xba => OPCODE_NAMES[(opcode - 0x61) as usize].to_string(),
+        0xbb..=0xfe => format!("OP_RETURN_{opcode}"),
+        0xff => "OP_INVALIDOPCODE".to_string(),
+    }
+}
+
+/// Disassemble `script` into esplora's ASM notation: opcode names separated by spaces,
+/// pushes followed by their data in hex
+pub(crate) fn to_asm(script: &[u8]) -> String {
+    let mut parts = Vec::new();
+    for instruction in instructions(script) {
+        match instruction {
+            Ok(Instruction {
+                opcode,
+                push_data: Some(data),
+            }) if opcode != OP_0 => {
+                parts.push(opcode_name(opcode));
+                parts.push(hex::encode(data));
+            }
+            Ok(instruction) => parts.push(opcode_name(instruction.opcode)),
+            Err(()) => parts.push("<unexpected end>".to_string()),
+        }
+    }
+
+    parts.join(" ")
+}
+
+/// Opcode for an esplora style name, the inverse of `opcode_name`
+pub(crate) fn opcode_from_name(name: &str) -> Option<u8> {
+    if let Some(n) = name.strip_prefix("OP_PUSHBYTES_") {
+        return n.parse().ok().filter(|n| (0x01..=0x4b).contains(n));
+    }
+    if let Some(n) = name.strip_prefix("OP_PUSHNUM_") {
+        return match n {
+            "NEG1" => Some(OP_1NEGATE),
+            _ => n
+                .parse::<u8>()
+                .ok()
+                .filter(|n| (1..=16).contains(n))
+                .map(|n| OP_1 + n - 1),
+        };
+    }
+    if let Some(n) = name.strip_prefix("OP_RETURN_") {
+        return n.parse().ok().filter(|n| (0xbb..=0xfe).contains(n));
+    }
+
+    match name {
+        "OP_0" => Some(OP_0),
+        "OP_PUSHDATA1" => Some(OP_PUSHDATA1),
+        "OP_PUSHDATA2" => Some(OP_PUSHDATA2),
+        "OP_PUSHDATA4" => Some(OP_PUSHDATA4),
+        "OP_RESERVED" => Some(0x50),
+        "OP_INVALIDOPCODE" => Some(0xff),
+        _ => OPCODE_NAMES
+            .iter()
+            .position(|candidate| *candidate == name)
+            .map(|index| 0x61 + index as u8),
+    }
+}
+
+/// Assemble esplora's ASM notation back into script bytes. Every push opcode has to be
+/// followed by its data in hex, with a length matching the opcode.
+pub(crate) fn from_asm(asm: &str) -> Result<Vec<u8>> {
+    let mut script = Vec::new();
+    let mut tokens = asm.split_whitespace();
+
+    while let Some(token) = tokens.next() {
+        let opcode = opcode_from_name(token).ok_or_else(|| anyhow!("Unknown opcode {token}"))?;
+        script.push(opcode);
+
+        let length_bytes = match opcode {
+            OP_PUSHDATA1 => 1,
+            OP_PUSHDATA2 => 2,
+            OP_PUSHDATA4 => 4,
+            0x01..=0x4b => 0,
+            _ => continue,
+        };
+
+        let data_hex = tokens
+            .next()
+            .ok_or_else(|| anyhow!("{token} is missing its data"))?;
+        let data = hex::decode(data_hex)?;
+
+        if length_bytes == 0 {
+            if data.len() != opcode as usize {
+                return Err(anyhow!("{token} followed by {} bytes", data.len()));
+            }
+        } else {
+            let length = (data.len() as u32).to_le_bytes();
+            if length[length_bytes..].iter().any(|byte| *byte != 0) {
+                return Err(anyhow!("{token} cannot push {} bytes", data.len()));
+            }
+            script.extend_from_slice(&length[..length_bytes]);
+        }
+        script.extend_from_slice(&data);
+    }
+
+    Ok(script)
+}
+
+/// Esplora's classification of an output script
+pub(crate) fn script_type(script: &[u8]) -> &'static str {
+    match script {
+        [OP_DUP, OP_HASH160, 0x14, .., OP_EQUALVERIFY, OP_CHECKSIG] if script.len() == 25 => {
+            "p2pkh"
+        }
+        _ if is_p2sh(script) => "p2sh",
+        [0x21, .., OP_CHECKSIG] if script.len() == 35 => "p2pk",
+        [0x41, .., OP_CHECKSIG] if script.len() == 67 => "p2pk",
+        [OP_RETURN, ..] => "op_return",
+        _ => match witness_program(script) {
+            Some((0, program)) if program.len() == 20 => "v0_p2wpkh",
+            Some((0, program)) if program.len() == 32 => "v0_p2wsh",
+            Some((1, program)) if program.len() == 32 => "v1_p2tr",
+            _ => "unknown",
+        },
+    }
+}
+
+/// Data pushed by the last instruction of a push-only script (the P2SH redeem script)
+pub(crate) fn last_push(script: &[u8]) -> Option<&[u8]> {
+    let mut last = None;
+    for instruction in instructions(script) {
+        last = Some(instruction.ok()?.push_data?);
+    }
+    last
+}
+
+pub(crate) fn is_p2sh(script: &[u8]) -> bool {
+    script.len() == 23 && script[0] == OP_HASH160 && script[1] == 0x14 && script[22] == OP_EQUAL
+}
+
+/// Version and program of a witness program script (OP_n followed by a 2 to 40 byte push)
+pub(crate) fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    if script.len() < 4 || script.len() > 42 || script[1] as usize + 2 != script.len() {
+        return None;
+    }
+
+    match script[0] {
+        OP_0 => Some((0, &script[2..])),
+        OP_1..=OP_16 => Some((script[0] - OP_1 + 1, &script[2..])),
+        _ => None,
+    }
+}
+
+/// Script pushing `n` the way BIP34 expects the height in the coinbase (minimal CScriptNum)
+pub(crate) fn push_int(n: i64) -> Vec<u8> {
+    match n {
+        0 => vec![OP_0],
+        -1 => vec![OP_1NEGATE],
+        1..=16 => vec![OP_1 + n as u8 - 1],
+        _ => {
+            let number = script_num(n);
+            let mut script = vec![number.len() as u8];
+            script.extend_from_slice(&number);
+            script
+        }
+    }
+}
+
+/// Minimal little endian sign-magnitude encoding of a script number
+fn script_num(n: i64) -> Vec<u8> {
+    let mut result = Vec::new();
+    let negative = n < 0;
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        result.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+
+    // The most significant bit carries the sign, add a byte if it is already taken
+    if let Some(last) = result.last_mut() {
+        if *last & 0x80 != 0 {
+            result.push(if negative { 0x80 } else { 0x00 });
+        } else if negative {
+            *last |= 0x80;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBKEY_HASH: &str = "751e76e8199196d454941c45d1b3a323f1433bd6";
+
+    #[test]
+    fn asm_round_trips() -> Result<()> {
+        let p2pkh = format!("76a914{PUBKEY_HASH}88ac");
+        let key = format!("02{}", "11".repeat(32));
+        let multisig = format!("5221{key}21{key}21{key}53ae");
+        let pushdata1 = format!("6a4c50{}", "ab".repeat(80));
+        let pushdata2 = format!("6a4d2c01{}", "cd".repeat(300));
+        let pushdata4 = format!("6a4e03000000{}", "ef".repeat(3));
+        let scripts = [
+            p2pkh.as_str(),
+            "a914748284390f9e263a4b766a75d0633c50426eb87587",
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+            "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c",
+            multisig.as_str(),
+            pushdata1.as_str(),
+            pushdata2.as_str(),
+            pushdata4.as_str(),
+            "004f5060bbfeff",
+        ];
+
+        for script_hex in scripts {
+            let script = hex::decode(script_hex)?;
+            assert_eq!(from_asm(&to_asm(&script))?, script, "script {script_hex}");
+        }
+
+        assert_eq!(
+            to_asm(&hex::decode(&p2pkh)?),
+            format!("OP_DUP OP_HASH160 OP_PUSHBYTES_20 {PUBKEY_HASH} OP_EQUALVERIFY OP_CHECKSIG")
+        );
+        assert_eq!(
+            to_asm(&hex::decode("004f5060bbfeff")?),
+            "OP_0 OP_PUSHNUM_NEG1 OP_RESERVED OP_PUSHNUM_16 OP_RETURN_187 OP_RETURN_254 \
+             OP_INVALIDOPCODE"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn every_opcode_round_trips() -> Result<()> {
+        for opcode in 0..=u8::MAX {
+            let script = match opcode {
+                0x01..=0x4b => [vec![opcode], vec![0x42; opcode as usize]].concat(),
+                OP_PUSHDATA1 => vec![opcode, 1, 0x42],
+                OP_PUSHDATA2 => vec![opcode, 1, 0, 0x42],
+                OP_PUSHDATA4 => vec![opcode, 1, 0, 0, 0, 0x42],
+                _ => vec![opcode],
+            };
+            assert_eq!(from_asm(&to_asm(&script))?, script, "opcode {opcode:#04x}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_asm_is_rejected() {
+        assert_eq!(to_asm(&[0x14, 0x01]), "<unexpected end>");
+        assert_eq!(
+            to_asm(&[OP_CHECKSIG, OP_PUSHDATA2, 0x01]),
+            "OP_CHECKSIG <unexpected end>"
+        );
+
+        assert!(from_asm("OP_NOT_AN_OPCODE").is_err());
+        assert!(from_asm("OP_PUSHBYTES_2 ab").is_err());
+        assert!(from_asm("OP_PUSHBYTES_76 ab").is_err());
+        assert!(from_asm("OP_PUSHBYTES_1").is_err());
+        assert!(from_asm("OP_PUSHBYTES_1 zz").is_err());
+        assert!(from_asm(&format!("OP_PUSHDATA1 {}", "00".repeat(256))).is_err());
+        assert!(from_asm("OP_PUSHNUM_17").is_err());
+        assert_eq!(from_asm("").unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn push_int_is_minimal() {
+        let vectors: [(i64, &str); 16] = [
+            (0, "00"),
+            (-1, "4f"),
+            (1, "51"),
+            (16, "60"),
+            (17, "0111"),
+            (-2, "0182"),
+            (-16, "0190"),
+            (-17, "0191"),
+            (127, "017f"),
+            (128, "028000"),
+            (-127, "01ff"),
+            (-128, "028080"),
+            (255, "02ff00"),
+            (256, "020001"),
+            (-256, "020081"),
+            // BIP34 height of block 500,000
+            (500_000, "0320a107"),
+        ];
+
+        for (n, expected) in vectors {
+            assert_eq!(hex::encode(push_int(n)), expected, "push_int({n})");
+        }
+    }
+
+    #[test]
+    fn sigops_are_counted_like_bitcoin_core() -> Result<()> {
+        let p2pkh = hex::decode(format!("76a914{PUBKEY_HASH}88ac"))?;
+        assert_eq!(count_sigops(&p2pkh, false), 1);
+
+        let key = format!("21{}", "11".repeat(33));
+        let multisig = hex::decode(format!("52{key}{key}{key}53ae"))?;
+        assert_eq!(count_sigops(&multisig, true), 3);
+        assert_eq!(count_sigops(&multisig, false), MAX_PUBKEYS_PER_MULTISIG);
+
+        // Without a preceding key count a multisig counts for the maximum
+        let bare = [OP_CHECKMULTISIGVERIFY, OP_CHECKSIGVERIFY];
+        assert_eq!(count_sigops(&bare, true), MAX_PUBKEYS_PER_MULTISIG + 1);
+
+        // Pushed data is not counted, and counting stops at a truncated push
+        assert_eq!(count_sigops(&[0x01, OP_CHECKSIG], true), 0);
+        assert_eq!(
+            count_sigops(&[OP_CHECKSIG, OP_PUSHDATA1, 0x05, OP_CHECKSIG], true),
+            1
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+
+use crate::block_validation::{MAX_BLOCK_SIGOPS_COST, MAX_BLOCK_WEIGHT};
+use crate::hash::Txid;
+use crate::policy::Policy;
+use crate::validation::Transaction;
+
+/// Weight kept free for the block header, transaction count and coinbase
+const COINBASE_WEIGHT_RESERVE: usize = 4_000;
+
+/// Sigop cost kept free for the coinbase
+const COINBASE_SIGOPS_RESERVE: usize = 400;
+
+/// Greedily pick the transactions with the highest fee per weight unit that fit into a block.
+/// A transaction spending another mempool transaction is only taken once its parent is in.
+pub fn select_transactions(transactions: Vec<Transaction>, policy: &Policy) -> Vec<Transaction> {
+    let mempool_txids: HashSet<Txid> = transactions.iter().filter_map(|tx| tx.id().ok()).collect();
+
+    // Transactions whose fee does not compute cannot be mined
+    let mut candidates: Vec<(Transaction, Txid, u64, usize, usize)> = transactions
+        .into_iter()
+        .filter_map(|tx| {
+            let txid = tx.id().ok()?;
+            let fee = tx.fee().ok()?;
+            let weight = tx.weight().ok()?;
+            let sigop_cost = tx.sigop_cost().ok()?;
+            Some((tx, txid, fee, weight, sigop_cost))
+        })
+        .collect();
+    candidates.sort_by(|(_, _, a_fee, a_weight, _), (_, _, b_fee, b_weight, _)| {
+        // Compare a.fee / a.weight with b.fee / b.weight without dividing
+        let a_rate = *a_fee as u128 * *b_weight as u128;
+        let b_rate = *b_fee as u128 * *a_weight as u128;
+        b_rate.cmp(&a_rate)
+    });
+
+    let max_weight = policy
+        .max_block_weight
+        .min(MAX_BLOCK_WEIGHT)
+        .saturating_sub(COINBASE_WEIGHT_RESERVE);
+    let max_sigops = MAX_BLOCK_SIGOPS_COST - COINBASE_SIGOPS_RESERVE;
+    let mut block_weight = 0;
+    let mut block_sigops = 0;
+    let mut included_txids = HashSet::new();
+    let mut selected = Vec::new();
+
+    // Every pass may unlock children of transactions taken in the previous pass
+    loop {
+        let mut remaining = Vec::new();
+        let selected_before = selected.len();
+
+        for (tx, txid, fee, weight, sigop_cost) in candidates {
+            let fits =
+                block_weight + weight <= max_weight && block_sigops + sigop_cost <= max_sigops;
+            let parents_included = tx.vin.iter().all(|input| {
+                !mempool_txids.contains(&input.txid) || included_txids.contains(&input.txid)
+            });
+
+            if fits && parents_included {
+                block_weight += weight;
+                block_sigops += sigop_cost;
+                included_txids.insert(txid);
+                selected.push(tx);
+            } else if fits {
+                remaining.push((tx, txid, fee, weight, sigop_cost));
+            }
+        }
+
+        if selected.len() == selected_before || remaining.is_empty() {
+            break;
+        }
+        candidates = remaining;
+    }
+
+    selected
+}
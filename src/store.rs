@@ -0,0 +1,694 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::block_validation::MAX_BLOCK_WEIGHT;
+use crate::chain::ChainState;
+use crate::difficulty::BlockInfo;
+use crate::hash::{BlockHash, OutPoint};
+use crate::network::Network;
+use crate::utxo::{Utxo, UtxoDelta, UtxoSet};
+
+/// The chain the first stored block builds on, reindexing starts from it
+const BASE_FILE: &str = "base.json";
+/// Append-only raw blocks, each behind the network magic and its size
+const BLOCKS_FILE: &str = "blocks.dat";
+/// Append-only block index, one JSON entry per line
+const INDEX_FILE: &str = "index.jsonl";
+/// Append-only UTXO changes of every block, one JSON entry per line
+const DELTAS_FILE: &str = "deltas.jsonl";
+/// UTXO set at a recent block, the deltas after it are replayed on opening
+const UTXOS_FILE: &str = "utxos.json";
+
+/// Blocks between two UTXO set snapshots
+const SNAPSHOT_INTERVAL: u32 = 100;
+
+/// Where a stored block is and what the chain rules need from its header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockIndexEntry {
+    pub height: u32,
+    pub hash: BlockHash,
+    pub previous_block_hash: BlockHash,
+    pub time: u32,
+    pub bits: u32,
+    /// Offset of the raw block in the block file, after its magic and size
+    pub offset: u64,
+    pub size: u32,
+}
+
+/// UTXO set at a tip as stored on disk, keyed by `txid:vout`
+#[derive(Debug, Serialize, Deserialize)]
+struct UtxoSnapshot {
+    network: Network,
+    tip: BlockHash,
+    /// Height of the next block
+    height: u32,
+    utxos: BTreeMap<String, Utxo>,
+}
+
+impl UtxoSnapshot {
+    fn new(state: &ChainState) -> UtxoSnapshot {
+        UtxoSnapshot {
+            network: state.params.network,
+            tip: state.tip,
+            height: state.height,
+            utxos: state
+                .utxos
+                .iter()
+                .map(|(outpoint, utxo)| (outpoint.to_string(), utxo.clone()))
+                .collect(),
+        }
+    }
+
+    fn into_state(self) -> Result<ChainState> {
+        let utxos = self
+            .utxos
+            .into_iter()
+            .map(|(outpoint, utxo)| Ok((outpoint.parse()?, utxo)))
+            .collect::<Result<UtxoSet>>()?;
+        Ok(ChainState::new(self.network, self.tip, self.height, utxos))
+    }
+}
+
+/// UTXO changes of the stored block at `height`, outpoints as `txid:vout`
+#[derive(Debug, Serialize, Deserialize)]
+struct DeltaRecord {
+    height: u32,
+    hash: BlockHash,
+    spent: BTreeMap<String, Utxo>,
+    created: BTreeMap<String, Utxo>,
+}
+
+impl DeltaRecord {
+    fn new(height: u32, hash: BlockHash, delta: &UtxoDelta) -> DeltaRecord {
+        let keyed_by_string = |utxos: &BTreeMap<OutPoint, Utxo>| {
+            utxos
+                .iter()
+                .map(|(outpoint, utxo)| (outpoint.to_string(), utxo.clone()))
+                .collect()
+        };
+        DeltaRecord {
+            height,
+            hash,
+            spent: keyed_by_string(&delta.spent),
+            created: keyed_by_string(&delta.created),
+        }
+    }
+
+    fn to_delta(&self) -> Result<UtxoDelta> {
+        let keyed_by_outpoint = |utxos: &BTreeMap<String, Utxo>| {
+            utxos
+                .iter()
+                .map(|(outpoint, utxo)| Ok((outpoint.parse()?, utxo.clone())))
+                .collect::<Result<_>>()
+        };
+        Ok(UtxoDelta {
+            spent: keyed_by_outpoint(&self.spent)?,
+            created: keyed_by_outpoint(&self.created)?,
+        })
+    }
+}
+
+/// Blocks and UTXO set of a chain, stored in a directory or kept in memory
+#[derive(Debug)]
+pub struct ChainStore {
+    dir: Option<PathBuf>,
+    index: Vec<BlockIndexEntry>,
+    state: ChainState,
+}
+
+impl ChainStore {
+    /// Store nothing, only keep the chain state
+    pub fn in_memory(state: ChainState) -> ChainStore {
+        ChainStore {
+            dir: None,
+            index: Vec::new(),
+            state,
+        }
+    }
+
+    /// Whether `dir` holds a stored chain
+    pub fn exists(dir: &Path) -> bool {
+        dir.join(BASE_FILE).is_file()
+    }
+
+    /// Start storing a new chain on top of `base` in `dir`
+    pub fn create(dir: &Path, base: ChainState) -> Result<ChainStore> {
+        if ChainStore::exists(dir) {
+            return Err(anyhow!("{} already holds a chain", dir.display()));
+        }
+        if !base.blocks.is_empty() {
+            return Err(anyhow!("A stored chain has to start without blocks"));
+        }
+
+        fs::create_dir_all(dir)?;
+        write_json_atomically(&dir.join(BASE_FILE), &UtxoSnapshot::new(&base))?;
+        File::create(dir.join(BLOCKS_FILE))?;
+        File::create(dir.join(INDEX_FILE))?;
+        File::create(dir.join(DELTAS_FILE))?;
+        write_json_atomically(&dir.join(UTXOS_FILE), &UtxoSnapshot::new(&base))?;
+
+        Ok(ChainStore {
+            dir: Some(dir.to_path_buf()),
+            index: Vec::new(),
+            state: base,
+        })
+    }
+
+    /// Load the chain stored in `dir` and resume from its tip: the last UTXO set snapshot
+    /// with the deltas of the blocks after it replayed. Fails when the snapshot and the
+    /// deltas do not reach the last indexed block, which a reindex repairs.
+    pub fn open(dir: &Path) -> Result<ChainStore> {
+        let base = read_snapshot(&dir.join(BASE_FILE))?;
+        let network = base.network;
+
+        // An entry cut off by a crash leaves its block unindexed, which is dropped below
+        let index_path = dir.join(INDEX_FILE);
+        let index_lines = read_json_lines::<BlockIndexEntry>(&index_path)?;
+        let index_size = index_lines.last().map_or(0, |(_, end)| *end);
+        let index_file_size = fs::metadata(&index_path)?.len();
+        if index_size < index_file_size {
+            warn!(
+                "Dropping {} bytes of an incomplete entry at the end of {}",
+                index_file_size - index_size,
+                index_path.display()
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&index_path)?
+                .set_len(index_size)?;
+        }
+        let index: Vec<BlockIndexEntry> = index_lines.into_iter().map(|(entry, _)| entry).collect();
+        let indexed_tip = index.last().map_or(base.tip, |entry| entry.hash);
+        let indexed_height = index.last().map_or(base.height, |entry| entry.height + 1);
+
+        // Drop a block written before a crash stopped it from being indexed, the next block
+        // takes its place
+        let blocks_path = dir.join(BLOCKS_FILE);
+        let indexed_size = index
+            .last()
+            .map_or(0, |entry| entry.offset + entry.size as u64);
+        truncate_unindexed(&blocks_path, indexed_size, indexed_tip)?;
+
+        let deltas_path = dir.join(DELTAS_FILE);
+        let deltas: Vec<(DeltaRecord, u64)> = read_json_lines::<DeltaRecord>(&deltas_path)?
+            .into_iter()
+            .take_while(|(record, _)| record.height < indexed_height)
+            .collect();
+        let deltas_size = deltas.last().map_or(0, |(_, end)| *end);
+        truncate_unindexed(&deltas_path, deltas_size, indexed_tip)?;
+
+        let snapshot = read_snapshot(&dir.join(UTXOS_FILE))?;
+        if snapshot.network != network {
+            return Err(anyhow!(
+                "UTXO set of {} is on {:?}, the chain on {network:?}",
+                dir.display(),
+                snapshot.network
+            ));
+        }
+        let snapshot_on_chain = snapshot.tip == base.tip
+            || index
+                .iter()
+                .any(|entry| entry.hash == snapshot.tip && entry.height + 1 == snapshot.height);
+        if !snapshot_on_chain {
+            return Err(anyhow!(
+                "UTXO set of {} is at {}, which is not in the block index, run reindex",
+                dir.display(),
+                snapshot.tip
+            ));
+        }
+
+        let mut state = snapshot.into_state()?;
+        let snapshot_height = state.height;
+        for (record, _) in deltas
+            .iter()
+            .filter(|(record, _)| record.height >= snapshot_height)
+        {
+            let indexed_hash = index
+                .iter()
+                .find(|entry| entry.height == record.height)
+                .map(|entry| entry.hash);
+            if record.height != state.height || indexed_hash != Some(record.hash) {
+                break;
+            }
+            state.utxos.apply_delta(&record.to_delta()?);
+            state.tip = record.hash;
+            state.height += 1;
+        }
+        if state.tip != indexed_tip {
+            return Err(anyhow!(
+                "UTXO set of {} only reaches {}, the block index {indexed_tip}, run reindex",
+                dir.display(),
+                state.tip
+            ));
+        }
+
+        state.blocks = index
+            .iter()
+            .map(|entry| BlockInfo {
+                height: entry.height,
+                time: entry.time,
+                bits: entry.bits,
+            })
+            .collect();
+
+        Ok(ChainStore {
+            dir: Some(dir.to_path_buf()),
+            index,
+            state,
+        })
+    }
+
+    /// Rebuild the block index, the UTXO deltas and the UTXO set of the chain in `dir` by
+    /// connecting every block of the block file to the base again. A block cut off at the
+    /// end of the file is dropped.
+    pub fn reindex(dir: &Path) -> Result<ChainStore> {
+        let base = read_snapshot(&dir.join(BASE_FILE))?;
+        let magic = base.network.magic();
+        let mut store = ChainStore {
+            dir: Some(dir.to_path_buf()),
+            index: Vec::new(),
+            state: base.into_state()?,
+        };
+
+        let blocks_path = dir.join(BLOCKS_FILE);
+        let mut bytes = Vec::new();
+        File::open(&blocks_path)?.read_to_end(&mut bytes)?;
+
+        let mut deltas = Vec::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            let record = &bytes[position..];
+            if record[..record.len().min(4)] != magic[..record.len().min(4)] {
+                return Err(anyhow!(
+                    "No block magic at offset {position} of {}",
+                    blocks_path.display()
+                ));
+            }
+
+            // A size no block can have is corrupt, a plausible one beyond the end of the
+            // file is the last append cut off by a crash
+            if record.len() >= 8 && read_size(record) as usize > MAX_BLOCK_WEIGHT {
+                return Err(anyhow!(
+                    "Block at offset {position} of {} claims an impossible size of {} bytes",
+                    blocks_path.display(),
+                    read_size(record)
+                ));
+            }
+            if record.len() < 8 || record.len() < 8 + read_size(record) as usize {
+                warn!(
+                    "Dropping {} bytes of an incomplete block at the end of {}",
+                    record.len(),
+                    blocks_path.display()
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&blocks_path)?
+                    .set_len(position as u64)?;
+                break;
+            }
+
+            let size = read_size(record);
+            let offset = position as u64 + 8;
+            let mut block = Block::deserialize(&record[8..8 + size as usize])?;
+            let delta = store.state.connect_block(&mut block)?;
+            let entry = store.index_entry(&block, offset, size);
+            deltas.push(DeltaRecord::new(entry.height, entry.hash, &delta));
+            store.index.push(entry);
+            position += 8 + size as usize;
+        }
+
+        let mut index_file = File::create(dir.join(INDEX_FILE))?;
+        for entry in &store.index {
+            writeln!(index_file, "{}", serde_json::to_string(entry)?)?;
+        }
+        index_file.sync_all()?;
+        let mut deltas_file = File::create(dir.join(DELTAS_FILE))?;
+        for record in &deltas {
+            writeln!(deltas_file, "{}", serde_json::to_string(record)?)?;
+        }
+        deltas_file.sync_all()?;
+        write_json_atomically(&dir.join(UTXOS_FILE), &UtxoSnapshot::new(&store.state))?;
+
+        Ok(store)
+    }
+
+    /// Connect `block` to the tip and store it together with its UTXO changes. The chain
+    /// state only keeps the block when it was stored.
+    pub fn append_block(&mut self, block: &mut Block) -> Result<()> {
+        let delta = self.state.connect_block(block)?;
+        let Some(dir) = self.dir.clone() else {
+            return Ok(());
+        };
+
+        let entry = match self.write_block(&dir, block, &delta) {
+            Ok(entry) => entry,
+            Err(e) => {
+                self.state.disconnect_tip(block, &delta);
+                return Err(e);
+            }
+        };
+        self.index.push(entry);
+
+        // The block is stored with its delta, the snapshot only shortens the replay on
+        // opening, so failing to write it does not fail the append
+        if self.state.height.is_multiple_of(SNAPSHOT_INTERVAL) {
+            let utxos_path = dir.join(UTXOS_FILE);
+            if let Err(e) = write_json_atomically(&utxos_path, &UtxoSnapshot::new(&self.state)) {
+                warn!(
+                    "Failed to write the UTXO snapshot {}: {e}",
+                    utxos_path.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the connected tip `block`, its delta and its index entry to the files in
+    /// `dir`. The block and delta files are cut back to their old size on failure.
+    fn write_block(&self, dir: &Path, block: &Block, delta: &UtxoDelta) -> Result<BlockIndexEntry> {
+        let blocks_path = dir.join(BLOCKS_FILE);
+        let deltas_path = dir.join(DELTAS_FILE);
+        let blocks_size = fs::metadata(&blocks_path)?.len();
+        let deltas_size = fs::metadata(&deltas_path)?.len();
+
+        // Index last, so a crash in between leaves unindexed bytes, which opening drops
+        let write = || -> Result<BlockIndexEntry> {
+            let raw_block = Block::serialize(block)?;
+            let mut blocks_file = OpenOptions::new().append(true).open(&blocks_path)?;
+            blocks_file.write_all(&self.state.params.network.magic())?;
+            blocks_file.write_all(&(raw_block.len() as u32).to_le_bytes())?;
+            blocks_file.write_all(&raw_block)?;
+            blocks_file.sync_data()?;
+
+            let entry = self.index_entry(block, blocks_size + 8, raw_block.len() as u32);
+            let record = DeltaRecord::new(entry.height, entry.hash, delta);
+            let mut deltas_file = OpenOptions::new().append(true).open(&deltas_path)?;
+            writeln!(deltas_file, "{}", serde_json::to_string(&record)?)?;
+            deltas_file.sync_data()?;
+
+            let mut index_file = OpenOptions::new().append(true).open(dir.join(INDEX_FILE))?;
+            writeln!(index_file, "{}", serde_json::to_string(&entry)?)?;
+            index_file.sync_data()?;
+            Ok(entry)
+        };
+
+        write().inspect_err(|_| {
+            // Best effort, opening drops whatever is left beyond the index
+            for (path, size) in [(&blocks_path, blocks_size), (&deltas_path, deltas_size)] {
+                if let Ok(file) = OpenOptions::new().write(true).open(path) {
+                    let _ = file.set_len(size);
+                }
+            }
+        })
+    }
+
+    /// Read the stored block at `height`
+    pub fn read_block(&self, height: u32) -> Result<Block> {
+        let dir = self
+            .dir
+            .as_ref()
+            .ok_or_else(|| anyhow!("The chain is not stored"))?;
+        let entry = self
+            .index
+            .iter()
+            .find(|entry| entry.height == height)
+            .ok_or_else(|| anyhow!("No stored block at height {height}"))?;
+
+        let mut blocks_file = File::open(dir.join(BLOCKS_FILE))?;
+        blocks_file.seek(SeekFrom::Start(entry.offset))?;
+        let mut raw_block = vec![0u8; entry.size as usize];
+        blocks_file.read_exact(&mut raw_block)?;
+        Block::deserialize(&raw_block)
+    }
+
+    pub fn state(&self) -> &ChainState {
+        &self.state
+    }
+
+    pub fn index(&self) -> &[BlockIndexEntry] {
+        &self.index
+    }
+
+    /// Entry for `block`, which was just connected as the tip
+    fn index_entry(&self, block: &Block, offset: u64, size: u32) -> BlockIndexEntry {
+        BlockIndexEntry {
+            height: self.state.height - 1,
+            hash: self.state.tip,
+            previous_block_hash: block.header.previous_block_hash,
+            time: block.header.time,
+            bits: block.header.bits,
+            offset,
+            size,
+        }
+    }
+}
+
+/// Cut `path` back to `indexed_size` bytes, what the block index up to `indexed_tip` covers
+fn truncate_unindexed(path: &Path, indexed_size: u64, indexed_tip: BlockHash) -> Result<()> {
+    let size = fs::metadata(path)?.len();
+    if indexed_size > size {
+        return Err(anyhow!(
+            "Block {indexed_tip} is indexed beyond the end of {}, run reindex",
+            path.display()
+        ));
+    }
+    if indexed_size < size {
+        warn!(
+            "Dropping {} unindexed bytes at the end of {}",
+            size - indexed_size,
+            path.display()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(indexed_size)?;
+    }
+    Ok(())
+}
+
+/// Entries of a JSON lines file, each with the offset after its line. A last line cut off
+/// by a crash has no newline yet and is left out.
+fn read_json_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<(T, u64)>> {
+    let file = File::open(path).map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut entries = Vec::new();
+    let mut end = 0;
+    let mut line = String::new();
+    for line_number in 1.. {
+        line.clear();
+        let length = reader
+            .read_line(&mut line)
+            .map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+        if length == 0 || !line.ends_with('\n') {
+            break;
+        }
+
+        end += length as u64;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|e| {
+            anyhow!(
+                "Invalid entry on line {line_number} of {}: {e}",
+                path.display()
+            )
+        })?;
+        entries.push((entry, end));
+    }
+    Ok(entries)
+}
+
+/// Size of the block following the magic at the start of `record`
+fn read_size(record: &[u8]) -> u32 {
+    u32::from_le_bytes([record[4], record[5], record[6], record[7]])
+}
+
+fn read_snapshot(path: &Path) -> Result<UtxoSnapshot> {
+    let contents = fs::read(path).map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+/// Write to a temporary file and rename it, so `path` always holds a complete file
+fn write_json_atomically(path: &Path, value: &impl Serialize) -> Result<()> {
+    let temporary_path = path.with_extension("tmp");
+    let mut file = File::create(&temporary_path)?;
+    serde_json::to_writer(&mut file, value)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::block::{create_block, create_coinbase_transaction};
+    use crate::block_validation::block_subsidy;
+    use crate::mine::{expand_target, mine_with_threads};
+
+    /// Coinbase-only block on top of the tip of `state`, ten minutes after the previous one
+    fn next_block(state: &ChainState) -> Result<Block> {
+        let height = state.height;
+        let time = 1_700_000_000 + height * 600;
+        let coinbase = create_coinbase_transaction(
+            height,
+            &[(vec![0x51], block_subsidy(height))],
+            Network::Regtest,
+        )?;
+        let target = expand_target(state.next_work_required(time));
+        mine_with_threads(create_block(vec![coinbase], state.tip, time, target)?, 1)
+    }
+
+    fn stored_chain(dir: &Path, blocks: u32) -> Result<ChainStore> {
+        let base = ChainState::new(Network::Regtest, BlockHash::ZERO, 0, UtxoSet::default());
+        let mut store = ChainStore::create(dir, base)?;
+        for _ in 0..blocks {
+            let mut block = next_block(store.state())?;
+            store.append_block(&mut block)?;
+        }
+        Ok(store)
+    }
+
+    fn utxos(store: &ChainStore) -> BTreeMap<OutPoint, Utxo> {
+        store
+            .state()
+            .utxos
+            .iter()
+            .map(|(outpoint, utxo)| (*outpoint, utxo.clone()))
+            .collect()
+    }
+
+    fn append_bytes(path: &Path, bytes: &[u8]) -> Result<()> {
+        OpenOptions::new()
+            .append(true)
+            .open(path)?
+            .write_all(bytes)?;
+        Ok(())
+    }
+
+    #[test]
+    fn reopened_store_resumes_at_its_tip() -> Result<()> {
+        let dir = tempdir()?;
+        let stored = stored_chain(dir.path(), 3)?;
+
+        let mut reopened = ChainStore::open(dir.path())?;
+        assert_eq!(reopened.state().tip, stored.state().tip);
+        assert_eq!(reopened.state().height, 3);
+        assert_eq!(reopened.state().blocks.len(), 3);
+        assert_eq!(utxos(&reopened), utxos(&stored));
+        assert_eq!(
+            reopened.read_block(1)?.header.block_hash(),
+            stored.index()[1].hash
+        );
+
+        let mut block = next_block(reopened.state())?;
+        reopened.append_block(&mut block)?;
+        assert_eq!(ChainStore::open(dir.path())?.state().height, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_block_file_tail_is_dropped() -> Result<()> {
+        let dir = tempdir()?;
+        let stored = stored_chain(dir.path(), 2)?;
+        let blocks_path = dir.path().join(BLOCKS_FILE);
+        let stored_size = fs::metadata(&blocks_path)?.len();
+
+        // Magic and size of a 200 byte block of which only 10 bytes made it to disk
+        let mut cut_off = Network::Regtest.magic().to_vec();
+        cut_off.extend_from_slice(&200u32.to_le_bytes());
+        cut_off.extend_from_slice(&[0; 10]);
+
+        append_bytes(&blocks_path, &cut_off)?;
+        assert_eq!(
+            ChainStore::open(dir.path())?.state().tip,
+            stored.state().tip
+        );
+        assert_eq!(fs::metadata(&blocks_path)?.len(), stored_size);
+
+        append_bytes(&blocks_path, &cut_off)?;
+        assert_eq!(
+            ChainStore::reindex(dir.path())?.state().tip,
+            stored.state().tip
+        );
+        assert_eq!(fs::metadata(&blocks_path)?.len(), stored_size);
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_index_line_is_dropped() -> Result<()> {
+        let dir = tempdir()?;
+        let stored = stored_chain(dir.path(), 3)?;
+
+        // A crash while indexing the last block
+        let index_path = dir.path().join(INDEX_FILE);
+        let index_size = fs::metadata(&index_path)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&index_path)?
+            .set_len(index_size - 20)?;
+
+        let mut reopened = ChainStore::open(dir.path())?;
+        assert_eq!(reopened.state().tip, stored.index()[1].hash);
+        assert_eq!(reopened.state().height, 2);
+        assert_eq!(reopened.index().len(), 2);
+
+        let mut block = next_block(reopened.state())?;
+        reopened.append_block(&mut block)?;
+        let reindexed = ChainStore::reindex(dir.path())?;
+        assert_eq!(reindexed.state().tip, reopened.state().tip);
+        assert_eq!(reindexed.index().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_index_line_names_the_file() -> Result<()> {
+        let dir = tempdir()?;
+        stored_chain(dir.path(), 1)?;
+        append_bytes(&dir.path().join(INDEX_FILE), b"{\"height\":1}\n")?;
+
+        let error = ChainStore::open(dir.path()).unwrap_err().to_string();
+        assert!(error.contains(INDEX_FILE), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn reindex_matches_open() -> Result<()> {
+        let dir = tempdir()?;
+        stored_chain(dir.path(), 3)?;
+
+        let opened = ChainStore::open(dir.path())?;
+        let reindexed = ChainStore::reindex(dir.path())?;
+        assert_eq!(reindexed.state().tip, opened.state().tip);
+        assert_eq!(reindexed.state().height, opened.state().height);
+        assert_eq!(utxos(&reindexed), utxos(&opened));
+        assert_eq!(
+            serde_json::to_string(reindexed.index())?,
+            serde_json::to_string(opened.index())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn impossible_block_size_is_an_error() -> Result<()> {
+        let dir = tempdir()?;
+        stored_chain(dir.path(), 2)?;
+        let blocks_path = dir.path().join(BLOCKS_FILE);
+        let mut bytes = fs::read(&blocks_path)?;
+        bytes[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&blocks_path, &bytes)?;
+
+        assert!(ChainStore::reindex(dir.path()).is_err());
+        assert_eq!(fs::read(&blocks_path)?, bytes);
+        Ok(())
+    }
+}
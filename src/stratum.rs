@@ -0,0 +1,496 @@
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use primitive_types::U256;
+use serde_json::{json, Value};
+
+use crate::block::{create_header, Block, Header, EXTRANONCE_SIZE};
+use crate::difficulty::{difficulty_from_target, target_from_difficulty};
+use crate::encoding::compact_size_len;
+use crate::hash::MerkleNode;
+use crate::merkle::{merkle_branch, merkle_root_from_branch};
+use crate::mine::{derive_target, max_allowed_block_time};
+
+/// The extranonce is split into a part fixed per connection and a part rolled by the miner
+const EXTRANONCE1_SIZE: usize = 4;
+const EXTRANONCE2_SIZE: usize = EXTRANONCE_SIZE - EXTRANONCE1_SIZE;
+
+/// How often idle connections and the accept loop check whether the block was found
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Share difficulty relative to the block difficulty when none is given
+const DEFAULT_SHARES_PER_BLOCK: f64 = 16.0;
+
+/// Error of a stratum request as `[code, message, null]`
+#[derive(Debug, PartialEq)]
+struct StratumError(i64, &'static str);
+
+/// JSON-RPC's error for a request which is not JSON
+const PARSE_ERROR: StratumError = StratumError(-32700, "Parse error");
+const UNKNOWN_METHOD: StratumError = StratumError(20, "Unknown method");
+const INVALID_PARAMS: StratumError = StratumError(20, "Invalid parameters");
+const JOB_NOT_FOUND: StratumError = StratumError(21, "Job not found");
+const DUPLICATE_SHARE: StratumError = StratumError(22, "Duplicate share");
+const LOW_DIFFICULTY_SHARE: StratumError = StratumError(23, "Low difficulty share");
+const UNAUTHORIZED: StratumError = StratumError(24, "Unauthorized worker");
+const NOT_SUBSCRIBED: StratumError = StratumError(25, "Not subscribed");
+
+/// The block handed out to every miner, with the coinbase split around the extranonce
+struct Job {
+    id: String,
+    block: Block,
+    /// Non-witness coinbase serialization up to the extranonce
+    coinbase1: Vec<u8>,
+    /// Non-witness coinbase serialization after the extranonce
+    coinbase2: Vec<u8>,
+    /// Merkle branch of the coinbase, internal byte order
+    merkle_branch: Vec<MerkleNode>,
+    block_target: U256,
+    share_target: U256,
+    share_difficulty: f64,
+    /// Extranonce, time and nonce of the accepted shares, every job starts without any
+    submitted_shares: Mutex<HashSet<(Vec<u8>, u32, u32)>>,
+}
+
+impl Job {
+    fn new(block: Block, share_difficulty: Option<f64>) -> Result<Job> {
+        let block_target = derive_target(block.header.bits)?;
+        let share_difficulty = share_difficulty
+            .unwrap_or_else(|| difficulty_from_target(block_target) / DEFAULT_SHARES_PER_BLOCK);
+        // Every block solution has to count as a share
+        let share_target = target_from_difficulty(share_difficulty).max(block_target);
+
+        let coinbase = block
+            .transactions
+            .first()
+            .ok_or_else(|| anyhow!("Block has no coinbase"))?;
+        let coinbase_bytes = coinbase.serialize(false)?;
+        // version, input count and the null outpoint come before the scriptsig
+        let scriptsig_len = coinbase.vin[0].scriptsig.len() / 2;
+        let extranonce_end = 4 + 1 + 36 + compact_size_len(scriptsig_len as u64) + scriptsig_len;
+        let extranonce_start = extranonce_end - EXTRANONCE_SIZE;
+
+        let txids = block
+            .transactions
+            .iter()
+            .map(|tx| Ok(tx.id()?.into()))
+            .collect::<Result<Vec<MerkleNode>>>()?;
+
+        Ok(Job {
+            id: format!("{:08x}", block.header.time),
+            coinbase1: coinbase_bytes[..extranonce_start].to_vec(),
+            coinbase2: coinbase_bytes[extranonce_end..].to_vec(),
+            merkle_branch: merkle_branch(&txids, 0)?,
+            block,
+            block_target,
+            share_target,
+            share_difficulty,
+            submitted_shares: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Parameters of `mining.notify`. Numbers are big endian hex, the merkle branch is in
+    /// internal byte order and so is the previous block hash, with every 4 byte word reversed.
+    fn notify_params(&self) -> Value {
+        let previous_block_hash = self
+            .block
+            .header
+            .previous_block_hash
+            .as_bytes()
+            .chunks(4)
+            .flat_map(|word| word.iter().rev().copied())
+            .collect::<Vec<u8>>();
+
+        json!([
+            self.id,
+            hex::encode(previous_block_hash),
+            hex::encode(&self.coinbase1),
+            hex::encode(&self.coinbase2),
+            self.merkle_branch
+                .iter()
+                .map(|hash| hex::encode(hash.as_bytes()))
+                .collect::<Vec<String>>(),
+            format!("{:08x}", self.block.header.version),
+            format!("{:08x}", self.block.header.bits),
+            format!("{:08x}", self.block.header.time),
+            true
+        ])
+    }
+
+    /// Header a miner hashed for the given extranonce, time and nonce
+    fn header(&self, extranonce: &[u8], time: u32, nonce: u32) -> Header {
+        let coinbase = [&self.coinbase1[..], extranonce, &self.coinbase2[..]].concat();
+        let merkle_root =
+            merkle_root_from_branch(MerkleNode::hash(&coinbase), 0, &self.merkle_branch);
+
+        let mut header = create_header(
+            self.block.header.previous_block_hash,
+            merkle_root,
+            time,
+            self.block.header.bits,
+        );
+        header.version = self.block.header.version;
+        header.nonce = nonce;
+        header
+    }
+
+    /// The full block for a winning share
+    fn solved_block(&self, extranonce: &[u8], time: u32, nonce: u32) -> Result<Block> {
+        let mut block = self.block.clone();
+        let extranonce: [u8; EXTRANONCE_SIZE] = extranonce
+            .try_into()
+            .map_err(|_| anyhow!("Extranonce must be {EXTRANONCE_SIZE} bytes"))?;
+        block.roll_extranonce(u64::from_le_bytes(extranonce))?;
+        block.header.time = time;
+        block.header.nonce = nonce;
+
+        if block.header.merkle_root != self.header(&extranonce, time, nonce).merkle_root {
+            return Err(anyhow!("Rebuilt coinbase does not match the share"));
+        }
+        Ok(block)
+    }
+}
+
+/// State shared by all connections
+struct Server {
+    job: Job,
+    found: AtomicBool,
+    solved_block: Mutex<Option<Block>>,
+    accepted_shares: AtomicU64,
+}
+
+impl Server {
+    fn new(job: Job) -> Server {
+        Server {
+            job,
+            found: AtomicBool::new(false),
+            solved_block: Mutex::new(None),
+            accepted_shares: AtomicU64::new(0),
+        }
+    }
+
+    /// Check a `mining.submit` of the connection with `extranonce1`
+    fn submit(&self, extranonce1: &[u8], params: &[Value]) -> Result<(), StratumError> {
+        let [_, job_id, extranonce2, time, nonce] = params else {
+            return Err(INVALID_PARAMS);
+        };
+        let (Some(job_id), Some(extranonce2), Some(time), Some(nonce)) = (
+            job_id.as_str(),
+            extranonce2.as_str().and_then(|hex| hex::decode(hex).ok()),
+            time.as_str()
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok()),
+            nonce
+                .as_str()
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok()),
+        ) else {
+            return Err(INVALID_PARAMS);
+        };
+
+        if job_id != self.job.id {
+            return Err(JOB_NOT_FOUND);
+        }
+        if extranonce2.len() != EXTRANONCE2_SIZE {
+            return Err(INVALID_PARAMS);
+        }
+        if time < self.job.block.header.time || time > max_allowed_block_time() {
+            return Err(INVALID_PARAMS);
+        }
+
+        let extranonce = [extranonce1, &extranonce2].concat();
+        let header = self.job.header(&extranonce, time, nonce);
+        let hash = header.block_hash();
+        let hash_value = hash.to_u256();
+        if hash_value >= self.job.share_target {
+            return Err(LOW_DIFFICULTY_SHARE);
+        }
+
+        // Only shares which count are remembered, a rejected one may be sent again
+        if !self
+            .job
+            .submitted_shares
+            .lock()
+            .unwrap()
+            .insert((extranonce.clone(), time, nonce))
+        {
+            return Err(DUPLICATE_SHARE);
+        }
+
+        self.accepted_shares.fetch_add(1, Ordering::Relaxed);
+        info!("Accepted share {hash}");
+
+        if hash_value < self.job.block_target {
+            match self.job.solved_block(&extranonce, time, nonce) {
+                Ok(block) => {
+                    info!("Share {hash} solves the block");
+                    self.solved_block.lock().unwrap().get_or_insert(block);
+                    self.found.store(true, Ordering::Relaxed);
+                }
+                Err(e) => warn!("Could not rebuild the block of share {hash}: {e}"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Serve `block` as a stratum v1 job on `address` until a miner submits a share solving it,
+/// and return the solved block. Shares are checked against `share_difficulty`, by default a
+/// 16th of the block difficulty.
+pub fn run_stratum_server(
+    address: &str,
+    block: Block,
+    share_difficulty: Option<f64>,
+) -> Result<Block> {
+    serve_stratum_job(TcpListener::bind(address)?, block, share_difficulty)
+}
+
+/// `run_stratum_server` on an already bound `listener`
+pub fn serve_stratum_job(
+    listener: TcpListener,
+    block: Block,
+    share_difficulty: Option<f64>,
+) -> Result<Block> {
+    let job = Job::new(block, share_difficulty)?;
+    listener.set_nonblocking(true)?;
+    info!(
+        "Stratum server listening on {}, share difficulty {}",
+        listener.local_addr()?,
+        job.share_difficulty
+    );
+
+    let server = Server::new(job);
+
+    thread::scope(|scope| -> Result<()> {
+        let mut next_extranonce1 = 0u32;
+        while !server.found.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    let extranonce1 = next_extranonce1.to_be_bytes();
+                    next_extranonce1 = next_extranonce1.wrapping_add(1);
+                    info!("Miner connected from {peer}");
+
+                    let server = &server;
+                    scope.spawn(move || {
+                        if let Err(e) = handle_connection(stream, server, &extranonce1) {
+                            warn!("Connection to {peer} failed: {e}");
+                        }
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    })?;
+
+    info!(
+        "Accepted {} shares",
+        server.accepted_shares.load(Ordering::Relaxed)
+    );
+    server
+        .solved_block
+        .into_inner()
+        .unwrap()
+        .ok_or_else(|| anyhow!("Stratum server stopped without a solved block"))
+}
+
+/// Answer the requests of one miner until it disconnects or the block is found
+fn handle_connection(stream: TcpStream, server: &Server, extranonce1: &[u8]) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let mut subscribed = false;
+    let mut authorized = false;
+    let mut line = String::new();
+    while !server.found.load(Ordering::Relaxed) {
+        // A timed out read keeps the partial line, the next read continues it
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e.into()),
+        }
+
+        // A line which is not JSON is answered with an error, the miner may go on
+        let request: Value = match serde_json::from_str(line.trim()) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Unparsable request {:?}: {e}", line.trim());
+                line.clear();
+                send(&mut writer, &response(&Value::Null, Err(PARSE_ERROR)))?;
+                continue;
+            }
+        };
+        line.clear();
+        let method = request["method"].as_str().unwrap_or_default();
+        let params = request["params"].as_array().cloned().unwrap_or_default();
+
+        let result = match method {
+            "mining.subscribe" => {
+                subscribed = true;
+                Ok(json!([
+                    [["mining.notify", server.job.id]],
+                    hex::encode(extranonce1),
+                    EXTRANONCE2_SIZE
+                ]))
+            }
+            "mining.authorize" => {
+                authorized = true;
+                Ok(json!(true))
+            }
+            "mining.submit" if !subscribed => Err(NOT_SUBSCRIBED),
+            "mining.submit" if !authorized => Err(UNAUTHORIZED),
+            "mining.submit" => server.submit(extranonce1, &params).map(|()| json!(true)),
+            _ => Err(UNKNOWN_METHOD),
+        };
+
+        send(&mut writer, &response(&request["id"], result))?;
+
+        if method == "mining.subscribe" {
+            let set_difficulty = json!({
+                "id": null,
+                "method": "mining.set_difficulty",
+                "params": [server.job.share_difficulty]
+            });
+            send(&mut writer, &set_difficulty)?;
+            let notify = json!({
+                "id": null,
+                "method": "mining.notify",
+                "params": server.job.notify_params()
+            });
+            send(&mut writer, &notify)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reply to the request with `id`
+fn response(id: &Value, result: Result<Value, StratumError>) -> Value {
+    match result {
+        Ok(result) => json!({"id": id, "result": result, "error": null}),
+        Err(StratumError(code, message)) => {
+            json!({"id": id, "result": null, "error": [code, message, null]})
+        }
+    }
+}
+
+/// Write one newline terminated JSON message
+pub fn send(writer: &mut impl Write, message: &Value) -> Result<()> {
+    writeln!(writer, "{message}")?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+
+    use super::*;
+    use crate::block::{create_block, create_coinbase_transaction};
+    use crate::hash::BlockHash;
+    use crate::mine::expand_target;
+    use crate::network::Network;
+
+    const BLOCK_TIME: u32 = 1_700_000_000;
+
+    /// Server for a difficulty 1 block, which no test share solves
+    fn server(share_difficulty: f64) -> Result<Server> {
+        let coinbase = create_coinbase_transaction(1, &[(vec![0x51], 1)], Network::Regtest)?;
+        let block = create_block(
+            vec![coinbase],
+            BlockHash::ZERO,
+            BLOCK_TIME,
+            expand_target(0x1d00ffff),
+        )?;
+        Ok(Server::new(Job::new(block, Some(share_difficulty))?))
+    }
+
+    fn share(server: &Server, nonce: u32) -> Vec<Value> {
+        vec![
+            json!("worker"),
+            json!(server.job.id),
+            json!("00000000"),
+            json!(format!("{BLOCK_TIME:08x}")),
+            json!(format!("{nonce:08x}")),
+        ]
+    }
+
+    #[test]
+    fn rejected_shares_are_not_remembered() -> Result<()> {
+        let server = server(1e12)?;
+        let share = share(&server, 0);
+        assert_eq!(server.submit(&[0; 4], &share), Err(LOW_DIFFICULTY_SHARE));
+        assert_eq!(server.submit(&[0; 4], &share), Err(LOW_DIFFICULTY_SHARE));
+        assert!(server.job.submitted_shares.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn accepted_shares_are_only_accepted_once() -> Result<()> {
+        // Every hash meets the share target
+        let server = server(1e-12)?;
+        let share = share(&server, 0);
+        assert_eq!(server.submit(&[0; 4], &share), Ok(()));
+        assert_eq!(server.submit(&[0; 4], &share), Err(DUPLICATE_SHARE));
+        // The same nonce with another extranonce is a different share
+        assert_eq!(server.submit(&[1; 4], &share), Ok(()));
+        assert_eq!(server.accepted_shares.load(Ordering::Relaxed), 2);
+
+        // A new job forgets the shares of the previous one
+        let server = Server::new(Job::new(server.job.block.clone(), Some(1e-12))?);
+        assert_eq!(server.submit(&[0; 4], &share), Ok(()));
+        Ok(())
+    }
+
+    /// Send `requests` to a connection handler and read one reply per line
+    fn exchange(server: &Server, requests: &[u8], lines: usize) -> Result<Vec<Value>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, _) = listener.accept()?;
+
+        thread::scope(|scope| {
+            let handler = scope.spawn(|| handle_connection(stream, server, &[0; 4]));
+            let replies = client
+                .write_all(requests)
+                .map_err(Into::into)
+                .and_then(|()| {
+                    BufReader::new(client.try_clone()?)
+                        .lines()
+                        .take(lines)
+                        .map(|line| Ok(serde_json::from_str(&line?)?))
+                        .collect()
+                });
+
+            // The handler keeps the connection open until the miner closes it
+            client.shutdown(Shutdown::Both)?;
+            handler.join().unwrap()?;
+            replies
+        })
+    }
+
+    #[test]
+    fn malformed_lines_get_a_parse_error() -> Result<()> {
+        let server = server(1.0)?;
+        let requests =
+            b"\n{not json\n{\"id\": 1, \"method\": \"mining.authorize\", \"params\": []}\n";
+        let replies = exchange(&server, requests, 3)?;
+
+        for reply in &replies[..2] {
+            assert_eq!(reply["id"], Value::Null);
+            assert_eq!(reply["error"], json!([-32700, "Parse error", null]));
+        }
+        assert_eq!(replies[2]["id"], json!(1));
+        assert_eq!(replies[2]["result"], json!(true));
+        Ok(())
+    }
+}
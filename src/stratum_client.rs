@@ -0,0 +1,227 @@
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::net::TcpStream;
+
+use anyhow::{anyhow, Result};
+use primitive_types::U256;
+use serde_json::{json, Value};
+
+use code_challenge_2024_mirebella_v2::difficulty::target_from_difficulty;
+use code_challenge_2024_mirebella_v2::hash::MerkleNode;
+use code_challenge_2024_mirebella_v2::merkle::merkle_root_from_branch;
+use code_challenge_2024_mirebella_v2::stratum::send;
+use code_challenge_2024_mirebella_v2::HeaderHasher;
+
+/// The extranonce2 is rolled as a little endian u64
+const MAX_EXTRANONCE2_SIZE: usize = 8;
+
+/// What the server told us so far
+#[derive(Default)]
+struct MinerState {
+    share_target: Option<U256>,
+    job: Option<Vec<Value>>,
+}
+
+struct Connection {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+    next_id: u64,
+    state: MinerState,
+}
+
+impl Connection {
+    /// Send a request and wait for its response, handling notifications on the way.
+    /// `None` once the server closed the connection.
+    fn request(&mut self, method: &str, params: Value) -> Result<Option<Value>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({"id": id, "method": method, "params": params});
+        if send(&mut self.writer, &request).is_err() {
+            return Ok(None);
+        }
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return Ok(None),
+                Ok(_) => {}
+                // The server drops the connection once the block is solved
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            let message: Value = serde_json::from_str(line.trim())?;
+
+            match message["method"].as_str() {
+                Some("mining.set_difficulty") => {
+                    let difficulty = message["params"][0]
+                        .as_f64()
+                        .ok_or_else(|| anyhow!("Invalid difficulty: {message}"))?;
+                    self.state.share_target = Some(target_from_difficulty(difficulty));
+                }
+                Some("mining.notify") => {
+                    self.state.job = message["params"].as_array().cloned();
+                }
+                Some(_) => {}
+                None if message["id"] == json!(id) => return Ok(Some(message)),
+                None => {}
+            }
+        }
+    }
+}
+
+/// Minimal stratum v1 miner for trying the server locally: subscribes, authorizes and
+/// hashes the job on a single thread, submitting every share it finds until the server
+/// closes the connection or `max_shares` shares were accepted. Returns the number of
+/// accepted shares.
+pub(crate) fn run_mock_client(address: &str, worker: &str, max_shares: Option<u64>) -> Result<u64> {
+    let stream = TcpStream::connect(address)?;
+    let mut connection = Connection {
+        writer: stream.try_clone()?,
+        reader: BufReader::new(stream),
+        next_id: 1,
+        state: MinerState::default(),
+    };
+
+    let subscription = connection
+        .request("mining.subscribe", json!(["mock-miner"]))?
+        .ok_or_else(|| anyhow!("Server closed the connection"))?;
+    let extranonce1 = hex::decode(
+        subscription["result"][1]
+            .as_str()
+            .ok_or_else(|| anyhow!("Invalid subscription: {subscription}"))?,
+    )?;
+    let extranonce2_size = subscription["result"][2]
+        .as_u64()
+        .ok_or_else(|| anyhow!("Invalid subscription: {subscription}"))?
+        as usize;
+    if extranonce2_size > MAX_EXTRANONCE2_SIZE {
+        return Err(anyhow!(
+            "Extranonce2 size {extranonce2_size} exceeds {MAX_EXTRANONCE2_SIZE} bytes"
+        ));
+    }
+    connection.request("mining.authorize", json!([worker, "x"]))?;
+
+    let (Some(share_target), Some(job)) =
+        (connection.state.share_target, connection.state.job.clone())
+    else {
+        return Err(anyhow!("Server sent no difficulty or job"));
+    };
+    let [job_id, previous_block_hash, coinbase1, coinbase2, merkle_branch, version, bits, time, ..] =
+        job.as_slice()
+    else {
+        return Err(anyhow!("Invalid job: {job:?}"));
+    };
+    let field = |value: &Value| -> Result<Vec<u8>> {
+        Ok(hex::decode(
+            value
+                .as_str()
+                .ok_or_else(|| anyhow!("Invalid job field {value}"))?,
+        )?)
+    };
+    let be_u32 = |value: &Value| -> Result<u32> {
+        let bytes: [u8; 4] = field(value)?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid job field {value}"))?;
+        Ok(u32::from_be_bytes(bytes))
+    };
+
+    // The job sends the previous block hash with every 4 byte word reversed
+    let previous_block_hash = field(previous_block_hash)?;
+    if previous_block_hash.len() != 32 {
+        return Err(anyhow!(
+            "Previous block hash must be 32 bytes, got {}",
+            previous_block_hash.len()
+        ));
+    }
+    let previous_block_hash: Vec<u8> = previous_block_hash
+        .chunks(4)
+        .flat_map(|word| word.iter().rev().copied())
+        .collect();
+    let coinbase1 = field(coinbase1)?;
+    let coinbase2 = field(coinbase2)?;
+    let merkle_branch = merkle_branch
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid merkle branch"))?
+        .iter()
+        .map(|hash| MerkleNode::from_slice(&field(hash)?))
+        .collect::<Result<Vec<MerkleNode>>>()?;
+    let (version, bits, time) = (be_u32(version)?, be_u32(bits)?, be_u32(time)?);
+
+    println!("Mining job {job_id} with share target {share_target:x}");
+    let mut accepted = 0u64;
+    for extranonce2 in 0..1u64 << (8 * extranonce2_size.min(7)) {
+        let extranonce2 = &extranonce2.to_le_bytes()[..extranonce2_size];
+        let coinbase = [&coinbase1[..], &extranonce1, extranonce2, &coinbase2].concat();
+        let merkle_root = merkle_root_from_branch(MerkleNode::hash(&coinbase), 0, &merkle_branch);
+
+        let mut header = [0u8; 80];
+        header[0..4].copy_from_slice(&version.to_le_bytes());
+        header[4..36].copy_from_slice(&previous_block_hash);
+        header[36..68].copy_from_slice(merkle_root.as_bytes());
+        header[68..72].copy_from_slice(&time.to_le_bytes());
+        header[72..76].copy_from_slice(&bits.to_le_bytes());
+        let mut hasher = HeaderHasher::new(&header);
+
+        for nonce in 0..=u32::MAX {
+            if U256::from_little_endian(&hasher.hash(nonce)) >= share_target {
+                continue;
+            }
+
+            let params = json!([
+                worker,
+                job_id,
+                hex::encode(extranonce2),
+                format!("{time:08x}"),
+                format!("{nonce:08x}")
+            ]);
+            let Some(response) = connection.request("mining.submit", params)? else {
+                return Ok(accepted);
+            };
+
+            match response["result"].as_bool() {
+                Some(true) => {
+                    accepted += 1;
+                    println!("Share accepted (nonce {nonce:08x})");
+                }
+                _ => println!("Share rejected: {}", response["error"]),
+            }
+            if max_shares.is_some_and(|max_shares| accepted >= max_shares) {
+                return Ok(accepted);
+            }
+        }
+    }
+
+    Err(anyhow!("Extranonce space exhausted"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use code_challenge_2024_mirebella_v2::block::{create_block, create_coinbase_transaction};
+    use code_challenge_2024_mirebella_v2::block_validation::check_proof_of_work;
+    use code_challenge_2024_mirebella_v2::hash::BlockHash;
+    use code_challenge_2024_mirebella_v2::network::Network;
+    use code_challenge_2024_mirebella_v2::stratum::serve_stratum_job;
+
+    use super::*;
+
+    #[test]
+    fn loopback_shares_are_accepted() -> Result<()> {
+        let coinbase = create_coinbase_transaction(1, &[(vec![0x51], 50)], Network::Regtest)?;
+        let target = U256::from(0x7fffffu32) << 232;
+        let block = create_block(vec![coinbase], BlockHash::ZERO, 1_700_000_000, target)?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?.to_string();
+        let server = thread::spawn(move || serve_stratum_job(listener, block, None));
+
+        // At the regtest target about every other share solves the block and ends the job
+        let accepted = run_mock_client(&address, "worker", None)?;
+        let solved_block = server.join().unwrap()?;
+
+        assert!(accepted >= 1);
+        check_proof_of_work(&solved_block.header)
+    }
+}
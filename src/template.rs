@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::block::{
+    calculate_witness_commitment, Block, WITNESS_COMMITMENT_HEADER, WITNESS_RESERVED_VALUE,
+};
+use crate::block_validation::{
+    block_subsidy, sum_values, total_fees, MAX_BLOCK_SIGOPS_COST, MAX_BLOCK_WEIGHT,
+};
+use crate::hash::{BlockHash, Txid, Wtxid};
+use crate::mine::expand_target;
+
+/// A block template in the shape of Bitcoin Core's `getblocktemplate` (BIP22, BIP23 and the
+/// segwit additions of BIP145), for miners which build their own coinbase
+#[derive(Debug, Serialize)]
+pub struct BlockTemplate {
+    pub version: u32,
+    pub rules: Vec<&'static str>,
+    pub previousblockhash: BlockHash,
+    pub transactions: Vec<TemplateTransaction>,
+    /// Subsidy plus fees, what the coinbase may pay out
+    pub coinbasevalue: u64,
+    pub target: String,
+    pub mintime: u32,
+    pub mutable: Vec<&'static str>,
+    pub noncerange: &'static str,
+    pub sigoplimit: usize,
+    pub sizelimit: usize,
+    pub weightlimit: usize,
+    pub curtime: u32,
+    pub bits: String,
+    pub height: u32,
+    /// Output script of the witness commitment, always given like Bitcoin Core does
+    pub default_witness_commitment: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplateTransaction {
+    /// Witness serialization
+    pub data: String,
+    pub txid: Txid,
+    /// Wtxid
+    pub hash: Wtxid,
+    /// 1-based indexes of the template transactions this one spends
+    pub depends: Vec<usize>,
+    pub fee: u64,
+    pub sigops: usize,
+    pub weight: usize,
+}
+
+/// Template of a block built by `create_block`, leaving the coinbase out.
+/// `median_time_past` gives the lower bound of the block time.
+pub fn create_block_template(
+    block: &Block,
+    height: u32,
+    median_time_past: Option<u32>,
+) -> Result<BlockTemplate> {
+    let transactions = &block.transactions[1..];
+
+    let index_by_txid: HashMap<Txid, usize> = transactions
+        .iter()
+        .enumerate()
+        .map(|(index, tx)| Ok((tx.id()?, index + 1)))
+        .collect::<Result<_>>()?;
+
+    let template_transactions = transactions
+        .iter()
+        .map(|tx| {
+            let mut depends: Vec<usize> = tx
+                .vin
+                .iter()
+                .filter_map(|input| index_by_txid.get(&input.txid).copied())
+                .collect();
+            depends.sort_unstable();
+            depends.dedup();
+
+            Ok(TemplateTransaction {
+                data: hex::encode(tx.serialize(true)?),
+                txid: tx.id()?,
+                hash: tx.wtxid()?,
+                depends,
+                fee: tx.fee()?,
+                sigops: tx.sigop_cost()?,
+                weight: tx.weight()?,
+            })
+        })
+        .collect::<Result<Vec<TemplateTransaction>>>()?;
+
+    // The coinbase wtxid does not enter the commitment, so it holds for any coinbase
+    let commitment = calculate_witness_commitment(&block.transactions, &WITNESS_RESERVED_VALUE)?;
+    let default_witness_commitment =
+        format!("{WITNESS_COMMITMENT_HEADER}{}", hex::encode(commitment));
+
+    // The configured payout may differ from what the coinbase is allowed to claim
+    let fees = total_fees(transactions)?;
+
+    let mut target = [0u8; 32];
+    expand_target(block.header.bits).to_big_endian(&mut target);
+
+    Ok(BlockTemplate {
+        version: block.header.version,
+        rules: vec!["segwit"],
+        previousblockhash: block.header.previous_block_hash,
+        transactions: template_transactions,
+        coinbasevalue: sum_values([block_subsidy(height), fees])?,
+        target: hex::encode(target),
+        mintime: median_time_past.map_or(0, |median_time_past| median_time_past.saturating_add(1)),
+        mutable: vec!["time", "transactions", "prevblock"],
+        noncerange: "00000000ffffffff",
+        sigoplimit: MAX_BLOCK_SIGOPS_COST,
+        sizelimit: MAX_BLOCK_WEIGHT,
+        weightlimit: MAX_BLOCK_WEIGHT,
+        curtime: block.header.time,
+        bits: format!("{:08x}", block.header.bits),
+        height,
+        default_witness_commitment,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{create_block, create_coinbase_transaction};
+    use crate::network::Network;
+    use crate::raw_tx::describe_output;
+    use crate::validation::{Input, PrevOut, Transaction};
+
+    /// Transaction spending `value` from the output `txid`:0 and paying out `value - fee`
+    fn spend(txid: Txid, value: u64, fee: u64, witness: bool) -> Result<Transaction> {
+        Ok(Transaction {
+            version: 2,
+            locktime: 0,
+            vin: vec![Input {
+                txid,
+                vout: 0,
+                prevout: PrevOut {
+                    scriptpubkey: "51".to_string(),
+                    value,
+                    ..PrevOut::default()
+                },
+                scriptsig: String::new(),
+                scriptsig_asm: String::new(),
+                witness: match witness {
+                    true => vec!["01".to_string()],
+                    false => vec![],
+                },
+                is_coinbase: false,
+                sequence: 0xFFFFFFFF,
+            }],
+            vout: vec![describe_output("51", value - fee, Network::Regtest)?],
+        })
+    }
+
+    fn block(transactions: Vec<Transaction>) -> Result<Block> {
+        let coinbase = create_coinbase_transaction(1, &[(vec![0x51], 1)], Network::Regtest)?;
+        create_block(
+            [vec![coinbase], transactions].concat(),
+            BlockHash::ZERO,
+            1_700_000_000,
+            expand_target(0x207fffff),
+        )
+    }
+
+    #[test]
+    fn witness_commitment_is_always_given() -> Result<()> {
+        // Without witness data the coinbase has no commitment, the template still gives one
+        let legacy = block(vec![spend(Txid::hash(b"legacy"), 10_000, 500, false)?])?;
+        let template = create_block_template(&legacy, 1, None)?;
+        assert!(template
+            .default_witness_commitment
+            .starts_with(WITNESS_COMMITMENT_HEADER));
+        assert_eq!(template.default_witness_commitment.len(), 38 * 2);
+        assert_eq!(template.coinbasevalue, block_subsidy(1) + 500);
+
+        // With witness data it is the commitment of the coinbase
+        let segwit = block(vec![spend(Txid::hash(b"segwit"), 10_000, 500, true)?])?;
+        let template = create_block_template(&segwit, 1, None)?;
+        let coinbase_commitment = &segwit.transactions[0].vout.last().unwrap().scriptpubkey;
+        assert_eq!(&template.default_witness_commitment, coinbase_commitment);
+        Ok(())
+    }
+
+    #[test]
+    fn fee_overflow_is_an_error() -> Result<()> {
+        let transactions = vec![
+            spend(Txid::hash(b"first"), u64::MAX, u64::MAX - 1, false)?,
+            spend(Txid::hash(b"second"), u64::MAX, u64::MAX - 1, false)?,
+        ];
+        assert!(create_block_template(&block(transactions)?, 1, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn mintime_saturates() -> Result<()> {
+        let block = block(vec![])?;
+        assert_eq!(create_block_template(&block, 1, None)?.mintime, 0);
+        assert_eq!(create_block_template(&block, 1, Some(100))?.mintime, 101);
+        assert_eq!(
+            create_block_template(&block, 1, Some(u32::MAX))?.mintime,
+            u32::MAX
+        );
+        Ok(())
+    }
+}
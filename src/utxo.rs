@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::hash::{OutPoint, Txid};
+use crate::script::OP_RETURN;
+use crate::validation::{PrevOut, Transaction};
+
+/// Blocks a coinbase output has to wait before it can be spent
+pub const COINBASE_MATURITY: u32 = 100;
+
+/// Scripts above this size in bytes fail to execute, so their outputs are unspendable
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// An unspent output and where it was created. Only what spending it needs is kept,
+/// the rest of the prevout fields can be derived from the script.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Utxo {
+    pub value: u64,
+    pub scriptpubkey: String,
+    pub height: u32,
+    pub is_coinbase: bool,
+}
+
+impl Utxo {
+    /// The output as the prevout of an input spending it
+    pub fn prevout(&self) -> PrevOut {
+        PrevOut {
+            scriptpubkey: self.scriptpubkey.clone(),
+            value: self.value,
+            ..PrevOut::default()
+        }
+    }
+}
+
+/// Net change of the UTXO set by one block: the outputs it spent which existed before it,
+/// and the outputs it created which it did not spend itself
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UtxoDelta {
+    pub spent: BTreeMap<OutPoint, Utxo>,
+    pub created: BTreeMap<OutPoint, Utxo>,
+}
+
+/// Unspent transaction outputs by outpoint
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    utxos: HashMap<OutPoint, Utxo>,
+}
+
+impl UtxoSet {
+    /// Add the outputs spent by `transactions` which none of them creates, i.e. the coins
+    /// the mempool assumes to be confirmed before `height`
+    pub fn add_mempool_prevouts(&mut self, transactions: &[Transaction], height: u32) {
+        let mempool_txids: HashSet<Txid> =
+            transactions.iter().filter_map(|tx| tx.id().ok()).collect();
+
+        for input in transactions.iter().flat_map(|tx| &tx.vin) {
+            if !input.is_coinbase && !mempool_txids.contains(&input.txid) {
+                self.utxos.insert(
+                    input.outpoint(),
+                    Utxo {
+                        value: input.prevout.value,
+                        scriptpubkey: input.prevout.scriptpubkey.clone(),
+                        height,
+                        is_coinbase: false,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Spend the inputs and add the spendable outputs of every transaction of the block at
+    /// `height`, returning what changed. The prevouts of the inputs are filled in from the
+    /// spent outputs, as raw blocks do not carry them. Nothing is changed when an input is
+    /// missing or spends an immature coinbase.
+    pub fn apply_block(&mut self, block: &mut Block, height: u32) -> Result<UtxoDelta> {
+        let mut delta = UtxoDelta::default();
+        for tx in &mut block.transactions {
+            if let Err(e) = self.apply_transaction(tx, height, &mut delta) {
+                self.undo(&delta);
+                return Err(e);
+            }
+        }
+
+        Ok(delta)
+    }
+
+    /// Redo a block applied before, e.g. when replaying stored deltas
+    pub fn apply_delta(&mut self, delta: &UtxoDelta) {
+        for outpoint in delta.spent.keys() {
+            self.utxos.remove(outpoint);
+        }
+        self.utxos.extend(delta.created.clone());
+    }
+
+    /// Revert the block `delta` was returned for, which has to be the last one applied
+    pub fn undo(&mut self, delta: &UtxoDelta) {
+        for outpoint in delta.created.keys() {
+            self.utxos.remove(outpoint);
+        }
+        self.utxos.extend(delta.spent.clone());
+    }
+
+    fn apply_transaction(
+        &mut self,
+        tx: &mut Transaction,
+        height: u32,
+        delta: &mut UtxoDelta,
+    ) -> Result<()> {
+        let txid = tx.id()?;
+        if !tx.is_coinbase() {
+            for input in &mut tx.vin {
+                let outpoint = input.outpoint();
+                let utxo = self.utxos.get(&outpoint).ok_or_else(|| {
+                    anyhow!("Transaction {txid} spends missing output {outpoint}")
+                })?;
+                if utxo.is_coinbase && height < utxo.height + COINBASE_MATURITY {
+                    return Err(anyhow!(
+                        "Transaction {txid} spends immature coinbase output {outpoint}"
+                    ));
+                }
+                input.prevout = utxo.prevout();
+
+                if let Some(utxo) = self.utxos.remove(&outpoint) {
+                    // An output created earlier in the same block leaves no trace
+                    if delta.created.remove(&outpoint).is_none() {
+                        delta.spent.insert(outpoint, utxo);
+                    }
+                }
+            }
+        }
+
+        for (vout, output) in tx.vout.iter().enumerate() {
+            if is_unspendable(&output.scriptpubkey) {
+                continue;
+            }
+
+            let outpoint = OutPoint::new(txid, vout as u32);
+            if self.utxos.contains_key(&outpoint) {
+                return Err(anyhow!(
+                    "Transaction {txid} overwrites unspent output {outpoint}"
+                ));
+            }
+            let utxo = Utxo {
+                value: output.value,
+                scriptpubkey: output.scriptpubkey.clone(),
+                height,
+                is_coinbase: tx.is_coinbase(),
+            };
+            self.utxos.insert(outpoint, utxo.clone());
+            delta.created.insert(outpoint, utxo);
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&OutPoint, &Utxo)> {
+        self.utxos.iter()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Utxo> {
+        self.utxos.get(outpoint)
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.utxos.contains_key(outpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    /// Sum of all unspent values in satoshis
+    pub fn total_value(&self) -> u64 {
+        self.utxos.values().map(|utxo| utxo.value).sum()
+    }
+}
+
+/// OP_RETURN outputs and outputs above the script size limit can never be spent, so they are
+/// left out of the UTXO set. `scriptpubkey` is hex encoded.
+fn is_unspendable(scriptpubkey: &str) -> bool {
+    scriptpubkey.len() > MAX_SCRIPT_SIZE * 2
+        || scriptpubkey
+            .get(..2)
+            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            == Some(OP_RETURN)
+}
+
+impl FromIterator<(OutPoint, Utxo)> for UtxoSet {
+    fn from_iter<I: IntoIterator<Item = (OutPoint, Utxo)>>(iter: I) -> UtxoSet {
+        UtxoSet {
+            utxos: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use primitive_types::U256;
+
+    use super::*;
+    use crate::block::{create_block, create_coinbase_transaction};
+    use crate::hash::BlockHash;
+    use crate::network::Network;
+
+    fn block_at(height: u32, mut transactions: Vec<Transaction>) -> Result<Block> {
+        let payout = (vec![0x51], 50);
+        let unspendable = [(vec![OP_RETURN, 0x00], 0), (vec![0x51; 10_001], 0)];
+        let outputs = [&[payout][..], &unspendable].concat();
+        transactions.insert(
+            0,
+            create_coinbase_transaction(height, &outputs, Network::Regtest)?,
+        );
+        create_block(transactions, BlockHash::ZERO, 1_700_000_000, U256::MAX >> 1)
+    }
+
+    #[test]
+    fn unspendable_outputs_are_not_added() -> Result<()> {
+        let mut utxos = UtxoSet::default();
+        let delta = utxos.apply_block(&mut block_at(1, Vec::new())?, 1)?;
+
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(delta.created.len(), 1);
+        assert!(delta.spent.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_block_leaves_the_set_unchanged() -> Result<()> {
+        let mut utxos = UtxoSet::default();
+        let mut first_block = block_at(1, Vec::new())?;
+        utxos.apply_block(&mut first_block, 1)?;
+        let before: HashMap<OutPoint, Utxo> = utxos.utxos.clone();
+
+        // Spends the first coinbase before it matured
+        let coinbase = &first_block.transactions[0];
+        let mut spend = coinbase.clone();
+        spend.vin[0].txid = coinbase.id()?;
+        spend.vin[0].vout = 0;
+        spend.vin[0].is_coinbase = false;
+
+        assert!(utxos
+            .apply_block(&mut block_at(2, vec![spend])?, 2)
+            .is_err());
+        assert_eq!(utxos.utxos, before);
+        Ok(())
+    }
+}
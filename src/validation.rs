@@ -1,157 +1,292 @@
 use std::collections::HashMap;
+use std::io::Read;
 
-use anyhow::Result;
-use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::warn;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::block::double_sha256;
-
-const TOTAL_MONEY_CAP: u64 = 21_000_000 * 100_000_000;
-const MAX_BLOCK_SIZE: usize = 1_000_000;
-
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct Transaction {
-    pub(crate) version: u32,
-    pub(crate) locktime: u32,
-    pub(crate) vin: Vec<Input>,
-    pub(crate) vout: Vec<Output>,
+use crate::block_validation::sum_values;
+use crate::consistency::find_mismatches;
+use crate::encoding::{
+    read_bytes, read_compact_size, read_var_bytes, write_compact_size, write_var_bytes,
+};
+use crate::hash::{OutPoint, Txid, Wtxid};
+use crate::input::MempoolEntry;
+use crate::network::Network;
+use crate::policy::{Policy, TOTAL_MONEY_CAP};
+use crate::script::{count_sigops, is_p2sh, last_push, witness_program};
+
+pub const WITNESS_SCALE_FACTOR: usize = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    pub version: u32,
+    pub locktime: u32,
+    pub vin: Vec<Input>,
+    pub vout: Vec<Output>,
 }
 impl Transaction {
-    pub(crate) fn id(&self) -> Result<String> {
-        let mut bytes = Vec::new();
-
+    pub fn id(&self) -> Result<Txid> {
         // TXID = HASH256([version][inputs][outputs][locktime])
+        Ok(Txid::hash(&self.serialize(false)?))
+    }
 
-        bytes.write_u32::<LittleEndian>(self.version)?;
-        // println!("Serialized === version: {}", hex::encode(bytes.clone()));
-        bytes.extend_from_slice(&self.get_pkh_inputs_bytes());
-        bytes.extend_from_slice(&self.get_pkh_outputs_bytes());
-        bytes.write_u32::<LittleEndian>(self.locktime)?;
-        // println!("Serialized locktime: {:?}", bytes);
+    /// Witness txid, equal to the txid for transactions without witness data
+    pub fn wtxid(&self) -> Result<Wtxid> {
+        Ok(Wtxid::hash(&self.serialize(true)?))
+    }
 
-        let mut double_hashed = double_sha256(&bytes);
-        double_hashed.reverse();
-        Ok(hex::encode(double_hashed))
+    pub fn has_witness(&self) -> bool {
+        self.vin.iter().any(|input| !input.witness.is_empty())
     }
 
-    pub fn get_pkh_inputs_bytes(&self) -> Vec<u8> {
+    /// Consensus serialization, with the segwit marker, flag and witnesses if `include_witness`
+    /// is set and any input carries witness data
+    pub fn serialize(&self, include_witness: bool) -> Result<Vec<u8>> {
+        let include_witness = include_witness && self.has_witness();
         let mut bytes = Vec::new();
 
-        bytes.write_u32::<BigEndian>(self.vin.len() as u32).unwrap();
-        // println!("Serialized input count: {:?}", bytes);
+        bytes.write_u32::<LittleEndian>(self.version)?;
+        if include_witness {
+            bytes.extend_from_slice(&[0x00, 0x01]);
+        }
 
+        write_compact_size(&mut bytes, self.vin.len() as u64);
         for input in &self.vin {
-            let txid_bytes = hex::decode(&input.txid).unwrap();
-            bytes.extend_from_slice(&txid_bytes);
-            // println!("Serialized txid: {}", hex::encode(&txid_bytes));
-
-            bytes.write_u32::<LittleEndian>(input.vout).unwrap();
-            // println!("Serialized vout: {}", hex::encode(input.vout.to_le_bytes()));
-
-            bytes
-                .write_u32::<LittleEndian>(input.scriptsig.len() as u32)
-                .unwrap();
-            bytes.extend_from_slice(input.scriptsig.as_bytes());
-            // println!(
-            //     "Serialized scriptsig size: {}",
-            //     hex::encode((input.scriptsig.len() as u64).to_le_bytes())
-            // );
-            // println!(
-            //     "Serialized scriptsig: {}",
-            //     hex::encode(input.scriptsig.as_bytes())
-            // );
-
-            bytes.write_u64::<LittleEndian>(input.sequence).unwrap();
-            // println!(
-            //     "Serialized sequence: {}",
-            //     hex::encode(input.sequence.to_le_bytes())
-            // );
+            bytes.extend_from_slice(input.txid.as_bytes());
+
+            bytes.write_u32::<LittleEndian>(input.vout)?;
+            write_var_bytes(&mut bytes, &hex::decode(&input.scriptsig)?);
+            bytes.write_u32::<LittleEndian>(u32::try_from(input.sequence)?)?;
+        }
+
+        write_compact_size(&mut bytes, self.vout.len() as u64);
+        for output in &self.vout {
+            bytes.write_u64::<LittleEndian>(output.value)?;
+            write_var_bytes(&mut bytes, &hex::decode(&output.scriptpubkey)?);
+        }
+
+        if include_witness {
+            for input in &self.vin {
+                write_compact_size(&mut bytes, input.witness.len() as u64);
+                for item in &input.witness {
+                    write_var_bytes(&mut bytes, &hex::decode(item)?);
+                }
+            }
+        }
+
+        bytes.write_u32::<LittleEndian>(self.locktime)?;
+
+        Ok(bytes)
+    }
+
+    /// Parse a consensus serialized transaction, legacy or segwit. Prevouts are not part of
+    /// the serialization and are left empty, as are the derived `_asm` fields.
+    pub fn deserialize(reader: &mut impl Read) -> Result<Transaction> {
+        let version = reader.read_u32::<LittleEndian>()?;
+
+        // An empty input list followed by flag 0x01 marks the segwit serialization
+        let mut input_count = read_compact_size(reader)?;
+        let has_witness = input_count == 0;
+        if has_witness {
+            let flag = reader.read_u8()?;
+            if flag != 0x01 {
+                return Err(anyhow!("Unknown segwit flag {flag:#04x}"));
+            }
+            input_count = read_compact_size(reader)?;
+        }
+
+        let mut vin = Vec::new();
+        for _ in 0..input_count {
+            let txid = Txid::from_slice(&read_bytes(reader, 32)?)?;
+            let vout = reader.read_u32::<LittleEndian>()?;
+            let scriptsig = read_var_bytes(reader)?;
+            let sequence = reader.read_u32::<LittleEndian>()?;
+
+            vin.push(Input {
+                is_coinbase: OutPoint::new(txid, vout).is_null(),
+                txid,
+                vout,
+                prevout: PrevOut::default(),
+                scriptsig: hex::encode(scriptsig),
+                scriptsig_asm: String::new(),
+                witness: vec![],
+                sequence: sequence as u64,
+            });
+        }
+
+        let output_count = read_compact_size(reader)?;
+        let mut vout = Vec::new();
+        for _ in 0..output_count {
+            let value = reader.read_u64::<LittleEndian>()?;
+            let scriptpubkey = read_var_bytes(reader)?;
+
+            vout.push(Output {
+                scriptpubkey: hex::encode(scriptpubkey),
+                scriptpubkey_asm: String::new(),
+                scriptpubkey_type: String::new(),
+                scriptpubkey_address: String::new(),
+                value,
+            });
+        }
+
+        if has_witness {
+            for input in &mut vin {
+                let item_count = read_compact_size(reader)?;
+                for _ in 0..item_count {
+                    input.witness.push(hex::encode(read_var_bytes(reader)?));
+                }
+            }
         }
 
-        bytes
+        let locktime = reader.read_u32::<LittleEndian>()?;
+
+        Ok(Transaction {
+            version,
+            locktime,
+            vin,
+            vout,
+        })
     }
 
-    pub(crate) fn get_pkh_outputs_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes
-            .write_u32::<BigEndian>(self.vout.len() as u32)
-            .unwrap();
+    /// Weight units: base size * 3 + total size (BIP141)
+    pub fn weight(&self) -> Result<usize> {
+        let base_size = self.serialize(false)?.len();
+        let total_size = self.serialize(true)?.len();
+        Ok(base_size * 3 + total_size)
+    }
+
+    /// Sum of input values minus sum of output values, zero for the coinbase. An error when
+    /// the values overflow or the outputs are worth more than the inputs.
+    pub fn fee(&self) -> Result<u64> {
+        if self.is_coinbase() {
+            return Ok(0);
+        }
+
+        let total_input_value = sum_values(self.vin.iter().map(|input| input.prevout.value))?;
+        let total_output_value = sum_values(self.vout.iter().map(|output| output.value))?;
+        total_input_value
+            .checked_sub(total_output_value)
+            .ok_or_else(|| anyhow!("Outputs are worth more than the inputs"))
+    }
 
+    pub fn is_coinbase(&self) -> bool {
+        self.vin.len() == 1 && self.vin[0].is_coinbase
+    }
+
+    /// Signature operation cost as Bitcoin Core's `GetTransactionSigOpCost`:
+    /// legacy and P2SH sigops count 4, witness sigops count 1
+    pub fn sigop_cost(&self) -> Result<usize> {
+        let mut legacy_sigops = 0;
+        for input in &self.vin {
+            legacy_sigops += count_sigops(&hex::decode(&input.scriptsig)?, false);
+        }
         for output in &self.vout {
-            bytes.write_u64::<LittleEndian>(output.value).unwrap();
+            legacy_sigops += count_sigops(&hex::decode(&output.scriptpubkey)?, false);
+        }
+
+        let mut cost = legacy_sigops * WITNESS_SCALE_FACTOR;
+        if self.is_coinbase() {
+            return Ok(cost);
+        }
+
+        for input in &self.vin {
+            let prevout_script = hex::decode(&input.prevout.scriptpubkey)?;
+            let scriptsig = hex::decode(&input.scriptsig)?;
+
+            let mut program_script = prevout_script.as_slice();
+            if is_p2sh(&prevout_script) {
+                if let Some(redeem_script) = last_push(&scriptsig) {
+                    cost += count_sigops(redeem_script, true) * WITNESS_SCALE_FACTOR;
+                    program_script = redeem_script;
+                }
+            }
 
-            let scriptpubkey_bytes = output.scriptpubkey.as_bytes();
-            bytes
-                .write_u32::<LittleEndian>(scriptpubkey_bytes.len() as u32)
-                .unwrap();
-            bytes.extend_from_slice(scriptpubkey_bytes);
+            cost += match witness_program(program_script) {
+                Some((0, program)) if program.len() == 20 => 1,
+                Some((0, program)) if program.len() == 32 => match input.witness.last() {
+                    Some(witness_script) => count_sigops(&hex::decode(witness_script)?, true),
+                    None => 0,
+                },
+                _ => 0,
+            };
         }
 
-        bytes
+        Ok(cost)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct Input {
-    pub(crate) txid: String,
-    pub(crate) vout: u32,
-    pub(crate) prevout: PrevOut,
-    pub(crate) scriptsig: String,
-    pub(crate) scriptsig_asm: String,
-    pub(crate) witness: Vec<String>,
-    pub(crate) is_coinbase: bool,
-    pub(crate) sequence: u64,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Input {
+    pub txid: Txid,
+    pub vout: u32,
+    pub prevout: PrevOut,
+    pub scriptsig: String,
+    pub scriptsig_asm: String,
+    pub witness: Vec<String>,
+    pub is_coinbase: bool,
+    pub sequence: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct PrevOut {
-    pub(crate) scriptpubkey: String,
-    pub(crate) scriptpubkey_asm: String,
-    pub(crate) scriptpubkey_type: String,
-    pub(crate) scriptpubkey_address: String,
-    pub(crate) value: u64,
+impl Input {
+    /// The output this input spends
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint::new(self.txid, self.vout)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct Output {
-    pub(crate) scriptpubkey: String,
-    pub(crate) scriptpubkey_asm: String,
-    pub(crate) scriptpubkey_type: String,
-    pub(crate) scriptpubkey_address: String,
-    pub(crate) value: u64,
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrevOut {
+    pub scriptpubkey: String,
+    pub scriptpubkey_asm: String,
+    pub scriptpubkey_type: String,
+    pub scriptpubkey_address: String,
+    pub value: u64,
 }
 
-pub(crate) fn validate_all_transactions(
-    txs: HashMap<String, String>,
-) -> HashMap<String, Transaction> {
-    let mut valid_txs = HashMap::new();
-    let outputs_hashmap = create_output_hashmap(&txs);
-    for (txid, tx_json) in &txs {
-        if let Some(tx) = is_transaction_valid(txid, &tx_json, &outputs_hashmap) {
-            valid_txs.insert(txid.clone(), tx);
-        }
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Output {
+    pub scriptpubkey: String,
+    pub scriptpubkey_asm: String,
+    pub scriptpubkey_type: String,
+    pub scriptpubkey_address: String,
+    pub value: u64,
+}
 
-    valid_txs
+/// Check every mempool transaction in parallel and keep the valid ones
+pub fn validate_all_transactions(
+    txs: HashMap<Txid, MempoolEntry>,
+    network: Network,
+    policy: &Policy,
+) -> HashMap<Txid, Transaction> {
+    let outputs_hashmap = create_output_hashmap(&txs);
+    txs.into_par_iter()
+        .filter_map(|(txid, entry)| {
+            let tx = is_transaction_valid(txid, entry, &outputs_hashmap, network, policy)?;
+            Some((txid, tx))
+        })
+        .collect()
 }
 
 fn is_transaction_valid(
-    tx_id: &str,
-    tx_json: &&String,
-    output_hashmap: &HashMap<String, String>,
+    tx_id: Txid,
+    entry: MempoolEntry,
+    output_hashmap: &HashMap<OutPoint, Txid>,
+    network: Network,
+    policy: &Policy,
 ) -> Option<Transaction> {
-    // Check syntactic correctness
-    let maybe_tx = is_valid_syntax(tx_json);
-    maybe_tx.as_ref()?;
-    let tx = maybe_tx.unwrap();
+    // Syntactic correctness was checked when parsing the entry
+    let MempoolEntry { tx, json_size, .. } = entry;
 
     // Make sure neither in or out lists are empty
     if !is_valid_in_and_out_txs_lists_are_not_empty(&tx) {
         return None;
     }
 
-    // Size in bytes <= MAX_BLOCK_SIZE
-    if !is_valid_max_block_size_correct(tx_json) {
+    // Size in bytes <= the policy's maximum transaction size
+    if !is_valid_max_tx_size_correct(json_size, policy.max_tx_size) {
         return None;
     }
 
@@ -166,7 +301,12 @@ fn is_transaction_valid(
     }
 
     // Check that nLockTime <= INT_MAX[1], size in bytes >= 100[2], and sig opcount <= 2[3]
-    if !is_valid_check_n_lock_time_size_sign_opcount(tx_json, &tx) {
+    if !is_valid_check_n_lock_time_size_sign_opcount(json_size, &tx) {
+        return None;
+    }
+
+    // Reject transactions whose type, address or ASM fields do not match the scripts they describe
+    if !is_valid_fields_consistent(tx_id, &tx, network) {
         return None;
     }
 
@@ -176,7 +316,12 @@ fn is_transaction_valid(
     }
 
     // Reject if transaction fee (defined as sum of input values minus sum of output values) would be too low to get into an empty block
-    if !is_valid_check_tx_fee(&tx) {
+    if !is_valid_check_tx_fee(&tx, policy) {
+        return None;
+    }
+
+    // Reject outputs too small to be worth spending, except data carriers
+    if !is_valid_no_dust_outputs(&tx, policy.dust_limit) {
         return None;
     }
 
@@ -194,56 +339,66 @@ fn is_transaction_valid(
 }
 
 fn is_valid_sum_of_inputs_bigger_than_outputs(tx: &Transaction) -> bool {
-    let total_input_value: u64 = tx.vin.iter().map(|input| input.prevout.value).sum();
-    let total_output_value: u64 = tx.vout.iter().map(|output| output.value).sum();
-
-    total_input_value >= total_output_value
+    tx.fee().is_ok()
 }
 
 fn is_valid_check_if_output_exists_in_other_tx(
-    current_tx_id: &str,
+    current_tx_id: Txid,
     current_tx: &Transaction,
-    output_references: &HashMap<String, String>,
+    output_references: &HashMap<OutPoint, Txid>,
 ) -> bool {
     for vin in &current_tx.vin {
-        let key = format!("{}:{}", vin.txid, vin.vout);
-        match output_references.get(&key) {
-            None => {}
-            Some(matching_txid) => {
-                if matching_txid != current_tx_id {
-                    return false;
-                }
-            }
+        match output_references.get(&vin.outpoint()) {
+            Some(matching_txid) if *matching_txid != current_tx_id => return false,
+            _ => {}
         }
     }
 
     true
 }
 
-fn create_output_hashmap(txs: &HashMap<String, String>) -> HashMap<String, String> {
+fn create_output_hashmap(txs: &HashMap<Txid, MempoolEntry>) -> HashMap<OutPoint, Txid> {
     let mut output_hashmap = HashMap::new();
-    for (txid, tx_json) in txs {
-        if let Ok(tx) = serde_json::from_str::<Transaction>(tx_json) {
-            for input in &tx.vin {
-                let key = format!("{}:{}", input.txid, input.vout);
-                output_hashmap.insert(key, txid.clone());
-            }
+    for (txid, entry) in txs {
+        for input in &entry.tx.vin {
+            output_hashmap.insert(input.outpoint(), *txid);
         }
     }
     output_hashmap
 }
 
-fn is_valid_check_tx_fee(tx: &Transaction) -> bool {
-    // Calculate the total input value
-    let total_input_value: u64 = tx.vin.iter().map(|input| input.prevout.value).sum();
+fn is_valid_check_tx_fee(tx: &Transaction, policy: &Policy) -> bool {
+    let Ok(tx_fee) = tx.fee() else {
+        return false;
+    };
 
-    // Calculate the total output value
-    let total_output_value: u64 = tx.vout.iter().map(|output| output.value).sum();
+    if tx_fee < policy.min_fee {
+        return false;
+    }
 
-    let tx_fee = total_input_value.saturating_sub(total_output_value);
+    // Fee rate per virtual byte, a virtual byte being WITNESS_SCALE_FACTOR weight units
+    match tx.weight() {
+        Ok(weight) => {
+            let vsize = weight.div_ceil(WITNESS_SCALE_FACTOR);
+            tx_fee as f64 >= policy.min_feerate * vsize as f64
+        }
+        Err(_) => false,
+    }
+}
 
-    // Assume min fee is 1 sat
-    tx_fee >= 1
+fn is_valid_no_dust_outputs(tx: &Transaction, dust_limit: u64) -> bool {
+    tx.vout
+        .iter()
+        .all(|output| output.value >= dust_limit || output.scriptpubkey_type == "op_return")
+}
+
+fn is_valid_fields_consistent(tx_id: Txid, tx: &Transaction, network: Network) -> bool {
+    let mismatches = find_mismatches(tx, network);
+    for mismatch in &mismatches {
+        warn!("Inconsistent transaction {tx_id}: {mismatch}");
+    }
+
+    mismatches.is_empty()
 }
 
 fn is_valid_reject_nonstandard_txs(tx: &Transaction) -> bool {
@@ -276,7 +431,7 @@ fn is_valid_reject_nonstandard_txs(tx: &Transaction) -> bool {
     true
 }
 
-fn is_valid_check_n_lock_time_size_sign_opcount(tx_json: &&String, tx: &Transaction) -> bool {
+fn is_valid_check_n_lock_time_size_sign_opcount(json_size: usize, tx: &Transaction) -> bool {
     // Check nLockTime <= INT_MAX
     let locktime = tx.locktime as u64;
     if locktime > i32::MAX as u64 {
@@ -284,7 +439,7 @@ fn is_valid_check_n_lock_time_size_sign_opcount(tx_json: &&String, tx: &Transact
     }
 
     // Check size in bytes >= 100
-    if tx_json.as_bytes().len() < 100 {
+    if json_size < 100 {
         return false;
     }
 
@@ -307,18 +462,18 @@ fn is_valid_check_output_and_total_money_range(tx: &Transaction) -> bool {
 
     // Sum all output values and check individual outputs
     for output in &tx.vout {
-        total_output_value += output.value;
+        total_output_value = total_output_value.saturating_add(output.value);
         if total_output_value >= TOTAL_MONEY_CAP {
-            println!("Output value exceeds the total money cap.");
+            warn!("Output value exceeds the total money cap.");
             return false;
         }
     }
 
     // Sum all input values and check individual inputs
     for input in &tx.vin {
-        total_input_value += input.prevout.value;
+        total_input_value = total_input_value.saturating_add(input.prevout.value);
         if total_input_value >= TOTAL_MONEY_CAP {
-            println!("Input value exceeds the total money cap.");
+            warn!("Input value exceeds the total money cap.");
             return false;
         }
     }
@@ -336,18 +491,62 @@ fn is_valid_check_hash_and_coinbase(tx: &Transaction) -> bool {
     true
 }
 
-fn is_valid_max_block_size_correct(tx_json: &str) -> bool {
-    tx_json.as_bytes().len() <= MAX_BLOCK_SIZE
+fn is_valid_max_tx_size_correct(json_size: usize, max_tx_size: usize) -> bool {
+    json_size <= max_tx_size
 }
 
 fn is_valid_in_and_out_txs_lists_are_not_empty(tx: &Transaction) -> bool {
     !tx.vin.is_empty() && !tx.vout.is_empty()
 }
 
-fn is_valid_syntax(tx_json: &str) -> Option<Transaction> {
-    convert_json_to_tx(tx_json).ok()
-}
-
-pub(crate) fn convert_json_to_tx(tx_json: &str) -> Result<Transaction> {
+/// Parse a transaction in the mempool JSON format, with the outputs it spends as `prevout`
+///
+/// ```
+/// use code_challenge_2024_mirebella_v2::{convert_json_to_tx, Transaction};
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let tx = convert_json_to_tx(
+///     r#"{
+///         "version": 2,
+///         "locktime": 834453,
+///         "vin": [{
+///             "txid": "be0e91c8161076830a5d35138868020ed10e58ea208b371b2e8c734fc3b9b694",
+///             "vout": 0,
+///             "prevout": {
+///                 "scriptpubkey": "5120e12efa737eefa3a1635084a88a959c92cbc4095a34f669b3ff3fb27eea9944bb",
+///                 "scriptpubkey_asm": "OP_PUSHNUM_1 OP_PUSHBYTES_32 e12efa737eefa3a1635084a88a959c92cbc4095a34f669b3ff3fb27eea9944bb",
+///                 "scriptpubkey_type": "v1_p2tr",
+///                 "scriptpubkey_address": "bc1puyh05um7a736zc6ssj5g49vujt9ugz26xnmxnvll87e8a65egjasft3s98",
+///                 "value": 105086
+///             },
+///             "scriptsig": "",
+///             "scriptsig_asm": "",
+///             "witness": ["378301008ff08fd55c3be8ee1ff3a770ad1799092e682c2148dba707a8980e9c64fea80accefdb6d3bf611f3f2145cbc776270fb3f5ca0516b0e3785f6449743"],
+///             "is_coinbase": false,
+///             "sequence": 0
+///         }],
+///         "vout": [{
+///             "scriptpubkey": "001420d2699d10c0e0d712fe77e4447e4598a68e671a",
+///             "scriptpubkey_asm": "OP_0 OP_PUSHBYTES_20 20d2699d10c0e0d712fe77e4447e4598a68e671a",
+///             "scriptpubkey_type": "v0_p2wpkh",
+///             "scriptpubkey_address": "bc1qyrfxn8gscrsdwyh7wljyglj9nznguec6nemwky",
+///             "value": 103697
+///         }]
+///     }"#,
+/// )?;
+///
+/// assert_eq!(
+///     tx.id()?.to_string(),
+///     "2f6f5c0e62d7d588185b138d243e1f9a4b527cd962b2f1f1bfc9c52b5ae0fe10"
+/// );
+/// assert_eq!(tx.fee()?, 1389);
+///
+/// // The consensus serialization round trips, apart from the prevouts it does not carry
+/// let raw = tx.serialize(true)?;
+/// assert_eq!(Transaction::deserialize(&mut raw.as_slice())?.serialize(true)?, raw);
+/// # Ok(())
+/// # }
+/// ```
+pub fn convert_json_to_tx(tx_json: &str) -> Result<Transaction> {
     serde_json::from_str::<Transaction>(tx_json).map_err(Into::into)
 }
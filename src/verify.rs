@@ -0,0 +1,96 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use primitive_types::U256;
+
+use code_challenge_2024_mirebella_v2::block_validation::{
+    block_weight, check_coinbase_value, check_spends_and_ordering, check_weight_and_sigops,
+    total_fees, MAX_BLOCK_WEIGHT,
+};
+use code_challenge_2024_mirebella_v2::input::read_mempool;
+use code_challenge_2024_mirebella_v2::network::Network;
+use code_challenge_2024_mirebella_v2::output::{check_output_file, read_output_file};
+use code_challenge_2024_mirebella_v2::policy::Policy;
+use code_challenge_2024_mirebella_v2::validation::{validate_all_transactions, Transaction};
+
+/// How well a block uses the mempool
+#[derive(Debug)]
+pub(crate) struct BlockScore {
+    pub(crate) tx_count: usize,
+    pub(crate) fees: u64,
+    /// Fees of every valid mempool transaction, what a block without a weight limit could collect
+    pub(crate) available_fees: u64,
+    pub(crate) weight: usize,
+    /// Fees collected out of the available fees
+    pub(crate) fee_share: f64,
+    /// Weight used out of the block weight limit
+    pub(crate) utilisation: f64,
+    /// Average of the fee share and the block space utilisation, in percent. The grader does
+    /// not publish its weighting, this is an approximation of it.
+    pub(crate) score: f64,
+}
+
+/// Grade an `output.txt` against the mempool it was built from: the header has to meet
+/// `target` and its own bits, every listed txid has to be in the mempool, parents have to
+/// come before children, nothing may be spent twice, weight and sigops have to be within the
+/// limits and the merkle root and witness commitment have to match. The available fees are
+/// those of the mempool transactions accepted under `policy`.
+pub(crate) fn verify_output_file(
+    output_path: &Path,
+    mempool_dir: &Path,
+    network: Network,
+    policy: &Policy,
+    height: u32,
+    target: U256,
+) -> Result<BlockScore> {
+    let output = read_output_file(output_path)?;
+
+    let hash = output.header.block_hash();
+    if hash.to_u256() >= target {
+        let mut target_bytes = [0u8; 32];
+        target.to_big_endian(&mut target_bytes);
+        return Err(anyhow!(
+            "Block hash {hash} is not below the target {}",
+            hex::encode(target_bytes)
+        ));
+    }
+
+    let mempool = read_mempool(mempool_dir, network)?;
+    let transactions = output
+        .txids
+        .iter()
+        .skip(1)
+        .map(|txid| match mempool.get(txid) {
+            Some(entry) => Ok(entry.tx.clone()),
+            None => Err(anyhow!("Transaction {txid} is not in the mempool")),
+        })
+        .collect::<Result<Vec<Transaction>>>()?;
+    check_output_file(&output, &transactions)?;
+
+    let mut block_transactions = vec![output.coinbase.clone()];
+    block_transactions.extend(transactions);
+    check_spends_and_ordering(&block_transactions)?;
+    check_weight_and_sigops(&block_transactions)?;
+    check_coinbase_value(&block_transactions, height)?;
+
+    let fees = total_fees(&block_transactions)?;
+    let weight = block_weight(&block_transactions)?;
+
+    let available_fees = total_fees(validate_all_transactions(mempool, network, policy).values())?;
+
+    let fee_share = match available_fees {
+        0 => 1.0,
+        _ => (fees as f64 / available_fees as f64).min(1.0),
+    };
+    let utilisation = weight as f64 / MAX_BLOCK_WEIGHT as f64;
+
+    Ok(BlockScore {
+        tx_count: block_transactions.len(),
+        fees,
+        available_fees,
+        weight,
+        fee_share,
+        utilisation,
+        score: (fee_share + utilisation) / 2.0 * 100.0,
+    })
+}
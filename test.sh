@@ -1,4 +1 @@
-cd ./grader || exit
-npm install
-
-npm run verify-block
\ No newline at end of file
+cargo run --release -- verify-block output.txt